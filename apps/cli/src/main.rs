@@ -1,7 +1,9 @@
 use clap::Parser;
-use p2p_node::P2PNode;
+use p2p_node::{ConnectionState, DnsConfig, GatewayBackend, IpReport, LanDiscoveryConfig, LocalForward, NodeConfig, NodeEvent, NodeKey, P2PNode, PublishedPort, RuleSet, StaticConfig};
+use p2p_node::gateway::{GatewayConfig, UdpMapping};
 use std::net::Ipv4Addr;
-use tracing::info;
+use std::time::Duration;
+use tracing::{debug, info, warn};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -11,6 +13,98 @@ struct Args {
     
     #[arg(short, long, default_value = "255.255.255.0")]
     mask: String,
+
+    /// Act as a gateway for the local network
+    #[arg(long)]
+    gateway: bool,
+
+    /// Gateway NAT backend: "userspace" or "kernel_nat" (Linux, nftables)
+    #[arg(long, default_value = "userspace")]
+    gateway_backend: String,
+
+    /// Idle timeout for gateway UDP flows, in seconds
+    #[arg(long)]
+    udp_idle_timeout: Option<u64>,
+
+    /// Idle timeout for gateway DNS flows, in seconds
+    #[arg(long)]
+    dns_idle_timeout: Option<u64>,
+
+    /// Idle timeout for gateway ICMP echo flows, in seconds
+    #[arg(long)]
+    icmp_idle_timeout: Option<u64>,
+
+    /// Most gateway UDP flows at once; the least recently used is evicted beyond it
+    #[arg(long)]
+    max_udp_flows: Option<usize>,
+
+    /// Most gateway TCP NAT mappings at once; new connections are refused beyond it
+    #[arg(long)]
+    max_tcp_mappings: Option<usize>,
+
+    /// Most gateway ICMP echo flows at once; new ones are refused beyond it
+    #[arg(long)]
+    max_icmp_flows: Option<usize>,
+
+    /// Gateway UDP mapping: "dependent" (a socket per destination) or "independent"
+    /// (a socket per client port, full cone; for games and VoIP)
+    #[arg(long, default_value = "dependent")]
+    udp_mapping: UdpMapping,
+
+    /// With independent UDP mapping, try to keep the client's source port on the LAN
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    preserve_ports: bool,
+
+    /// LAN DNS domain this gateway resolves for peers (repeatable)
+    #[arg(long = "lan-domain")]
+    lan_domains: Vec<String>,
+
+    /// Proxy rules file (DOMAIN-SUFFIX,example.com,DIRECT ...)
+    #[arg(long)]
+    rules: Option<String>,
+
+    /// Preferred local HTTP proxy port
+    #[arg(long)]
+    http_proxy_port: Option<u16>,
+
+    /// Forward a local port to a peer: [BIND:]PORT:PEER[/HOST]:PORT (repeatable)
+    #[arg(short = 'L', long = "forward")]
+    forwards: Vec<LocalForward>,
+
+    /// Publish a local service on the virtual IP: PORT:[HOST:]TARGET_PORT (repeatable)
+    #[arg(short = 'R', long = "publish")]
+    publish: Vec<PublishedPort>,
+
+    /// Domain peers resolve under via MagicDNS
+    #[arg(long, default_value = "syuink.internal")]
+    dns_domain: String,
+
+    /// Disable the MagicDNS resolver
+    #[arg(long)]
+    no_dns: bool,
+
+    /// Let gateways claim this LAN domain even though it is a public or single-label
+    /// name (repeatable); by default only private-use names like home.arpa or *.lan
+    #[arg(long = "accept-lan-domain")]
+    accept_lan_domains: Vec<String>,
+
+    /// Announce this node on the LAN and connect directly to known peers heard there
+    #[arg(long)]
+    lan_discovery: bool,
+
+    /// Signaling server URL (repeatable; later ones are fallbacks, tried in order).
+    /// Defaults to SIGNALING_URL, itself a comma-separated list.
+    #[arg(long = "server")]
+    servers: Vec<String>,
+
+    /// Static peers file (WireGuard style). Without --server or SIGNALING_URL the
+    /// node runs without a signaling server.
+    #[arg(long = "static")]
+    static_config: Option<String>,
+
+    /// Print a new private key and its public key, then exit
+    #[arg(long)]
+    genkey: bool,
 }
 
 #[tokio::main]
@@ -22,6 +116,12 @@ async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     
     let args = Args::parse();
+    if args.genkey {
+        let key = NodeKey::generate()?;
+        println!("PrivateKey = {}", key.to_base64());
+        println!("# PublicKey = {}", key.public_key());
+        return Ok(());
+    }
     let ip: Ipv4Addr = args.ip.parse()?;
     let mask: Ipv4Addr = args.mask.parse()?;
 
@@ -29,7 +129,86 @@ async fn main() -> anyhow::Result<()> {
     info!("Virtual IP: {}", ip);
     info!("Netmask: {}", mask);
     
-    let node = P2PNode::new(ip, mask, "CLI Node".to_string());
+    let gateway_backend = match args.gateway_backend.as_str() {
+        "userspace" => GatewayBackend::Userspace,
+        "kernel_nat" | "kernel-nat" => GatewayBackend::KernelNat,
+        other => anyhow::bail!("Unknown gateway backend: {}", other),
+    };
+    let rules = match &args.rules {
+        Some(path) => RuleSet::load(path)?,
+        None => RuleSet::default(),
+    };
+    let mut gateway = GatewayConfig::default();
+    if let Some(secs) = args.udp_idle_timeout {
+        gateway.udp_idle_timeout = Duration::from_secs(secs);
+    }
+    if let Some(secs) = args.dns_idle_timeout {
+        gateway.dns_idle_timeout = Duration::from_secs(secs);
+    }
+    if let Some(secs) = args.icmp_idle_timeout {
+        gateway.icmp_idle_timeout = Duration::from_secs(secs);
+    }
+    if let Some(max) = args.max_udp_flows {
+        gateway.max_udp_flows = max;
+    }
+    if let Some(max) = args.max_tcp_mappings {
+        gateway.max_tcp_mappings = max;
+    }
+    if let Some(max) = args.max_icmp_flows {
+        gateway.max_icmp_flows = max;
+    }
+    gateway.udp_mapping = args.udp_mapping;
+    gateway.preserve_ports = args.preserve_ports;
+    let static_config = match &args.static_config {
+        Some(path) => Some(StaticConfig::load(path)?),
+        None => None,
+    };
+    let config = NodeConfig {
+        gateway_backend,
+        gateway,
+        lan_domains: args.lan_domains,
+        rules,
+        http_proxy_port: args.http_proxy_port,
+        local_forwards: args.forwards,
+        published_ports: args.publish,
+        dns: DnsConfig {
+            enabled: !args.no_dns,
+            domain: args.dns_domain,
+            accept_domains: args.accept_lan_domains,
+            ..Default::default()
+        },
+        key: static_config.as_ref().and_then(|c| c.private_key.clone()),
+        p2p_port: static_config.as_ref().and_then(|c| c.listen_port),
+        static_peers: static_config.as_ref().map(|c| c.peers.clone()).unwrap_or_default(),
+        lan_discovery: LanDiscoveryConfig {
+            enabled: args.lan_discovery,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let (event_tx, mut event_rx) = tokio::sync::mpsc::channel::<NodeEvent>(32);
+    tokio::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                NodeEvent::Signaling(ConnectionState::Connected { url }) => info!("Signaling connected: {}", url),
+                NodeEvent::Signaling(ConnectionState::Disconnected { reason }) => warn!("Signaling disconnected: {}", reason),
+                NodeEvent::Signaling(ConnectionState::Reconnecting { attempt, delay_ms }) => {
+                    info!("Signaling reconnecting in {} ms (attempt {})", delay_ms, attempt)
+                }
+                NodeEvent::SignalingRtt { rtt_ms } => debug!("Signaling RTT: {} ms", rtt_ms),
+                NodeEvent::GatewayStats(stats) => info!(
+                    "Gateway: {} UDP flows ({} created, {} expired, {} evicted), {} packets out, {} in",
+                    stats.active_udp_flows, stats.udp_flows_created, stats.udp_flows_expired,
+                    stats.udp_flows_evicted, stats.udp_packets_out, stats.udp_packets_in
+                ),
+            }
+        }
+    });
+
+    let node = P2PNode::new(ip, mask, "CLI Node".to_string())
+        .with_config(config)
+        .with_events(event_tx);
     
     // Check if we have admin privileges (required for TUN)
     #[cfg(target_os = "windows")]
@@ -38,17 +217,46 @@ async fn main() -> anyhow::Result<()> {
     let (shutdown_tx, _shutdown_rx) = tokio::sync::broadcast::channel(1);
     let (_cmd_tx, cmd_rx) = tokio::sync::mpsc::channel(32);
     
-    let signaling_url = std::env::var("SIGNALING_URL").unwrap_or_else(|_| "ws://127.0.0.1:8787".to_string());
-    let node_id = uuid::Uuid::new_v4().to_string();
+    let signaling_urls = if args.servers.is_empty() {
+        let default_url = if static_config.is_some() { "" } else { "ws://127.0.0.1:8787" };
+        std::env::var("SIGNALING_URL")
+            .unwrap_or_else(|_| default_url.to_string())
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect()
+    } else {
+        args.servers
+    };
+    let node_id = static_config
+        .and_then(|c| c.node_id)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let (ip_report_tx, mut ip_report_rx) = tokio::sync::mpsc::channel::<IpReport>(1);
+    tokio::spawn(async move {
+        if let Some(report) = ip_report_rx.recv().await {
+            info!("Node up on {}", report.ip);
+            info!(
+                "SOCKS5 proxy: socks5://{}:{}@127.0.0.1:{}",
+                report.socks5_auth.username, report.socks5_auth.password, report.socks5_port
+            );
+            if report.http_proxy_port != 0 {
+                info!(
+                    "HTTP proxy: http://{}:{}@127.0.0.1:{}",
+                    report.socks5_auth.username, report.socks5_auth.password, report.http_proxy_port
+                );
+            }
+        }
+    });
 
     node.start(
         shutdown_tx.subscribe(),
+        Some(ip_report_tx),
         None,
-        None,
-        signaling_url,
+        signaling_urls,
         None,
         node_id,
-        (Some("CLI".to_string()), None, Some("cli".to_string()), false),
+        (Some("CLI".to_string()), None, Some("cli".to_string()), args.gateway),
         vec![],
         cmd_rx
     ).await?;
@@ -1,7 +1,9 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use p2p_node::{NodeCommand, P2PNode};
+use p2p_node::{NodeCommand, NodeConfig, NodeEvent, P2PNode, RuleAction, RuleSet};
+use p2p_node::gateway::GatewayConfig;
 use p2p_node::signaling::ServiceDecl;
+use p2p_node::socks5::Socks5Credentials;
 use std::net::Ipv4Addr;
 use std::sync::Mutex;
 use tauri::{
@@ -18,10 +20,43 @@ struct VpnState {
     node_task_handle: Mutex<Option<tauri::async_runtime::JoinHandle<()>>>,
     current_ip: Mutex<Option<String>>,
     socks5_port: Mutex<Option<u16>>,
+    socks5_auth: Mutex<Option<Socks5Credentials>>,
+    http_proxy_port: Mutex<Option<u16>>,
     command_tx: Mutex<Option<tokio::sync::mpsc::Sender<NodeCommand>>>,
     menu_connected: Mutex<Option<CheckMenuItem<Wry>>>,
     menu_rules: Mutex<Option<CheckMenuItem<Wry>>>,
     menu_global: Mutex<Option<CheckMenuItem<Wry>>>,
+    // Tray proxy mode, "rules" or "global", and what each one routes by
+    proxy_mode: Mutex<String>,
+    rules_path: Mutex<Option<String>>,
+    exit_peer: Mutex<Option<String>>,
+}
+
+impl VpnState {
+    /// Rules for the current mode: global sends everything to the exit peer (directly
+    /// without one), rules mode uses the loaded file or the defaults.
+    fn proxy_rules(&self) -> Result<RuleSet, String> {
+        if *self.proxy_mode.lock().unwrap() == "global" {
+            return Ok(match self.exit_peer.lock().unwrap().clone() {
+                Some(exit) => RuleSet::with_default(RuleAction::Via(exit)),
+                None => RuleSet::default(),
+            });
+        }
+        match self.rules_path.lock().unwrap().clone() {
+            Some(path) => RuleSet::load(&path).map_err(|e| e.to_string()),
+            None => Ok(RuleSet::default()),
+        }
+    }
+
+    /// Hands the current mode's rules to the running node, if any.
+    async fn apply_proxy_rules(&self) -> Result<(), String> {
+        let rules = self.proxy_rules()?;
+        let sender = self.command_tx.lock().unwrap().clone();
+        if let Some(sender) = sender {
+            sender.send(NodeCommand::SetRules(rules)).await.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
 }
 
 use tauri::Emitter;
@@ -34,9 +69,14 @@ async fn start_vpn(
     node_id: Option<String>,
     token: Option<String>,
     server_url: Option<String>,
+    // Tried in order after `server_url` if it is unreachable
+    fallback_urls: Option<Vec<String>>,
     ip: Option<String>,
     is_gateway: bool,
     services: Vec<ServiceDecl>,
+    // Gateway UDP NAT: "dependent" (default) or "independent"
+    udp_mapping: Option<String>,
+    preserve_ports: Option<bool>,
 ) -> Result<String, String> {
     // Check if already running
     {
@@ -63,6 +103,15 @@ async fn start_vpn(
     let version = System::os_version();
     let device_type = Some("desktop".to_string());
     let my_meta = (os, version, device_type, is_gateway);
+    let rules = state.proxy_rules()?;
+
+    let mut gateway = GatewayConfig::default();
+    if let Some(mapping) = udp_mapping {
+        gateway.udp_mapping = mapping.parse().map_err(|e| format!("UDP 映射设置错误: {}", e))?;
+    }
+    if let Some(preserve) = preserve_ports {
+        gateway.preserve_ports = preserve;
+    }
 
     let name = device_name.unwrap_or_else(|| "My Device".to_string());
     // Generate UUID if not provided (should be provided by frontend for persistence)
@@ -108,6 +157,25 @@ async fn start_vpn(
         }
     });
 
+    // Forward node events (signaling connection state and RTT) to the UI
+    let (event_tx, mut event_rx) = tokio::sync::mpsc::channel(32);
+    let app_handle = app.clone();
+    tauri::async_runtime::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                NodeEvent::Signaling(state) => {
+                    let _ = app_handle.emit("signaling-state", &state);
+                }
+                NodeEvent::SignalingRtt { rtt_ms } => {
+                    let _ = app_handle.emit("signaling-rtt", rtt_ms);
+                }
+                NodeEvent::GatewayStats(stats) => {
+                    let _ = app_handle.emit("gateway-stats", &stats);
+                }
+            }
+        }
+    });
+
     // Create a channel to wait for the node's background task result
     let (node_result_tx, mut node_result_rx) = tokio::sync::mpsc::channel(1);
 
@@ -138,15 +206,19 @@ async fn start_vpn(
             std::thread::sleep(std::time::Duration::from_millis(800));
         }
 
-        let node = P2PNode::new(ip_addr, mask, name);
+        let node = P2PNode::new(ip_addr, mask, name)
+            .with_config(NodeConfig { gateway, rules, http_proxy_trust_loopback: true, ..Default::default() })
+            .with_events(event_tx);
         let base_url = server_url.unwrap_or_else(|| "ws://127.0.0.1:8787".to_string());
-        println!("Starting P2P Node with Signaling URL: {}", base_url);
+        let mut signaling_urls = vec![base_url];
+        signaling_urls.extend(fallback_urls.unwrap_or_default());
+        println!("Starting P2P Node with Signaling URLs: {}", signaling_urls.join(", "));
 
         let result = node.start(
             b_tx_clone.subscribe(),
             Some(ip_report_tx),
             Some(peer_update_tx),
-            base_url,
+            signaling_urls,
             token,
             my_id,
             my_meta,
@@ -184,7 +256,9 @@ async fn start_vpn(
     tokio::select! {
         ip_info = ip_report_rx.recv() => {
             match ip_info {
-                Some((allocated_ip, socks5_port)) => {
+                Some(report) => {
+                    let allocated_ip = report.ip;
+                    let socks5_port = report.socks5_port;
                     println!("VPN successfully started with IP: {}, SOCKS5 Port: {}", allocated_ip, socks5_port);
                     // Update state
                     {
@@ -192,6 +266,10 @@ async fn start_vpn(
                         *current = Some(allocated_ip.clone());
                         let mut port = state.socks5_port.lock().unwrap();
                         *port = Some(socks5_port);
+                        let mut auth = state.socks5_auth.lock().unwrap();
+                        *auth = Some(report.socks5_auth);
+                        let mut http_port = state.http_proxy_port.lock().unwrap();
+                        *http_port = (report.http_proxy_port != 0).then_some(report.http_proxy_port);
                     }
                     let result = format!("{}|{}", allocated_ip, socks5_port);
                     let _ = app.emit("vpn-connected", &result);
@@ -225,6 +303,19 @@ async fn start_vpn(
     }
 }
 
+#[tauri::command]
+fn get_socks5_credentials(state: State<'_, VpnState>) -> Option<Socks5Credentials> {
+    state.socks5_auth.lock().unwrap().clone()
+}
+
+#[tauri::command]
+fn get_http_proxy_port(state: State<'_, VpnState>) -> Option<u16> {
+    *state.http_proxy_port.lock().unwrap()
+}
+
+/// Points the OS proxy settings at our HTTP proxy (`port`). SOCKS credentials can't be
+/// handed to WinINet or passed safely to `networksetup`, and most applications never
+/// answer a 407 challenge, so the node serves loopback clients there without auth.
 #[tauri::command]
 async fn set_system_proxy(enable: bool, port: u16) -> Result<(), String> {
     #[cfg(target_os = "windows")]
@@ -249,7 +340,7 @@ async fn set_system_proxy(enable: bool, port: u16) -> Result<(), String> {
                 ])
                 .creation_flags(CREATE_NO_WINDOW)
                 .output();
-            let proxy_server = format!("socks=127.0.0.1:{}", port);
+            let proxy_server = format!("http=127.0.0.1:{port};https=127.0.0.1:{port}", port = port);
             let _ = Command::new("reg")
                 .args(&[
                     "add",
@@ -335,25 +426,26 @@ async fn set_system_proxy(enable: bool, port: u16) -> Result<(), String> {
         let mut applied = false;
         for service in services {
             if enable {
-                let _ = Command::new("networksetup")
-                    .args(&[
-                        "-setsocksfirewallproxy",
-                        service,
-                        "127.0.0.1",
-                        &port.to_string(),
-                    ])
-                    .output();
-                let _ = Command::new("networksetup")
-                    .args(&["-setsocksfirewallproxystate", service, "on"])
-                    .output();
-            } else {
+                let port_str = port.to_string();
+                for kind in ["-setwebproxy", "-setsecurewebproxy"] {
+                    let _ = Command::new("networksetup")
+                        .args(&[kind, service, "127.0.0.1", port_str.as_str()])
+                        .output();
+                }
+                // Left behind by versions that used the SOCKS proxy
                 let _ = Command::new("networksetup")
                     .args(&["-setsocksfirewallproxystate", service, "off"])
                     .output();
+            } else {
+                for kind in ["-setwebproxystate", "-setsecurewebproxystate", "-setsocksfirewallproxystate"] {
+                    let _ = Command::new("networksetup")
+                        .args(&[kind, service, "off"])
+                        .output();
+                }
             }
             // verify per service
             let verify = Command::new("networksetup")
-                .args(&["-getsocksfirewallproxy", service])
+                .args(&["-getwebproxy", service])
                 .output();
             if let Ok(out) = verify {
                 let text = String::from_utf8_lossy(&out.stdout).to_string();
@@ -409,6 +501,19 @@ async fn update_services(
     }
 }
 
+/// Sets the rules file used in rules mode and the exit peer used in global mode, and
+/// applies them to the running node. `None` (or empty) restores the defaults.
+#[tauri::command]
+async fn set_proxy_rules(state: State<'_, VpnState>, path: Option<String>, exit_peer: Option<String>) -> Result<(), String> {
+    let path = path.map(|p| p.trim().to_string()).filter(|p| !p.is_empty());
+    if let Some(p) = &path {
+        RuleSet::load(p).map_err(|e| e.to_string())?;
+    }
+    *state.rules_path.lock().unwrap() = path;
+    *state.exit_peer.lock().unwrap() = exit_peer.map(|e| e.trim().to_string()).filter(|e| !e.is_empty());
+    state.apply_proxy_rules().await
+}
+
 #[tauri::command]
 async fn stop_vpn(app: tauri::AppHandle, state: State<'_, VpnState>) -> Result<String, String> {
     let tx = {
@@ -439,6 +544,10 @@ async fn stop_vpn(app: tauri::AppHandle, state: State<'_, VpnState>) -> Result<S
             *current = None;
             let mut port = state.socks5_port.lock().unwrap();
             *port = None;
+            let mut auth = state.socks5_auth.lock().unwrap();
+            *auth = None;
+            let mut http_port = state.http_proxy_port.lock().unwrap();
+            *http_port = None;
         }
 
         // Also clear command tx
@@ -476,6 +585,10 @@ async fn stop_vpn(app: tauri::AppHandle, state: State<'_, VpnState>) -> Result<S
             *current = None;
             let mut port = state.socks5_port.lock().unwrap();
             *port = None;
+            let mut auth = state.socks5_auth.lock().unwrap();
+            *auth = None;
+            let mut http_port = state.http_proxy_port.lock().unwrap();
+            *http_port = None;
         }
         Err("VPN 未运行".to_string())
     }
@@ -577,6 +690,7 @@ async fn quit_app(app: tauri::AppHandle, state: State<'_, VpnState>) -> Result<(
 
 
 
+/// Switches the tray proxy mode: updates the menu checks and the node's rules.
 #[tauri::command]
 async fn set_proxy_mode_menu(state: State<'_, VpnState>, mode: String) -> Result<(), String> {
     {
@@ -591,7 +705,8 @@ async fn set_proxy_mode_menu(state: State<'_, VpnState>, mode: String) -> Result
             let _ = item.set_checked(mode == "global");
         }
     }
-    Ok(())
+    *state.proxy_mode.lock().unwrap() = mode;
+    state.apply_proxy_rules().await
 }
 
 fn main() {
@@ -640,10 +755,15 @@ fn main() {
             node_task_handle: Mutex::new(None),
             current_ip: Mutex::new(None),
             socks5_port: Mutex::new(None),
+            socks5_auth: Mutex::new(None),
+            http_proxy_port: Mutex::new(None),
             command_tx: Mutex::new(None),
             menu_connected: Mutex::new(None),
             menu_rules: Mutex::new(None),
             menu_global: Mutex::new(None),
+            proxy_mode: Mutex::new("rules".to_string()),
+            rules_path: Mutex::new(None),
+            exit_peer: Mutex::new(None),
         })
         .setup(|app| {
             #[cfg(target_os = "macos")]
@@ -759,16 +879,17 @@ fn main() {
                             let _ = app.emit("toggle-vpn", ());
                             // Check state will be updated by backend event "vpn-connected"/"vpn-disconnected"
                         }
-                        "mode_rules" => {
-                            let _ = app.emit("set-proxy-mode", "rules");
-                            // Update UI state immediately via State
-                            let state = app.state::<VpnState>();
-                            let _ = set_proxy_mode_menu(state, "rules".to_string());
-                        }
-                        "mode_global" => {
-                            let _ = app.emit("set-proxy-mode", "global");
-                            let state = app.state::<VpnState>();
-                            let _ = set_proxy_mode_menu(state, "global".to_string());
+                        "mode_rules" | "mode_global" => {
+                            let mode = if id == "mode_global" { "global" } else { "rules" };
+                            let _ = app.emit("set-proxy-mode", mode);
+                            // Update the menu and the node's rules right away
+                            let app_handle = app.clone();
+                            tauri::async_runtime::spawn(async move {
+                                let state = app_handle.state::<VpnState>();
+                                if let Err(e) = set_proxy_mode_menu(state, mode.to_string()).await {
+                                    eprintln!("Failed to apply proxy mode {}: {}", mode, e);
+                                }
+                            });
                         }
                         _ => {}
                     }
@@ -796,8 +917,11 @@ fn main() {
             get_hostname,
             get_system_info,
             update_services,
+            set_proxy_rules,
             set_system_proxy,
             get_vpn_status,
+            get_socks5_credentials,
+            get_http_proxy_port,
             quit_app,
             set_proxy_mode_menu
         ])
@@ -1,7 +1,7 @@
 import React, { createContext, useContext, useState, useEffect, ReactNode } from 'react';
 import { invoke } from "@tauri-apps/api/core";
 import { listen } from "@tauri-apps/api/event";
-import { getHttpBaseUrl, getWsBaseUrl } from "../utils/server";
+import { getHttpBaseUrl, getWsBaseUrl, getWsFallbackUrls } from "../utils/server";
 
 export interface PeerInfo {
     id: string;
@@ -16,11 +16,17 @@ export interface PeerInfo {
     isSelf?: boolean;
 }
 
+export interface Socks5Auth {
+    username: string;
+    password: string;
+}
+
 interface VPNContextType {
     isConnected: boolean;
     status: string;
     currentIp: string;
     socks5Port: number;
+    socks5Auth: Socks5Auth | null;
     peers: PeerInfo[];
     nodeId: string;
     deviceName: string;
@@ -44,6 +50,7 @@ export function VPNProvider({ children }: { children: ReactNode }) {
     const [status, setStatus] = useState("未连接");
     const [currentIp, setCurrentIp] = useState("");
     const [socks5Port, setSocks5Port] = useState(0);
+    const [socks5Auth, setSocks5Auth] = useState<Socks5Auth | null>(null);
     const [peers, setPeers] = useState<PeerInfo[]>([]);
     const [deviceName, setDeviceName] = useState("");
     const [deviceOs, setDeviceOs] = useState("");
@@ -90,14 +97,17 @@ export function VPNProvider({ children }: { children: ReactNode }) {
             }
         });
         
-        // Load Global Proxy state
+        // Load Global Proxy state, after the rules each mode routes by
         const savedProxy = localStorage.getItem("syuink_global_proxy");
         if (savedProxy === "true") {
             setIsGlobalProxy(true);
-            invoke("set_proxy_mode_menu", { mode: "global" }).catch(console.error);
-        } else {
-            invoke("set_proxy_mode_menu", { mode: "rules" }).catch(console.error);
         }
+        invoke("set_proxy_rules", {
+            path: localStorage.getItem("syuink_rules_path"),
+            exitPeer: localStorage.getItem("syuink_exit_peer"),
+        }).catch(console.error).finally(() => {
+            invoke("set_proxy_mode_menu", { mode: savedProxy === "true" ? "global" : "rules" }).catch(console.error);
+        });
 
         // Sync state from backend (for multi-window support)
         invoke("get_vpn_status").then((res) => {
@@ -120,6 +130,17 @@ export function VPNProvider({ children }: { children: ReactNode }) {
         }).catch(console.error);
     }, []);
 
+    // SOCKS5 credentials are generated per session by the node
+    useEffect(() => {
+        if (!isConnected || socks5Port === 0) {
+            setSocks5Auth(null);
+            return;
+        }
+        invoke("get_socks5_credentials").then((res) => {
+            setSocks5Auth((res as Socks5Auth | null) ?? null);
+        }).catch(console.error);
+    }, [isConnected, socks5Port]);
+
     // Listen for Backend Events
     useEffect(() => {
         console.log("VPNProvider: Setting up event listeners");
@@ -165,6 +186,19 @@ export function VPNProvider({ children }: { children: ReactNode }) {
             }
         });
 
+        // Signaling connection state: { state: "connected" | "disconnected" | "reconnecting", ... }
+        const unlistenSignaling = listen('signaling-state', (event) => {
+            console.log("Signaling state:", event.payload);
+            const s = event.payload as { state: string; reason?: string; attempt?: number; delay_ms?: number };
+            if (s.state === "connected") {
+                setStatus("已连接到网络");
+            } else if (s.state === "disconnected") {
+                setStatus("信令连接已断开: " + (s.reason ?? ""));
+            } else if (s.state === "reconnecting") {
+                setStatus(`正在重连信令服务器 (第 ${s.attempt} 次, ${Math.round((s.delay_ms ?? 0) / 1000)} 秒后)`);
+            }
+        });
+
         const healthInterval = setInterval(async () => {
             try {
                 const statusStr = await invoke("get_vpn_status") as string;
@@ -194,6 +228,7 @@ export function VPNProvider({ children }: { children: ReactNode }) {
             unlistenConnected.then(f => f());
             unlistenDisconnected.then(f => f());
             unlistenPeers.then(f => f());
+            unlistenSignaling.then(f => f());
         };
     }, []);
 
@@ -269,8 +304,11 @@ export function VPNProvider({ children }: { children: ReactNode }) {
                 nodeId: nodeId,
                 token: token,
                 serverUrl: serverUrl,
+                fallbackUrls: getWsFallbackUrls(),
                 isGateway: isGateway,
-                services: services
+                services: services,
+                udpMapping: localStorage.getItem("syuink_udp_mapping") ?? "dependent",
+                preservePorts: localStorage.getItem("syuink_preserve_ports") !== "false"
             }) as string;
             
             console.log("VPN Started result raw:", res);
@@ -297,7 +335,10 @@ export function VPNProvider({ children }: { children: ReactNode }) {
 
             // Restore global proxy setting if it was enabled
             if (isGlobalProxy) {
-                await invoke("set_system_proxy", { enable: true, port: port });
+                const httpPort = await invoke("get_http_proxy_port") as number | null;
+                if (httpPort) {
+                    await invoke("set_system_proxy", { enable: true, port: httpPort });
+                }
             }
         } catch (e) {
             console.error(e);
@@ -338,7 +379,10 @@ export function VPNProvider({ children }: { children: ReactNode }) {
 
         if (isConnected && socks5Port > 0) {
             try {
-                await invoke("set_system_proxy", { enable: enable, port: socks5Port });
+                // The system proxy is the HTTP proxy, which serves loopback clients without auth
+                const httpPort = await invoke("get_http_proxy_port") as number | null;
+                if (!httpPort) throw new Error("HTTP proxy is not running");
+                await invoke("set_system_proxy", { enable: enable, port: httpPort });
             } catch (e) {
                 console.error("Failed to set system proxy:", e);
                 // Revert preference
@@ -356,6 +400,7 @@ export function VPNProvider({ children }: { children: ReactNode }) {
             status,
             currentIp,
             socks5Port,
+            socks5Auth,
             peers,
             deviceName,
             deviceOs,
@@ -13,6 +13,7 @@ function Home() {
     status, 
     currentIp, 
     socks5Port,
+    socks5Auth,
     peers, 
     deviceName, 
     connect, 
@@ -244,6 +245,11 @@ function Home() {
                       虚拟 IP: {currentIp} | SOCKS5 代理: 127.0.0.1:{socks5Port}
                   </div>
               )}
+              {isConnected && socks5Auth && (
+                  <div style={{ fontSize: '12px', marginTop: '3px', color: '#999', fontFamily: 'monospace', userSelect: 'text' }}>
+                      代理账号: {socks5Auth.username} / 密码: {socks5Auth.password}
+                  </div>
+              )}
           </div>
 
           {/* Device Info */}
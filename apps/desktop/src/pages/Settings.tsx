@@ -1,6 +1,7 @@
 import { useState, useEffect } from "react";
 import { useNavigate } from "react-router-dom";
-import { getServerConfig, saveServerConfig } from "../utils/server";
+import { invoke } from "@tauri-apps/api/core";
+import { getServerConfig, saveServerConfig, getWsFallbackUrls, saveWsFallbackUrls } from "../utils/server";
 import { ArrowLeft, Monitor, Server, User } from "lucide-react";
 
 function Settings() {
@@ -12,6 +13,11 @@ function Settings() {
   const [host, setHost] = useState("127.0.0.1");
   const [port, setPort] = useState("8787");
   const [useSsl, setUseSsl] = useState(false);
+  const [fallbacks, setFallbacks] = useState("");
+  const [udpMapping, setUdpMapping] = useState("dependent");
+  const [preservePorts, setPreservePorts] = useState(true);
+  const [rulesPath, setRulesPath] = useState("");
+  const [exitPeer, setExitPeer] = useState("");
   
   const [isTestingServer, setIsTestingServer] = useState(false);
   const [serverStatus, setServerStatus] = useState("");
@@ -23,11 +29,16 @@ function Settings() {
     try {
         const savedName = localStorage.getItem("syuink_device_name");
         if (savedName) setDeviceName(savedName);
+        setUdpMapping(localStorage.getItem("syuink_udp_mapping") ?? "dependent");
+        setPreservePorts(localStorage.getItem("syuink_preserve_ports") !== "false");
+        setRulesPath(localStorage.getItem("syuink_rules_path") ?? "");
+        setExitPeer(localStorage.getItem("syuink_exit_peer") ?? "");
 
         const config = getServerConfig();
         setHost(config.host || "127.0.0.1");
         setPort(config.port || "8787");
         setUseSsl(!!config.useSsl);
+        setFallbacks(getWsFallbackUrls().join("\n"));
 
         const savedUser = localStorage.getItem("syuink_user_email");
         if (savedUser) {
@@ -46,10 +57,30 @@ function Settings() {
     alert("设备名称已保存");
   };
 
+  // Applied the next time the VPN starts
+  const handleSaveGateway = () => {
+    localStorage.setItem("syuink_udp_mapping", udpMapping);
+    localStorage.setItem("syuink_preserve_ports", String(preservePorts));
+    alert("网关设置已保存，重新连接后生效");
+  };
+
+  // Rules mode uses the file, global mode sends everything through the exit peer
+  const handleSaveProxyRules = async () => {
+    try {
+      await invoke("set_proxy_rules", { path: rulesPath, exitPeer: exitPeer });
+      localStorage.setItem("syuink_rules_path", rulesPath.trim());
+      localStorage.setItem("syuink_exit_peer", exitPeer.trim());
+      alert("代理规则已保存");
+    } catch (e) {
+      alert("规则文件加载失败: " + e);
+    }
+  };
+
   const handleTestAndSaveServer = async () => {
     setIsTestingServer(true);
     setServerStatus("正在保存...");
     saveServerConfig({ host, port, useSsl });
+    saveWsFallbackUrls(fallbacks.split(/[\s,]+/).filter(u => u.length > 0));
     setServerStatus("设置已保存");
     setIsTestingServer(false);
   };
@@ -85,6 +116,45 @@ function Settings() {
               />
               <button onClick={handleSaveDeviceName} style={{ padding: '8px 16px', cursor: 'pointer' }}>保存</button>
             </div>
+
+            <div style={{ marginBottom: '15px' }}>
+              <label style={{ display: 'block', marginBottom: '5px', fontWeight: 'bold' }}>网关 UDP 映射</label>
+              <select
+                value={udpMapping}
+                onChange={(e) => setUdpMapping(e.target.value)}
+                style={{ width: '100%', padding: '8px', marginBottom: '10px' }}
+              >
+                <option value="dependent">按目标分配端口 (默认)</option>
+                <option value="independent">固定端口 (Full Cone，适合游戏和语音)</option>
+              </select>
+              <label style={{ display: 'flex', alignItems: 'center', gap: '8px', cursor: 'pointer', marginBottom: '10px' }}>
+                <input
+                  type="checkbox"
+                  checked={preservePorts}
+                  onChange={(e) => setPreservePorts(e.target.checked)}
+                />
+                <span>尽量保留客户端源端口</span>
+              </label>
+              <button onClick={handleSaveGateway} style={{ padding: '8px 16px', cursor: 'pointer' }}>保存</button>
+            </div>
+
+            <div style={{ marginBottom: '15px' }}>
+              <label style={{ display: 'block', marginBottom: '5px', fontWeight: 'bold' }}>规则模式: 规则文件路径</label>
+              <input
+                value={rulesPath}
+                onChange={(e) => setRulesPath(e.target.value)}
+                placeholder="/path/to/rules.conf (留空使用默认规则)"
+                style={{ width: '100%', padding: '8px', marginBottom: '10px', fontFamily: 'monospace' }}
+              />
+              <label style={{ display: 'block', marginBottom: '5px', fontWeight: 'bold' }}>全局模式: 出口设备</label>
+              <input
+                value={exitPeer}
+                onChange={(e) => setExitPeer(e.target.value)}
+                placeholder="设备名称 (留空则直连)"
+                style={{ width: '100%', padding: '8px', marginBottom: '10px' }}
+              />
+              <button onClick={handleSaveProxyRules} style={{ padding: '8px 16px', cursor: 'pointer' }}>保存</button>
+            </div>
           </div>
         );
       case "server":
@@ -123,6 +193,17 @@ function Settings() {
                 </label>
             </div>
 
+            <div style={{ marginBottom: '20px' }}>
+              <label style={{ display: 'block', marginBottom: '5px', fontWeight: 'bold' }}>备用信令服务器 (每行一个，按顺序尝试)</label>
+              <textarea
+                value={fallbacks}
+                onChange={(e) => setFallbacks(e.target.value)}
+                placeholder="wss://backup.example.com"
+                rows={3}
+                style={{ width: '100%', padding: '8px', fontFamily: 'monospace' }}
+              />
+            </div>
+
             <div style={{ display: 'flex', gap: '10px', alignItems: 'center' }}>
                 <button 
                     onClick={handleTestAndSaveServer} 
@@ -61,6 +61,17 @@ export function getHttpBaseUrl(): string {
     return `${protocol}://${config.host}${portPart}`;
 }
 
+// Fallback signaling servers, tried in order when the configured one is unreachable.
+// Stored as a comma-separated list of ws(s):// URLs.
+export function getWsFallbackUrls(): string[] {
+    const raw = localStorage.getItem("syuink_server_fallbacks") || "";
+    return raw.split(",").map(u => u.trim()).filter(u => u.length > 0);
+}
+
+export function saveWsFallbackUrls(urls: string[]) {
+    localStorage.setItem("syuink_server_fallbacks", urls.join(","));
+}
+
 export function getWsBaseUrl(): string {
     const config = getServerConfig();
     const protocol = config.useSsl ? "wss" : "ws";
@@ -0,0 +1,499 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role};
+use tracing::{debug, info, warn};
+use crate::relay::RelayInfo;
+use crate::room::Rooms;
+use crate::store::{RegisterError, Store};
+
+// Request heads larger than this are refused.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+// Register/login bodies are tiny JSON objects.
+const MAX_BODY_LEN: usize = 64 * 1024;
+// The whole request, head and body, must arrive within this.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct AppState {
+    pub store: Store,
+    pub rooms: Rooms,
+    /// Accept WebSocket joins without a token, grouped by the `/wapi/<group>` path.
+    pub open: bool,
+    /// Packet relay nodes are told about after joining, if one runs.
+    pub relay: Option<RelayInfo>,
+    /// Behind a reverse proxy: take the client address from its forwarding headers.
+    pub trust_proxy: bool,
+}
+
+/// Serves one TCP connection: a single HTTP request, or a WebSocket session.
+pub async fn serve(mut socket: TcpStream, remote: SocketAddr, state: Arc<AppState>) -> Result<()> {
+    // 1. Request head and body
+    let (request, body) = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut socket)).await {
+        Ok(read) => read?,
+        Err(_) => {
+            socket.write_all(&text_response(408, "Request Timeout")).await?;
+            return Err(anyhow!("Request not received within {:?}", REQUEST_TIMEOUT));
+        }
+    };
+
+    debug!("{} {} from {}", request.method, request.path, remote);
+
+    // 2. Route
+    if request.method == "OPTIONS" {
+        socket.write_all(&response(204, None, b"")).await?;
+        return Ok(());
+    }
+    if request.path.starts_with("/wapi/") {
+        return upgrade(socket, remote, &request, state).await;
+    }
+    let reply = match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/api/register") => register(&state.store, &body).await,
+        ("POST", "/api/login") => login(&state.store, &body).await,
+        (_, path) if path.starts_with("/api/group/") => group_api(&request, &state).await,
+        _ => text_response(200, "Syuink Signaling Server Running"),
+    };
+    socket.write_all(&reply).await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    email: Option<String>,
+    password: Option<String>,
+}
+
+async fn register(store: &Store, body: &[u8]) -> Vec<u8> {
+    let (email, password) = match serde_json::from_slice::<Credentials>(body) {
+        Ok(Credentials { email: Some(e), password: Some(p) }) if !e.is_empty() && !p.is_empty() => (e, p),
+        _ => return text_response(400, "Missing fields"),
+    };
+    match store.register(&email, &password).await {
+        Ok(id) => {
+            info!("Registered user {} ({})", email, id);
+            json_response(201, &json!({ "id": id, "email": email }))
+        }
+        Err(RegisterError::EmailTaken) => text_response(409, "Email already registered"),
+        Err(RegisterError::Other(e)) => text_response(500, &format!("Error: {}", e)),
+    }
+}
+
+async fn login(store: &Store, body: &[u8]) -> Vec<u8> {
+    let Ok(Credentials { email: Some(email), password: Some(password) }) = serde_json::from_slice(body) else {
+        return text_response(401, "Invalid credentials");
+    };
+    match store.login(&email, &password).await {
+        Ok(Some(id)) => json_response(200, &json!({ "token": id, "email": email })),
+        Ok(None) => text_response(401, "Invalid credentials"),
+        Err(e) => text_response(500, &format!("Error: {}", e)),
+    }
+}
+
+/// `/api/group/<group>/devices` and `/api/group/<group>/allocate_ip`; the bearer
+/// token must be the group id.
+async fn group_api(request: &Request, state: &AppState) -> Vec<u8> {
+    let parts: Vec<&str> = request.path.split('/').collect();
+    let (group, action) = match parts.as_slice() {
+        ["", "api", "group", group, action] if !group.is_empty() => (*group, *action),
+        _ => return text_response(404, "Not Found"),
+    };
+    let token = request.header("authorization")
+        .map(|v| v.trim_start_matches("Bearer ").trim())
+        .unwrap_or_default();
+    if token != group {
+        warn!("[API] Unauthorized /{} for group {}", action, group);
+        return text_response(401, "Unauthorized");
+    }
+
+    match action {
+        "devices" => {
+            let room = state.rooms.lock().await.get(group).cloned();
+            let devices = match room {
+                Some(room) => room.lock().await.devices(),
+                None => Vec::new(),
+            };
+            json_response(200, &json!(devices))
+        }
+        // The map stays locked so the room can't be dropped before it holds the lease
+        "allocate_ip" => match state.rooms.lock().await.entry(group.to_string()).or_default().lock().await.allocate_ip() {
+            Some(ip) => {
+                info!("[API] Allocated IP: {} for group: {}", ip, group);
+                json_response(200, &json!({ "ip": ip.to_string() }))
+            }
+            None => text_response(507, "No available IPs"),
+        },
+        _ => text_response(404, "Not Found"),
+    }
+}
+
+/// Authenticates a `/wapi/` request, completes the WebSocket handshake and runs
+/// the session.
+async fn upgrade(mut socket: TcpStream, remote: SocketAddr, request: &Request, state: Arc<AppState>) -> Result<()> {
+    // 1. Group: the user id given as token, or the path in open mode
+    let (group, user) = match request.query("token") {
+        Some(token) => match state.store.user_exists(&token).await {
+            Ok(true) => (token.clone(), Some(token)),
+            Ok(false) => {
+                warn!("[WS] Connection rejected: Invalid token {}", token);
+                socket.write_all(&text_response(401, "Unauthorized: Invalid Token")).await?;
+                return Ok(());
+            }
+            Err(e) => {
+                socket.write_all(&text_response(500, &format!("Error: {}", e))).await?;
+                return Err(e);
+            }
+        },
+        None if state.open => {
+            let group = request.path.trim_start_matches("/wapi/").trim_matches('/');
+            let group = if group.is_empty() { "default-group" } else { group };
+            (percent_decode(group), None)
+        }
+        None => {
+            warn!("[WS] Connection rejected: Missing token");
+            socket.write_all(&text_response(401, "Unauthorized: Token required")).await?;
+            return Ok(());
+        }
+    };
+
+    // 2. Handshake
+    let key = match request.header("sec-websocket-key") {
+        Some(key) if request.header("upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket")) => key,
+        _ => {
+            socket.write_all(&text_response(426, "Expected Upgrade: websocket")).await?;
+            return Ok(());
+        }
+    };
+    let accept = derive_accept_key(key.as_bytes());
+    socket.write_all(format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    ).as_bytes()).await?;
+    let ws = WebSocketStream::from_raw_socket(socket, Role::Server, None).await;
+
+    let public_addr = public_addr(request, remote.ip(), state.trust_proxy);
+    let relays = relay_urls(request, &state);
+    info!("[WS] {} joined group {} from {}", remote, group, public_addr);
+    run_session(ws, state, group, user, public_addr, relays).await;
+    Ok(())
+}
+
+async fn run_session(
+    ws: WebSocketStream<TcpStream>,
+    state: Arc<AppState>,
+    group: String,
+    user: Option<String>,
+    public_addr: String,
+    relays: Vec<String>,
+) {
+    let (mut write, mut read) = ws.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    let (room, conn) = {
+        let mut rooms = state.rooms.lock().await;
+        let room = rooms.entry(group.clone()).or_default().clone();
+        let conn = room.lock().await.connect(tx.clone(), public_addr);
+        (room, conn)
+    };
+
+    // Ends when the room drops our sender: on disconnect, or when a newer
+    // session with the same id replaces this one
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if write.send(msg).await.is_err() {
+                break;
+            }
+        }
+        let _ = write.close().await;
+    });
+
+    while let Some(msg) = read.next().await {
+        match msg {
+            Ok(Message::Text(text)) => {
+                let joined = room.lock().await.handle_message(conn, &text);
+                let Some(peer) = joined else {
+                    continue;
+                };
+                if let Some(user) = &user {
+                    if let Err(e) = state.store.touch_device(&peer.id, user, &peer.name).await {
+                        warn!("{}", e);
+                    }
+                }
+                // Joined: hand out the packet relay and this node's token for it
+                if let Some(relay) = &state.relay {
+                    let relay_map = json!({
+                        "type": "relay_map",
+                        "relays": relays,
+                        "token": relay.token(&group, &peer.id),
+                        "cert_sha256": relay.cert_sha256,
+                    });
+                    let _ = tx.send(Message::Text(relay_map.to_string()));
+                }
+            }
+            Ok(Message::Binary(bytes)) => room.lock().await.handle_binary(conn, &bytes),
+            Ok(Message::Close(_)) | Err(_) => break,
+            _ => {}
+        }
+    }
+
+    let mut rooms = state.rooms.lock().await;
+    let idle = {
+        let mut room = room.lock().await;
+        room.disconnect(conn);
+        room.is_idle()
+    };
+    if idle {
+        rooms.remove(&group);
+    }
+    writer.abort();
+}
+
+/// Relay URLs for a node that reached us with this request: the configured ones,
+/// or our Host on the relay port.
+fn relay_urls(request: &Request, state: &AppState) -> Vec<String> {
+    let Some(relay) = &state.relay else {
+        return Vec::new();
+    };
+    if !relay.urls.is_empty() {
+        return relay.urls.clone();
+    }
+    let Some(host) = request.header("host") else {
+        return Vec::new();
+    };
+    let host = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().map(|h| format!("[{}]", h)).unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default().to_string(),
+    };
+    vec![format!("quic://{}:{}", host, relay.port)]
+}
+
+/// The client's address as seen from outside: with `trust_proxy`, a public
+/// forwarded-for address from the reverse proxy; otherwise the socket's peer, as
+/// anyone can send those headers.
+fn public_addr(request: &Request, remote: IpAddr, trust_proxy: bool) -> String {
+    if !trust_proxy {
+        return remote.to_string();
+    }
+    let forwarded = [
+        request.header("x-forwarded-for").and_then(|v| v.split(',').next()),
+        request.header("x-real-ip"),
+        request.header("cf-connecting-ip"),
+    ];
+    forwarded.into_iter()
+        .flatten()
+        .filter_map(|v| v.trim().parse::<IpAddr>().ok())
+        .find(|ip| !is_private(ip))
+        .unwrap_or(remote)
+        .to_string()
+}
+
+fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
+        IpAddr::V6(v6) => v6.is_loopback() || (v6.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split_whitespace();
+        let method = parts.next()?.to_ascii_uppercase();
+        let target = parts.next()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let headers = lines
+            .filter(|l| !l.is_empty())
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .collect();
+
+        Some(Self { method, path: path.to_string(), query: query.to_string(), headers })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn query(&self, name: &str) -> Option<String> {
+        self.query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| percent_decode(v))
+            .filter(|v| !v.is_empty())
+    }
+}
+
+/// Reads and parses the request head, then its body.
+async fn read_request(socket: &mut TcpStream) -> Result<(Request, Vec<u8>)> {
+    let (head, mut body) = read_head(socket).await?;
+    let request = match Request::parse(&head) {
+        Some(r) => r,
+        None => {
+            socket.write_all(&text_response(400, "Bad Request")).await?;
+            return Err(anyhow!("Malformed request"));
+        }
+    };
+    let content_length: usize = request.header("content-length").and_then(|v| v.parse().ok()).unwrap_or(0);
+    if content_length > MAX_BODY_LEN {
+        socket.write_all(&text_response(413, "Payload Too Large")).await?;
+        return Err(anyhow!("Request body too large"));
+    }
+    while body.len() < content_length {
+        let mut chunk = vec![0u8; content_length - body.len()];
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before request body"));
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    Ok((request, body))
+}
+
+/// Reads up to the blank line ending the request head.
+async fn read_head(socket: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before request head"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let body = buf.split_off(end + 4);
+            buf.truncate(end);
+            return Ok((String::from_utf8_lossy(&buf).to_string(), body));
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            socket.write_all(&text_response(431, "Request Header Fields Too Large")).await?;
+            return Err(anyhow!("Request head too large"));
+        }
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let decoded = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match decoded {
+                    Some(b) => {
+                        out.push(b);
+                        i += 3;
+                        continue;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn text_response(code: u16, body: &str) -> Vec<u8> {
+    response(code, Some("text/plain; charset=utf-8"), body.as_bytes())
+}
+
+fn json_response(code: u16, body: &Value) -> Vec<u8> {
+    response(code, Some("application/json"), body.to_string().as_bytes())
+}
+
+// Every response carries the Worker's CORS headers
+fn response(code: u16, content_type: Option<&str>, body: &[u8]) -> Vec<u8> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\
+         Access-Control-Allow-Headers: Content-Type, Authorization\r\n\
+         Access-Control-Max-Age: 86400\r\n\
+         Content-Length: {}\r\nConnection: close\r\n",
+        code, reason(code), body.len()
+    );
+    if let Some(content_type) = content_type {
+        head.push_str(&format!("Content-Type: {}\r\n", content_type));
+    }
+    head.push_str("\r\n");
+    let mut out = head.into_bytes();
+    out.extend_from_slice(body);
+    out
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_heads() {
+        let head = "get /api/group/g1?token=a%20b&x= HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For:  198.51.100.7 \r\nbroken line";
+        let request = Request::parse(head).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/api/group/g1");
+        assert_eq!(request.header("host"), Some("example.com"));
+        assert_eq!(request.header("x-forwarded-for"), Some("198.51.100.7"));
+        assert_eq!(request.headers.len(), 2);
+
+        assert!(Request::parse("").is_none());
+        assert!(Request::parse("GET").is_none());
+    }
+
+    #[test]
+    fn query_values_are_decoded() {
+        let request = Request::parse("GET /wapi/g?token=a%20b&name=x+y&empty=&flag HTTP/1.1").unwrap();
+        assert_eq!(request.query("token").as_deref(), Some("a b"));
+        assert_eq!(request.query("name").as_deref(), Some("x y"));
+        assert_eq!(request.query("empty"), None);
+        assert_eq!(request.query("flag"), None);
+        assert_eq!(request.query("missing"), None);
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%2Fb%3d"), "a/b=");
+        assert_eq!(percent_decode("%E4%BD%A0"), "你");
+        // Incomplete or invalid escapes stay as they are
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+}
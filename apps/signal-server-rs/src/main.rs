@@ -0,0 +1,118 @@
+mod http;
+mod relay;
+mod room;
+mod store;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use clap::Parser;
+use rand::RngCore;
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+use crate::http::AppState;
+use crate::relay::{RelayInfo, RelayServer};
+use crate::store::Store;
+
+/// Self-hosted Syuink signaling and relay server, speaking the same protocol as
+/// the Cloudflare Worker in apps/signal-server.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Address to listen on
+    #[arg(short, long, default_value = "0.0.0.0:8787")]
+    listen: String,
+
+    /// SQLite database for users and devices
+    #[arg(long, default_value = "syuink-signal.db")]
+    db: String,
+
+    /// Let nodes join without a token, grouped by the /wapi/<group> path
+    #[arg(long)]
+    open: bool,
+
+    /// Also run the QUIC packet relay on this UDP address, e.g. 0.0.0.0:8788
+    #[arg(long)]
+    relay_listen: Option<SocketAddr>,
+
+    /// Relay URL to hand to nodes, quic://host:port (repeatable), each reaching the
+    /// relay run here. Defaults to the host nodes reached this server at, on the
+    /// relay port.
+    #[arg(long = "relay-url")]
+    relay_urls: Vec<String>,
+
+    /// Secret for relay tokens; set it to keep tokens valid across restarts
+    #[arg(long)]
+    relay_secret: Option<String>,
+
+    /// Running behind a reverse proxy: take client addresses from
+    /// X-Forwarded-For, X-Real-IP and CF-Connecting-IP
+    #[arg(long)]
+    trust_proxy: bool,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // Initialize logging
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+    let store = Store::open(&args.db)?;
+    info!("Database: {}", args.db);
+    if args.open {
+        warn!("Open mode: nodes may join any group without a token");
+    }
+
+    let relay = match args.relay_listen {
+        Some(addr) => {
+            let secret = match &args.relay_secret {
+                Some(s) => s.as_bytes().to_vec(),
+                None => {
+                    let mut secret = vec![0u8; 32];
+                    rand::thread_rng().fill_bytes(&mut secret);
+                    secret
+                }
+            };
+            let server = Arc::new(RelayServer::bind(addr, secret.clone())?);
+            let port = server.local_addr()?.port();
+            let cert_sha256 = server.cert_sha256().to_string();
+            tokio::spawn(server.run());
+            Some(RelayInfo::new(args.relay_urls.clone(), port, cert_sha256, secret))
+        }
+        None => {
+            if !args.relay_urls.is_empty() {
+                warn!("--relay-url given without --relay-listen; not advertising relays");
+            }
+            None
+        }
+    };
+
+    let state = Arc::new(AppState {
+        store,
+        rooms: Default::default(),
+        open: args.open,
+        relay,
+        trust_proxy: args.trust_proxy,
+    });
+
+    let listener = TcpListener::bind(&args.listen).await?;
+    info!("Syuink signaling server listening on {}", listener.local_addr()?);
+
+    loop {
+        let (socket, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Accept failed: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(socket, remote, state).await {
+                debug!("Connection {} ended: {}", remote, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Result, anyhow, bail};
+use hmac::{Hmac, Mac};
+use quinn::{Connection, Endpoint, SendStream};
+use sha2::{Digest, Sha256};
+use shared_proto::relay::{read_frame, write_frame, Frame, HelloStatus, RelayHello, ALPN, KIND_MESSAGE, KIND_PACKET, KIND_UNREACHABLE};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+use crate::store::{hex, unhex};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HELLO_LEN: usize = 1024;
+
+/// What the signaling side needs to send nodes to the relay.
+pub struct RelayInfo {
+    /// Advertised URLs; empty to derive one from the Host the node connected to.
+    pub urls: Vec<String>,
+    pub port: u16,
+    /// hex SHA-256 of the relay's certificate, which nodes pin.
+    pub cert_sha256: String,
+    secret: Vec<u8>,
+}
+
+impl RelayInfo {
+    pub fn new(urls: Vec<String>, port: u16, cert_sha256: String, secret: Vec<u8>) -> Self {
+        Self { urls, port, cert_sha256, secret }
+    }
+
+    /// Token the relay accepts from `id` in `group`.
+    pub fn token(&self, group: &str, id: &str) -> String {
+        token(&self.secret, group, id)
+    }
+}
+
+/// DERP-style packet relay: nodes hold one QUIC connection each and send frames
+/// addressed to a peer id, which the relay hands to that peer's connection if it is
+/// in the same group. Frames are opaque to it.
+pub struct RelayServer {
+    endpoint: Endpoint,
+    cert_sha256: String,
+    secret: Vec<u8>,
+    // (group, node id) -> connection
+    clients: Mutex<HashMap<(String, String), Client>>,
+}
+
+#[derive(Clone)]
+struct Client {
+    conn: Connection,
+    // Lazily opened for frames too large for a datagram
+    overflow: Arc<Mutex<Option<SendStream>>>,
+}
+
+impl RelayServer {
+    pub fn bind(addr: SocketAddr, secret: Vec<u8>) -> Result<Self> {
+        let (endpoint, cert_sha256) = make_server_endpoint(addr)?;
+        info!("Packet relay listening on {} (certificate SHA-256 {})", endpoint.local_addr()?, cert_sha256);
+        Ok(Self {
+            endpoint,
+            cert_sha256,
+            secret,
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// hex SHA-256 of the certificate generated for this run.
+    pub fn cert_sha256(&self) -> &str {
+        &self.cert_sha256
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        while let Some(connecting) = self.endpoint.accept().await {
+            let relay = self.clone();
+            tokio::spawn(async move {
+                let remote = connecting.remote_address();
+                if let Err(e) = relay.handle(connecting).await {
+                    debug!("Relay connection {} ended: {}", remote, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, connecting: quinn::Connecting) -> Result<()> {
+        let conn = connecting.await?;
+
+        // 1. Hello
+        let (mut send, mut recv) = tokio::time::timeout(HELLO_TIMEOUT, conn.accept_bi())
+            .await
+            .map_err(|_| anyhow!("no hello"))??;
+        let hello = RelayHello::decode(&recv.read_to_end(MAX_HELLO_LEN).await?)
+            .ok_or_else(|| anyhow!("malformed hello"))?;
+        if !verify_token(&self.secret, &hello.group, &hello.id, &hello.token) {
+            warn!("Relay rejected {} from {}: bad token", hello.id, conn.remote_address());
+            send.write_all(&[HelloStatus::Unauthorized as u8]).await?;
+            let _ = send.finish().await;
+            bail!("unauthorized");
+        }
+        send.write_all(&[HelloStatus::Ok as u8]).await?;
+        let _ = send.finish().await;
+
+        // 2. Register, replacing an older connection for the same node
+        let key = (hello.group.clone(), hello.id.clone());
+        let client = Client { conn: conn.clone(), overflow: Arc::new(Mutex::new(None)) };
+        if let Some(old) = self.clients.lock().await.insert(key.clone(), client) {
+            old.conn.close(0u32.into(), b"replaced");
+        }
+        info!("Relay: {} joined (group {}) from {}", hello.id, hello.group, conn.remote_address());
+
+        // 3. Forward until it goes away
+        let streams = async {
+            // A node keeps one overflow stream open at a time
+            while let Ok(mut recv) = conn.accept_uni().await {
+                while let Ok(bytes) = read_frame(&mut recv).await {
+                    self.forward(&key, &bytes).await;
+                }
+            }
+        };
+        let datagrams = async {
+            while let Ok(dg) = conn.read_datagram().await {
+                self.forward(&key, &dg).await;
+            }
+        };
+        tokio::select! {
+            _ = streams => {}
+            _ = datagrams => {}
+        }
+
+        let mut clients = self.clients.lock().await;
+        if clients.get(&key).is_some_and(|c| c.conn.stable_id() == conn.stable_id()) {
+            clients.remove(&key);
+        }
+        info!("Relay: {} left (group {})", hello.id, hello.group);
+        Ok(())
+    }
+
+    // A frame from `from` to the peer it names in the same group
+    async fn forward(&self, from: &(String, String), bytes: &[u8]) {
+        let Some(frame) = Frame::decode(bytes) else {
+            return;
+        };
+        if frame.kind != KIND_PACKET && frame.kind != KIND_MESSAGE {
+            return;
+        }
+        let (dest, source) = {
+            let clients = self.clients.lock().await;
+            let dest = clients.get(&(from.0.clone(), frame.peer.to_string())).cloned();
+            (dest, clients.get(from).cloned())
+        };
+
+        let Some(dest) = dest else {
+            if let (Some(source), Some(notice)) = (source, Frame::new(KIND_UNREACHABLE, frame.peer, &[]).encode()) {
+                let _ = source.conn.send_datagram(notice.into());
+            }
+            return;
+        };
+        let Some(out) = frame.readdressed(&from.1) else {
+            return;
+        };
+        if let Err(e) = dest.send(out).await {
+            debug!("Relay: failed to deliver to {}: {}", frame.peer, e);
+        }
+    }
+}
+
+impl Client {
+    async fn send(&self, frame: Vec<u8>) -> Result<()> {
+        if self.conn.max_datagram_size().is_some_and(|max| frame.len() <= max) {
+            self.conn.send_datagram(frame.into())?;
+            return Ok(());
+        }
+        let mut overflow = self.overflow.lock().await;
+        if overflow.is_none() {
+            *overflow = Some(self.conn.open_uni().await?);
+        }
+        let stream = overflow.as_mut().expect("opened above");
+        let result = write_frame(stream, &frame).await;
+        if result.is_err() {
+            *overflow = None;
+        }
+        Ok(result?)
+    }
+}
+
+// HMAC-SHA256(secret, group "\n" id)
+fn token_mac(secret: &[u8], group: &str, id: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(format!("{}\n{}", group, id).as_bytes());
+    mac
+}
+
+/// hex(HMAC-SHA256(secret, group "\n" id))
+fn token(secret: &[u8], group: &str, id: &str) -> String {
+    hex(&token_mac(secret, group, id).finalize().into_bytes())
+}
+
+fn verify_token(secret: &[u8], group: &str, id: &str, token: &str) -> bool {
+    match unhex(token) {
+        Some(tag) => token_mac(secret, group, id).verify_slice(&tag).is_ok(),
+        None => false,
+    }
+}
+
+// The endpoint and hex SHA-256 of its freshly generated certificate
+fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, String)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["syuink-relay".into()])?;
+    let cert_der = rustls::Certificate(cert.serialize_der()?);
+    let cert_sha256 = hex(&Sha256::digest(&cert_der.0));
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert_der], key)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    let mut transport_config = quinn::TransportConfig::default();
+    transport_config.keep_alive_interval(Some(Duration::from_secs(5)));
+    transport_config.datagram_receive_buffer_size(Some(4 * 1024 * 1024));
+    transport_config.datagram_send_buffer_size(4 * 1024 * 1024);
+    server_config.transport_config(Arc::new(transport_config));
+
+    Ok((Endpoint::server(server_config, bind_addr)?, cert_sha256))
+}
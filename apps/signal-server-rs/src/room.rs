@@ -0,0 +1,519 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use shared_proto::data_frame::{DataFrame, KIND_BROADCAST, KIND_TCP_DATA, KIND_TUN_PACKET};
+use tokio::sync::{mpsc, Mutex};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tracing::{debug, info, warn};
+use crate::store::now_ms;
+
+// Allocated addresses are held this long for the device to join with them.
+const LEASE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Group id -> room. The map is only locked to find, create or drop a room; a
+/// session's messages lock just its own room. Lock the map before a room, never after.
+pub type Rooms = Arc<Mutex<HashMap<String, Arc<Mutex<Room>>>>>;
+
+/// One group's live state: what the Worker keeps in its Durable Object.
+#[derive(Default)]
+pub struct Room {
+    next_conn: u64,
+    sessions: HashMap<u64, Session>,
+    // Peer id -> its ServiceDecl list, as sent
+    services: HashMap<String, Vec<Value>>,
+    leases: HashMap<Ipv4Addr, Instant>,
+}
+
+struct Session {
+    tx: mpsc::UnboundedSender<Message>,
+    public_addr: String,
+    // Set once the session has joined
+    meta: Option<PeerMeta>,
+    // Takes data-plane messages as binary frames
+    binary: bool,
+}
+
+/// What a joined peer is announced with, in `peer_joined` and `/devices`.
+#[derive(Serialize, Clone, Debug)]
+pub struct PeerMeta {
+    pub id: String,
+    pub ip: String,
+    pub public_addr: String,
+    pub p2p_port: u16,
+    pub name: String,
+    pub os: String,
+    pub version: String,
+    pub device_type: String,
+    pub is_gateway: bool,
+    pub domains: Vec<String>,
+    /// Base64 key of the peer's QUIC certificate, for the others to pin.
+    pub public_key: String,
+    pub connected_at: i64,
+}
+
+impl Room {
+    /// Adds a WebSocket session; messages for it go to `tx`.
+    pub fn connect(&mut self, tx: mpsc::UnboundedSender<Message>, public_addr: String) -> u64 {
+        self.next_conn += 1;
+        let conn = self.next_conn;
+        self.sessions.insert(conn, Session { tx, public_addr, meta: None, binary: false });
+        info!("New WebSocket session {}. Total sessions: {}", conn, self.sessions.len());
+        conn
+    }
+
+    /// Handles one text message from `conn`. Returns the peer if it was a join.
+    pub fn handle_message(&mut self, conn: u64, text: &str) -> Option<PeerMeta> {
+        // A kicked duplicate may still be draining its socket
+        let session = self.sessions.get(&conn)?;
+        let msg: Value = match serde_json::from_str(text) {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("Ignoring malformed message from session {}: {}", conn, e);
+                return None;
+            }
+        };
+        let kind = msg.get("type").and_then(Value::as_str);
+
+        // Until it has joined, a session can do nothing else
+        if session.meta.is_none() && kind != Some("join") {
+            debug!("Ignoring {:?} from session {} before join", kind, conn);
+            return None;
+        }
+
+        match kind {
+            Some("register_services") => {
+                self.register_services(conn, &msg);
+                None
+            }
+            Some("join") => Some(self.join(conn, &msg)),
+            // Heartbeat: echoed to the sender as-is
+            Some("keep_alive") => {
+                let _ = session.tx.send(Message::Text(text.to_string()));
+                None
+            }
+            Some(kind) if is_relayed(kind) => {
+                // Relayed messages must be sent in the sender's own name
+                let source = msg.get("source").and_then(Value::as_str);
+                if source.is_none() || source != self.peer_id(conn) {
+                    warn!("Dropping {} from session {} claiming to be {:?}", kind, conn, source);
+                    return None;
+                }
+                let target = msg.get("target_id").or_else(|| msg.get("target")).and_then(Value::as_str);
+                match target {
+                    Some(target) => {
+                        let found = self.sessions.values()
+                            .find(|s| s.meta.as_ref().is_some_and(|m| m.id == target));
+                        match found {
+                            Some(session) => {
+                                let _ = session.tx.send(Message::Text(text.to_string()));
+                            }
+                            None => warn!("Target {} not found for message type: {}", target, kind),
+                        }
+                    }
+                    None => self.broadcast(text, Some(conn)),
+                }
+                None
+            }
+            // Server-to-node kinds (peer_joined, relay_map, ...) and anything unknown
+            kind => {
+                warn!("Dropping {:?} from session {}: not a peer-to-peer message", kind, conn);
+                None
+            }
+        }
+    }
+
+    /// Handles one binary data frame from `conn`: relayed like its JSON form, and
+    /// converted to JSON for sessions that didn't ask for binary framing.
+    pub fn handle_binary(&self, conn: u64, bytes: &[u8]) {
+        // Only joined sessions, in their own name
+        let Some(sender) = self.peer_id(conn) else {
+            return;
+        };
+        let Some(frame) = DataFrame::decode(bytes) else {
+            debug!("Ignoring malformed binary frame from session {}", conn);
+            return;
+        };
+        if frame.source != sender {
+            warn!("Dropping binary frame from session {} claiming to be {}", conn, frame.source);
+            return;
+        }
+        if frame.target.is_empty() {
+            for (_, session) in self.sessions.iter().filter(|(c, _)| **c != conn) {
+                session.send_data(&frame, bytes);
+            }
+            return;
+        }
+        match self.sessions.values().find(|s| s.meta.as_ref().is_some_and(|m| m.id == frame.target)) {
+            Some(session) => session.send_data(&frame, bytes),
+            None => warn!("Target {} not found for binary frame kind {}", frame.target, frame.kind),
+        }
+    }
+
+    /// Drops `conn`, telling the others if it had joined.
+    pub fn disconnect(&mut self, conn: u64) {
+        let Some(session) = self.sessions.remove(&conn) else {
+            return;
+        };
+        if let Some(meta) = session.meta {
+            self.broadcast_json(&json!({ "type": "peer_left", "id": meta.id }), None);
+            if self.services.remove(&meta.id).is_some() {
+                self.broadcast_service_update();
+            }
+            if let Ok(ip) = meta.ip.parse() {
+                self.leases.remove(&ip);
+            }
+        }
+        info!("WebSocket session {} closed. Total sessions: {}", conn, self.sessions.len());
+    }
+
+    /// Nothing left worth keeping in memory.
+    pub fn is_idle(&mut self) -> bool {
+        self.prune_leases();
+        self.sessions.is_empty() && self.leases.is_empty()
+    }
+
+    pub fn devices(&self) -> Vec<PeerMeta> {
+        self.sessions.values().filter_map(|s| s.meta.clone()).collect()
+    }
+
+    /// First overlay address not used by a session or an unexpired lease.
+    pub fn allocate_ip(&mut self) -> Option<Ipv4Addr> {
+        self.prune_leases();
+        let used: HashSet<Ipv4Addr> = self.sessions.values()
+            .filter_map(|s| s.meta.as_ref()?.ip.parse().ok())
+            .chain(self.leases.keys().copied())
+            .collect();
+        let ip = (2..255).map(|i| Ipv4Addr::new(10, 251, 0, i)).find(|ip| !used.contains(ip))?;
+        self.leases.insert(ip, Instant::now());
+        Some(ip)
+    }
+
+    // The id `conn` joined with
+    fn peer_id(&self, conn: u64) -> Option<&str> {
+        self.sessions.get(&conn)?.meta.as_ref().map(|m| m.id.as_str())
+    }
+
+    fn join(&mut self, conn: u64, msg: &Value) -> PeerMeta {
+        let id = clamp(msg.get("id"), 128);
+        info!("[JOIN] {} (Name: {}, IP: {})", id, clamp(msg.get("name"), 128), clamp(msg.get("ip"), 32));
+
+        // 0. A second session with the same id replaces the first. Dropping its
+        // sender closes the socket; it is already gone, so no peer_left goes out.
+        self.sessions.retain(|c, s| {
+            let duplicate = *c != conn && s.meta.as_ref().is_some_and(|m| m.id == id);
+            if duplicate {
+                info!("[JOIN] Kicking duplicate session for ID: {}", id);
+            }
+            !duplicate
+        });
+
+        let public_addr = self.sessions.get(&conn).map(|s| s.public_addr.clone()).unwrap_or_default();
+        let is_gateway = msg.get("is_gateway").and_then(Value::as_bool).unwrap_or(false);
+        let meta = PeerMeta {
+            id,
+            ip: clamp(msg.get("ip"), 32),
+            public_addr,
+            p2p_port: msg.get("p2p_port").and_then(Value::as_u64).and_then(|p| u16::try_from(p).ok()).unwrap_or(0),
+            name: clamp(msg.get("name"), 128),
+            os: clamp(msg.get("os"), 128),
+            version: clamp(msg.get("version"), 128),
+            device_type: clamp(msg.get("device_type"), 32),
+            is_gateway,
+            // Only gateways resolve LAN domains, and only real multi-label names
+            domains: msg.get("domains").and_then(Value::as_array)
+                .filter(|_| is_gateway)
+                .map(|d| d.iter().take(16).map(|d| clamp(Some(d), 253).trim_matches('.').to_ascii_lowercase()).filter(|d| is_lan_domain(d)).collect())
+                .unwrap_or_default(),
+            public_key: clamp(msg.get("public_key"), 128),
+            connected_at: now_ms(),
+        };
+        if let Ok(ip) = meta.ip.parse() {
+            self.prune_leases();
+            self.leases.insert(ip, Instant::now());
+        }
+        let binary = msg.get("binary_frames").and_then(Value::as_bool).unwrap_or(false);
+        if let Some(session) = self.sessions.get_mut(&conn) {
+            session.meta = Some(meta.clone());
+            session.binary = binary;
+            let features = json!({ "type": "features", "binary_frames": binary, "keep_alive": true });
+            let _ = session.tx.send(Message::Text(features.to_string()));
+        }
+
+        // 1. Existing peers to the joiner
+        if let Some(session) = self.sessions.get(&conn) {
+            for other in self.sessions.iter().filter(|(c, _)| **c != conn).filter_map(|(_, s)| s.meta.as_ref()) {
+                let _ = session.tx.send(Message::Text(peer_joined(other).to_string()));
+            }
+        }
+
+        // 2. Current services to everyone, the joiner to the others
+        self.broadcast_service_update();
+        self.broadcast_json(&peer_joined(&meta), Some(conn));
+        meta
+    }
+
+    // Always in the session's own name; the message's `id` is not trusted
+    fn register_services(&mut self, conn: u64, msg: &Value) {
+        let Some(sender) = self.peer_id(conn).map(str::to_string) else {
+            return;
+        };
+        let Some(services) = msg.get("services").and_then(Value::as_array) else {
+            return;
+        };
+
+        let mut accepted = Vec::with_capacity(services.len());
+        for svc in services {
+            let ip = svc.get("ip").and_then(Value::as_str).filter(|ip| is_dotted_quad(ip));
+            let port = svc.get("port").and_then(Value::as_u64).filter(|p| (1..=65535).contains(p));
+            let protocol = svc.get("protocol").and_then(Value::as_str).and_then(normalize_protocol);
+            let (Some(ip), Some(port), Some(protocol)) = (ip, port, protocol) else {
+                warn!("Service registration rejected (invalid fields) for {}", sender);
+                return;
+            };
+
+            // Any other peer's service on the same ip:port conflicts, whatever the protocol
+            let conflict = self.services.iter()
+                .filter(|(pid, _)| **pid != sender)
+                .flat_map(|(_, svcs)| svcs)
+                .any(|existing| {
+                    existing.get("ip").and_then(Value::as_str) == Some(ip)
+                        && existing.get("port").and_then(Value::as_u64) == Some(port)
+                        && existing.get("protocol").and_then(Value::as_str).and_then(normalize_protocol).is_some()
+                });
+            if conflict {
+                warn!("Service conflict detected for {} on {}:{}", sender, ip, port);
+                return;
+            }
+
+            let mut svc = svc.clone();
+            svc["protocol"] = Value::String(protocol.to_string());
+            accepted.push(svc);
+        }
+
+        self.services.insert(sender, accepted);
+        self.broadcast_service_update();
+    }
+
+    fn broadcast_service_update(&self) {
+        let services: Vec<Value> = self.services.iter()
+            .flat_map(|(pid, svcs)| svcs.iter().map(move |s| json!([pid, s])))
+            .collect();
+        self.broadcast_json(&json!({ "type": "service_update", "services": services }), None);
+    }
+
+    fn broadcast_json(&self, msg: &Value, except: Option<u64>) {
+        self.broadcast(&msg.to_string(), except);
+    }
+
+    // To every session except `except`, joined or not, as the Worker does
+    fn broadcast(&self, text: &str, except: Option<u64>) {
+        for (conn, session) in &self.sessions {
+            if Some(*conn) != except {
+                let _ = session.tx.send(Message::Text(text.to_string()));
+            }
+        }
+    }
+
+    fn prune_leases(&mut self) {
+        let before = self.leases.len();
+        self.leases.retain(|_, ts| ts.elapsed() < LEASE_TTL);
+        if self.leases.len() < before {
+            debug!("Pruned {} expired leases", before - self.leases.len());
+        }
+    }
+}
+
+impl Session {
+    // `raw` is the frame as received, passed through to binary sessions
+    fn send_data(&self, frame: &DataFrame, raw: &[u8]) {
+        if self.binary {
+            let _ = self.tx.send(Message::Binary(raw.to_vec()));
+            return;
+        }
+        let data = BASE64.encode(frame.payload);
+        let msg = match frame.kind {
+            KIND_TUN_PACKET => json!({ "type": "tun_packet", "target": frame.target, "source": frame.source, "data": data }),
+            KIND_BROADCAST => json!({ "type": "broadcast", "source": frame.source, "data": data }),
+            KIND_TCP_DATA => json!({
+                "type": "tcp_data", "stream_id": frame.stream_id,
+                "target": frame.target, "source": frame.source, "data": data,
+            }),
+            _ => return,
+        };
+        let _ = self.tx.send(Message::Text(msg.to_string()));
+    }
+}
+
+fn peer_joined(meta: &PeerMeta) -> Value {
+    let mut msg = Map::new();
+    msg.insert("type".to_string(), Value::String("peer_joined".to_string()));
+    if let Value::Object(fields) = json!(meta) {
+        msg.extend(fields);
+    }
+    Value::Object(msg)
+}
+
+// The kinds one peer may address to others through the server
+fn is_relayed(kind: &str) -> bool {
+    matches!(kind, "offer" | "answer" | "candidate" | "broadcast" | "tun_packet")
+        || ["tcp_", "udp_", "dns_"].iter().any(|prefix| kind.starts_with(prefix))
+}
+
+// Strings only, cut to `max` characters; anything else becomes empty
+fn clamp(value: Option<&Value>, max: usize) -> String {
+    value.and_then(Value::as_str).map(|s| s.chars().take(max).collect()).unwrap_or_default()
+}
+
+// Dot-separated LDH labels, at least two of them
+fn is_lan_domain(domain: &str) -> bool {
+    domain.contains('.')
+        && domain.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        })
+}
+
+fn normalize_protocol(protocol: &str) -> Option<&'static str> {
+    match protocol.to_ascii_lowercase().as_str() {
+        "tcp" => Some("tcp"),
+        "udp" => Some("udp"),
+        "both" => Some("both"),
+        _ => None,
+    }
+}
+
+// Four dot-separated groups of 1-3 digits, like the Worker's regex
+fn is_dotted_quad(ip: &str) -> bool {
+    let parts: Vec<&str> = ip.split('.').collect();
+    parts.len() == 4 && parts.iter().all(|p| (1..=3).contains(&p.len()) && p.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(room: &mut Room) -> (u64, mpsc::UnboundedReceiver<Message>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (room.connect(tx, "203.0.113.1:1000".to_string()), rx)
+    }
+
+    fn joined(room: &mut Room, id: &str, ip: &str) -> (u64, mpsc::UnboundedReceiver<Message>) {
+        let (conn, mut rx) = session(room);
+        room.handle_message(conn, &json!({ "type": "join", "id": id, "ip": ip }).to_string());
+        while rx.try_recv().is_ok() {}
+        (conn, rx)
+    }
+
+    fn received(rx: &mut mpsc::UnboundedReceiver<Message>) -> Vec<Value> {
+        let mut out = Vec::new();
+        while let Ok(Message::Text(text)) = rx.try_recv() {
+            out.push(serde_json::from_str(&text).unwrap());
+        }
+        out
+    }
+
+    #[test]
+    fn duplicate_join_kicks_the_older_session() {
+        let mut room = Room::default();
+        let (old, mut old_rx) = joined(&mut room, "a", "10.251.0.2");
+        let (new, _new_rx) = joined(&mut room, "a", "10.251.0.2");
+
+        assert_ne!(old, new);
+        assert_eq!(room.devices().len(), 1);
+        // The kicked session's sender is dropped, closing its socket
+        assert!(matches!(old_rx.try_recv(), Err(mpsc::error::TryRecvError::Disconnected)));
+        assert!(room.handle_message(old, &json!({ "type": "keep_alive", "ts": 1 }).to_string()).is_none());
+    }
+
+    #[test]
+    fn leases_hold_addresses_until_released() {
+        let mut room = Room::default();
+        let first = room.allocate_ip().unwrap();
+        let second = room.allocate_ip().unwrap();
+        assert_eq!(first, Ipv4Addr::new(10, 251, 0, 2));
+        assert_eq!(second, Ipv4Addr::new(10, 251, 0, 3));
+
+        // A joined peer keeps its address; leaving frees it
+        let (conn, _rx) = joined(&mut room, "a", "10.251.0.4");
+        assert_eq!(room.allocate_ip(), Some(Ipv4Addr::new(10, 251, 0, 5)));
+        room.disconnect(conn);
+        assert_eq!(room.allocate_ip(), Some(Ipv4Addr::new(10, 251, 0, 4)));
+    }
+
+    #[test]
+    fn relayed_messages_must_come_from_the_sender() {
+        let mut room = Room::default();
+        let (a, _a_rx) = joined(&mut room, "a", "10.251.0.2");
+        let (_b, mut b_rx) = joined(&mut room, "b", "10.251.0.3");
+
+        let spoofed = json!({ "type": "offer", "target": "b", "source": "c", "sdp": "" });
+        room.handle_message(a, &spoofed.to_string());
+        let anonymous = json!({ "type": "offer", "target": "b", "sdp": "" });
+        room.handle_message(a, &anonymous.to_string());
+        assert!(received(&mut b_rx).is_empty());
+
+        let genuine = json!({ "type": "offer", "target": "b", "source": "a", "sdp": "" });
+        room.handle_message(a, &genuine.to_string());
+        assert_eq!(received(&mut b_rx), vec![genuine]);
+    }
+
+    #[test]
+    fn server_messages_cannot_be_forged() {
+        let mut room = Room::default();
+        let (a, _a_rx) = joined(&mut room, "a", "10.251.0.2");
+        let (_b, mut b_rx) = joined(&mut room, "b", "10.251.0.3");
+
+        for kind in ["peer_joined", "peer_left", "relay_map", "service_update", "features"] {
+            room.handle_message(a, &json!({ "type": kind, "id": "c", "source": "a" }).to_string());
+        }
+        assert!(received(&mut b_rx).is_empty());
+    }
+
+    #[test]
+    fn sessions_that_have_not_joined_are_ignored() {
+        let mut room = Room::default();
+        let (_a, mut a_rx) = joined(&mut room, "a", "10.251.0.2");
+        let (lurker, mut lurker_rx) = session(&mut room);
+
+        let offer = json!({ "type": "offer", "target": "a", "source": "", "sdp": "" });
+        room.handle_message(lurker, &offer.to_string());
+        room.handle_message(lurker, &json!({ "type": "keep_alive", "ts": 1 }).to_string());
+        let services = json!({ "type": "register_services", "id": "a", "services": [] });
+        room.handle_message(lurker, &services.to_string());
+
+        assert!(received(&mut a_rx).is_empty());
+        assert!(received(&mut lurker_rx).is_empty());
+    }
+
+    #[test]
+    fn services_are_registered_in_the_senders_name() {
+        let mut room = Room::default();
+        let (a, mut a_rx) = joined(&mut room, "a", "10.251.0.2");
+        let services = json!({
+            "type": "register_services", "id": "b",
+            "services": [{ "ip": "192.168.1.10", "port": 80, "protocol": "TCP" }],
+        });
+        room.handle_message(a, &services.to_string());
+
+        let update = received(&mut a_rx).pop().unwrap();
+        assert_eq!(update["type"], "service_update");
+        assert_eq!(update["services"][0][0], "a");
+        assert_eq!(update["services"][0][1]["protocol"], "tcp");
+    }
+
+    #[test]
+    fn relayed_kinds() {
+        for kind in ["offer", "answer", "candidate", "broadcast", "tun_packet", "tcp_data", "udp_reply", "dns_query"] {
+            assert!(is_relayed(kind), "{}", kind);
+        }
+        for kind in ["join", "peer_joined", "peer_left", "relay_map", "service_update", "features", "register_services"] {
+            assert!(!is_relayed(kind), "{}", kind);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Result, anyhow};
+use rand::RngCore;
+use ring::pbkdf2;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+// Same tables as the Worker's D1 database, so a dump of one loads into the other.
+const INIT_SQL: &str = "
+CREATE TABLE IF NOT EXISTS users (id TEXT PRIMARY KEY, email TEXT NOT NULL UNIQUE, password_hash TEXT NOT NULL, created_at INTEGER NOT NULL);
+CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, user_id TEXT NOT NULL, name TEXT NOT NULL, last_seen INTEGER, FOREIGN KEY (user_id) REFERENCES users(id));
+";
+
+static PBKDF2_ALG: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+// Stored as "pbkdf2-sha256$<iterations>$<salt hex>$<hash hex>"
+const PBKDF2_ITERATIONS: u32 = 600_000;
+const PBKDF2_PREFIX: &str = "pbkdf2-sha256$";
+
+pub enum RegisterError {
+    EmailTaken,
+    Other(anyhow::Error),
+}
+
+/// Users and devices in SQLite. rusqlite and password hashing both block, so every
+/// call runs on the blocking thread pool.
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(INIT_SQL)?;
+        migrate_plaintext_passwords(&conn)?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    async fn with_conn<T: Send + 'static>(&self, f: impl FnOnce(&Connection) -> T + Send + 'static) -> Result<T> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&conn.lock().unwrap()))
+            .await
+            .map_err(|e| anyhow!("Database task failed: {}", e))
+    }
+
+    /// New user; returns its id, which is also its login token and group.
+    pub async fn register(&self, email: &str, password: &str) -> Result<String, RegisterError> {
+        let (email, password) = (email.to_string(), password.to_string());
+        self.with_conn(move |conn| -> Result<String, RegisterError> {
+            let id = uuid::Uuid::new_v4().to_string();
+            match conn.execute(
+                "INSERT INTO users (id, email, password_hash, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![id, email, hash_password(&password), now_ms()],
+            ) {
+                Ok(_) => Ok(id),
+                Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+                    Err(RegisterError::EmailTaken)
+                }
+                Err(e) => Err(RegisterError::Other(e.into())),
+            }
+        })
+        .await
+        .map_err(RegisterError::Other)?
+    }
+
+    /// The user's id if the credentials match. A hash in an older format is
+    /// replaced with a current one on the way.
+    pub async fn login(&self, email: &str, password: &str) -> Result<Option<String>> {
+        let (email, password) = (email.to_string(), password.to_string());
+        self.with_conn(move |conn| -> Result<Option<String>> {
+            let row: Option<(String, String)> = conn
+                .query_row(
+                    "SELECT id, password_hash FROM users WHERE email = ?1",
+                    params![email],
+                    |r| Ok((r.get(0)?, r.get(1)?)),
+                )
+                .optional()?;
+            let (id, stored) = match row {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+            if !verify_password(&password, &stored) {
+                return Ok(None);
+            }
+            if !stored.starts_with(PBKDF2_PREFIX) {
+                conn.execute(
+                    "UPDATE users SET password_hash = ?1 WHERE id = ?2",
+                    params![hash_password(&password), id],
+                )?;
+            }
+            Ok(Some(id))
+        })
+        .await?
+    }
+
+    pub async fn user_exists(&self, id: &str) -> Result<bool> {
+        let id = id.to_string();
+        self.with_conn(move |conn| -> Result<bool> {
+            let found = conn
+                .query_row("SELECT 1 FROM users WHERE id = ?1", params![id], |_| Ok(()))
+                .optional()?;
+            Ok(found.is_some())
+        })
+        .await?
+    }
+
+    /// Records a device joining `user_id`'s group.
+    pub async fn touch_device(&self, id: &str, user_id: &str, name: &str) -> Result<()> {
+        let (id, user_id, name) = (id.to_string(), user_id.to_string(), name.to_string());
+        self.with_conn(move |conn| -> Result<()> {
+            conn.execute(
+                "INSERT INTO devices (id, user_id, name, last_seen) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(id) DO UPDATE SET user_id = excluded.user_id, name = excluded.name, last_seen = excluded.last_seen",
+                params![id, user_id, name, now_ms()],
+            )
+            .map_err(|e| anyhow!("Failed to record device {}: {}", id, e))?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+// Rows imported from the Worker hold the password as-is; hash them before serving.
+fn migrate_plaintext_passwords(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT id, password_hash FROM users")?;
+    let plaintext: Vec<(String, String)> = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .filter_map(|row| row.ok())
+        .filter(|(_, stored): &(String, String)| !stored.starts_with(PBKDF2_PREFIX) && !stored.starts_with("sha256$"))
+        .collect();
+    if plaintext.is_empty() {
+        return Ok(());
+    }
+    info!("Hashing {} plaintext password(s) imported from the Worker", plaintext.len());
+    for (id, password) in plaintext {
+        conn.execute("UPDATE users SET password_hash = ?1 WHERE id = ?2", params![hash_password(&password), id])?;
+    }
+    Ok(())
+}
+
+fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut hash = [0u8; 32];
+    pbkdf2::derive(PBKDF2_ALG, NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(), &salt, password.as_bytes(), &mut hash);
+    format!("{}{}${}${}", PBKDF2_PREFIX, PBKDF2_ITERATIONS, hex(&salt), hex(&hash))
+}
+
+fn verify_password(password: &str, stored: &str) -> bool {
+    if let Some(rest) = stored.strip_prefix(PBKDF2_PREFIX) {
+        let parts: Vec<&str> = rest.split('$').collect();
+        let (iterations, salt, hash) = match parts.as_slice() {
+            [iterations, salt, hash] => (iterations.parse::<u32>().ok().and_then(NonZeroU32::new), unhex(salt), unhex(hash)),
+            _ => return false,
+        };
+        return match (iterations, salt, hash) {
+            (Some(iterations), Some(salt), Some(hash)) => {
+                pbkdf2::verify(PBKDF2_ALG, iterations, &salt, password.as_bytes(), &hash).is_ok()
+            }
+            _ => false,
+        };
+    }
+    // Single-round hashes from earlier versions, upgraded by `login`
+    match stored.strip_prefix("sha256$").and_then(|rest| rest.split_once('$')) {
+        Some((salt, expected)) => constant_time_eq(legacy_digest(salt, password).as_bytes(), expected.as_bytes()),
+        None => false,
+    }
+}
+
+fn legacy_digest(salt: &str, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(password.as_bytes());
+    hex(&hasher.finalize())
+}
+
+pub fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Few iterations, so the test doesn't pay for a real hash
+    fn pbkdf2_hash(password: &str, iterations: u32) -> String {
+        let salt = [7u8; 16];
+        let mut hash = [0u8; 32];
+        pbkdf2::derive(PBKDF2_ALG, NonZeroU32::new(iterations).unwrap(), &salt, password.as_bytes(), &mut hash);
+        format!("{}{}${}${}", PBKDF2_PREFIX, iterations, hex(&salt), hex(&hash))
+    }
+
+    #[test]
+    fn verifies_pbkdf2_hashes() {
+        let stored = pbkdf2_hash("secret", 10);
+        assert!(verify_password("secret", &stored));
+        assert!(!verify_password("Secret", &stored));
+        assert!(!verify_password("secret", &stored.replace("$10$", "$11$")));
+        assert!(!verify_password("secret", "pbkdf2-sha256$10$zz$00"));
+        assert!(!verify_password("secret", "pbkdf2-sha256$0$00$00"));
+        assert!(!verify_password("secret", "pbkdf2-sha256$10"));
+    }
+
+    #[test]
+    fn verifies_legacy_hashes() {
+        let stored = format!("sha256$salt${}", legacy_digest("salt", "secret"));
+        assert!(verify_password("secret", &stored));
+        assert!(!verify_password("other", &stored));
+        assert!(!verify_password("secret", "secret"));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(unhex(&hex(&[0, 0xab, 0xff])), Some(vec![0, 0xab, 0xff]));
+        assert_eq!(unhex("abc"), None);
+        assert_eq!(unhex("zz"), None);
+    }
+}
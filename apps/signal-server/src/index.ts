@@ -7,6 +7,9 @@ const INIT_USERS_SQL = "CREATE TABLE IF NOT EXISTS users (id TEXT PRIMARY KEY, e
 
 const INIT_DEVICES_SQL = "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, user_id TEXT NOT NULL, name TEXT NOT NULL, last_seen INTEGER, FOREIGN KEY (user_id) REFERENCES users(id));";
 
+// The kinds one peer may address to others through the server
+const RELAYED_KINDS = /^(offer|answer|candidate|broadcast|tun_packet|tcp_\w+|udp_\w+|dns_\w+)$/;
+
 export default {
 	async fetch(request: Request, env: Env, ctx: ExecutionContext): Promise<Response> {
 		// Initialize DB on first access (cheap check)
@@ -153,6 +156,8 @@ export class SignalRoom {
         version?: string, 
         device_type?: string, 
         is_gateway?: boolean,
+        domains?: string[],
+        public_key?: string,
         connected_at?: number,
         replaced?: boolean 
     }>;
@@ -342,10 +347,15 @@ export class SignalRoom {
 		try {
 			const msgStr = data as string;
 			const msg = JSON.parse(msgStr);
-			
-			// Handle Service Registration
+			const senderId = this.sessions.get(sender)?.id;
+
+			// Until it has joined, a session can do nothing else
+			if (!senderId && msg.type !== 'join') {
+				return;
+			}
+
+			// Handle Service Registration, always in the session's own name
 			if (msg.type === 'register_services') {
-				const senderId = msg.id;
 				const newServices = msg.services; // Array of ServiceDecl
 
                 const ipRegex = /^\d{1,3}(?:\.\d{1,3}){3}$/;
@@ -398,6 +408,12 @@ export class SignalRoom {
 				return;
 			}
 
+			// Heartbeat: echo it back to the sender unchanged
+			if (msg.type === 'keep_alive') {
+				this.safeSend(sender, msgStr);
+				return;
+			}
+
 			// Intercept JOIN message to update metadata
 			if (msg.type === 'join') {
                 console.log(`[JOIN] Received join request from ${msg.id} (Name: ${msg.name}, IP: ${msg.ip})`);
@@ -431,6 +447,13 @@ export class SignalRoom {
                     version: this.clamp(msg.version),
                     device_type: this.clamp(msg.device_type, 32),
                     is_gateway: !!msg.is_gateway,
+                    // Only gateways resolve LAN domains, and only real multi-label names
+                    domains: msg.is_gateway && Array.isArray(msg.domains)
+                        ? msg.domains.slice(0, 16)
+                            .map((d: any) => this.clamp(d, 253).replace(/^\.+|\.+$/g, '').toLowerCase())
+                            .filter((d: string) => /^(?!-)[a-z0-9-]{1,63}(?<!-)(\.(?!-)[a-z0-9-]{1,63}(?<!-))+$/.test(d))
+                        : [],
+                    public_key: this.clamp(msg.public_key),
                     connected_at: Date.now()
                 };
                 if (meta.ip) {
@@ -446,6 +469,8 @@ export class SignalRoom {
                 }
 				this.sessions.set(sender, meta);
 				console.log(`[JOIN] Session stored. Total active sessions: ${this.sessions.size}`);
+				// Binary framing is not supported here; heartbeats are echoed
+				this.safeSend(sender, JSON.stringify({ type: 'features', binary_frames: false, keep_alive: true }));
 
 				// 1. Send existing peers to the new joiner
                 let sentCount = 0;
@@ -476,11 +501,19 @@ export class SignalRoom {
 				return;
 			}
 
-			// Forward other messages
+			// Forward peer-to-peer messages, sent in the sender's own name
+			if (typeof msg.type !== 'string' || !RELAYED_KINDS.test(msg.type)) {
+				console.warn(`Dropping '${msg.type}' from ${senderId}: not a peer-to-peer message`);
+				return;
+			}
+			if (msg.source !== senderId) {
+				console.warn(`Dropping '${msg.type}' from ${senderId} claiming to be ${msg.source}`);
+				return;
+			}
             const targetId = msg.target_id || msg.target; // Support both fields
 			if (targetId) {
 				// Find target socket
-                console.log(`[FORWARD] Relaying message type '${msg.type}' from ${this.sessions.get(sender)?.id || 'unknown'} to ${targetId}`);
+                console.log(`[FORWARD] Relaying message type '${msg.type}' from ${senderId} to ${targetId}`);
                 let found = false;
 				for (const [ws, meta] of this.sessions) {
 					if (meta.id === targetId) {
@@ -494,7 +527,7 @@ export class SignalRoom {
                 }
 			} else {
 				// Broadcast
-				console.log(`[BROADCAST] Broadcasting message type '${msg.type}' from ${this.sessions.get(sender)?.id || 'unknown'}`);
+				console.log(`[BROADCAST] Broadcasting message type '${msg.type}' from ${senderId}`);
 				this.broadcast(msg, sender);
 			}
 		} catch (e) {
@@ -73,7 +73,7 @@ impl BroadcastReflector {
     }
 }
 
-fn create_multicast_socket(multicast_addr: Ipv4Addr, port: u16) -> Result<UdpSocket> {
+pub(crate) fn create_multicast_socket(multicast_addr: Ipv4Addr, port: u16) -> Result<UdpSocket> {
     let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
     
     // 1. 设置地址重用，允许共用端口
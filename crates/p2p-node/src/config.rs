@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+use crate::gateway::GatewayConfig;
+use crate::rules::RuleSet;
+use crate::forward::{LocalForward, PublishedPort};
+use crate::dns::DnsConfig;
+use crate::static_peers::{NodeKey, StaticPeer};
+use crate::lan_discovery::LanDiscoveryConfig;
+
+/// Which NAT implementation a gateway node uses to reach its LAN.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GatewayBackend {
+    /// `GatewayRouter`: userspace UDP/ICMP NAT, works on every platform.
+    #[default]
+    Userspace,
+    /// Linux only: IP forwarding plus nftables masquerade, handled by the kernel.
+    KernelNat,
+}
+
+/// Optional node settings. Everything here has a sensible default.
+#[derive(Clone, Debug, Default)]
+pub struct NodeConfig {
+    pub gateway_backend: GatewayBackend,
+    pub gateway: GatewayConfig,
+    /// LAN DNS domains this node resolves for others when acting as a gateway.
+    pub lan_domains: Vec<String>,
+    /// Rules the local proxies apply to each connection.
+    pub rules: RuleSet,
+    /// Preferred HTTP proxy port (default 1081, 0 for any); falls back to a random port.
+    pub http_proxy_port: Option<u16>,
+    /// Let loopback clients use the HTTP proxy without credentials. Apps sent to it
+    /// by the OS proxy settings can't answer its 407 challenge.
+    pub http_proxy_trust_loopback: bool,
+    /// Local ports forwarded to peers from startup.
+    pub local_forwards: Vec<LocalForward>,
+    /// Local services published on our virtual IP from startup.
+    pub published_ports: Vec<PublishedPort>,
+    /// MagicDNS resolver for peer names.
+    pub dns: DnsConfig,
+    /// Long-term key for direct QUIC; a fresh one is generated per run if unset.
+    pub key: Option<NodeKey>,
+    /// UDP port for direct QUIC (default: any free port).
+    pub p2p_port: Option<u16>,
+    /// Peers reached without a signaling server.
+    pub static_peers: Vec<StaticPeer>,
+    /// Finding peers of our group on the local network.
+    pub lan_discovery: LanDiscoveryConfig,
+}
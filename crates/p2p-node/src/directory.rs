@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use ipnetwork::Ipv4Network;
+use tokio::sync::Mutex;
+use crate::split_dns::is_dns_name;
+use crate::PeerInfo;
+
+// Names a gateway may claim for its LAN without the user's say-so: private-use
+// suffixes that never resolve on the internet
+const PRIVATE_SUFFIXES: &[&str] = &["home.arpa", "internal", "lan", "local", "localdomain", "home", "corp", "intranet", "private"];
+
+/// Where a proxied connection should go inside the mesh.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeshTarget {
+    pub peer_id: String,
+    /// Host the owning peer should connect to: an IP literal, or a LAN hostname
+    /// that the peer resolves with its own DNS.
+    pub host: String,
+}
+
+/// Mesh lookup state shared with the local proxies, kept current by the node's event loop.
+#[derive(Clone, Default)]
+pub struct MeshDirectory {
+    // Service IP -> owning Peer ID
+    routes: Arc<Mutex<HashMap<IpAddr, String>>>,
+    // Routed prefixes -> Peer ID, most specific first (static peers)
+    prefixes: Arc<Mutex<Vec<(Ipv4Network, String)>>>,
+    peers: Arc<Mutex<Vec<PeerInfo>>>,
+    // MagicDNS domain peers are named under, besides `.local`
+    domain: String,
+    // LAN domains the user lets gateways claim outside `PRIVATE_SUFFIXES`
+    accepted_domains: Vec<String>,
+}
+
+impl MeshDirectory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also resolves device names under `domain`, e.g. `nas.syuink.internal`.
+    pub fn with_domain(mut self, domain: &str) -> Self {
+        self.domain = domain.trim_matches('.').to_ascii_lowercase();
+        self
+    }
+
+    /// Lets gateways claim `domains` even though they are single-label or public names.
+    pub fn with_accepted_domains(mut self, domains: &[String]) -> Self {
+        self.accepted_domains = domains.iter().map(|d| d.trim_matches('.').to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// The LAN domains `peer` may resolve and route for us: none unless it is a
+    /// gateway, and only valid names under a private-use suffix or accepted by the user.
+    /// Anything else would pull a public domain's lookups and traffic to that peer.
+    pub fn lan_domains(&self, peer: &PeerInfo) -> Vec<String> {
+        if !peer.is_gateway {
+            return Vec::new();
+        }
+        peer.domains.iter()
+            .map(|d| d.trim_matches('.').to_ascii_lowercase())
+            .filter(|d| is_dns_name(d))
+            .filter(|d| self.accepted_domains.contains(d) || is_private_domain(d))
+            .collect()
+    }
+
+    pub async fn set_routes(&self, routes: HashMap<IpAddr, String>) {
+        *self.routes.lock().await = routes;
+    }
+
+    /// Adds one route, e.g. an address learned from a gateway's DNS answer.
+    pub async fn add_route(&self, ip: IpAddr, peer_id: String) {
+        self.routes.lock().await.insert(ip, peer_id);
+    }
+
+    /// Prefixes routed through a peer, most specific first.
+    pub async fn set_prefixes(&self, prefixes: Vec<(Ipv4Network, String)>) {
+        *self.prefixes.lock().await = prefixes;
+    }
+
+    pub async fn set_peers(&self, peers: Vec<PeerInfo>) {
+        *self.peers.lock().await = peers;
+    }
+
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.lock().await.clone()
+    }
+
+    /// Online peer by device name, DNS label or ID.
+    pub async fn find_peer(&self, name: &str) -> Option<PeerInfo> {
+        self.peers.lock().await.iter().find(|p| peer_matches(p, name)).cloned()
+    }
+
+    /// Resolves an IP literal or hostname to the peer that can reach it.
+    ///
+    /// In order: advertised service IPs, peer virtual IPs, routed prefixes, bare device
+    /// labels such as `nas`, gateway LAN domains (handed to the gateway unresolved), then
+    /// device labels under `.local` or the MagicDNS domain such as `nas.local`. A device
+    /// name is only ever matched as its DNS label, so a peer calling itself "github.com"
+    /// is `github-com` and can't capture that name.
+    pub async fn resolve(&self, host: &str) -> Option<MeshTarget> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        // "[2001:db8::1]" as written in URLs
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            let host = ip.to_string();
+            if let Some(peer_id) = self.routes.lock().await.get(&ip) {
+                return Some(MeshTarget { peer_id: peer_id.clone(), host });
+            }
+            if let Some(peer) = self.peers.lock().await.iter().find(|p| p.ip.parse::<IpAddr>().ok() == Some(ip)) {
+                return Some(MeshTarget { peer_id: peer.id.clone(), host });
+            }
+            return match ip {
+                IpAddr::V4(v4) => self.prefixes.lock().await.iter()
+                    .find(|(net, _)| net.contains(v4))
+                    .map(|(_, peer_id)| MeshTarget { peer_id: peer_id.clone(), host }),
+                IpAddr::V6(_) => None,
+            };
+        }
+
+        let peers = self.peers.lock().await;
+        let peer_target = |p: &PeerInfo| MeshTarget { peer_id: p.id.clone(), host: p.ip.clone() };
+
+        let label_is = |p: &PeerInfo, label: &str| {
+            let own = dns_label(&p.name);
+            !own.is_empty() && own == label
+        };
+
+        if let Some(peer) = peers.iter().find(|p| label_is(p, &host)) {
+            return Some(peer_target(peer));
+        }
+
+        // Longest matching domain wins when several gateways overlap
+        let gateway = peers.iter()
+            .flat_map(|p| self.lan_domains(p).into_iter().map(move |d| (p, d)))
+            .filter(|(_, d)| domain_matches(&host, d))
+            .max_by_key(|(_, d)| d.len());
+        if let Some((peer, _)) = gateway {
+            return Some(MeshTarget { peer_id: peer.id.clone(), host });
+        }
+
+        // Device name under .local or our own domain, e.g. "nas.local"; anything else
+        // is a real name on the internet
+        let (first_label, suffix) = host.split_once('.')?;
+        if suffix != "local" && (self.domain.is_empty() || suffix != self.domain) {
+            return None;
+        }
+        peers.iter()
+            .find(|p| label_is(p, first_label))
+            .map(peer_target)
+    }
+}
+
+// At least two labels, under a private-use suffix: "nas.lan" or "home.arpa", not "lan"
+fn is_private_domain(domain: &str) -> bool {
+    domain.contains('.')
+        && PRIVATE_SUFFIXES.iter().any(|suffix| domain == *suffix || domain.ends_with(&format!(".{}", suffix)))
+}
+
+/// Turns a device name into a DNS label: "Bob's MacBook Pro" -> "bob-s-macbook-pro".
+pub fn dns_label(name: &str) -> String {
+    let mut label = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            label.push(c.to_ascii_lowercase());
+        } else if !label.ends_with('-') {
+            label.push('-');
+        }
+    }
+    label.trim_matches('-').to_string()
+}
+
+/// True if `name` refers to `peer` by ID, device name or DNS label.
+pub fn peer_matches(peer: &PeerInfo, name: &str) -> bool {
+    peer.id == name || peer.name.eq_ignore_ascii_case(name) || dns_label(&peer.name) == dns_label(name)
+}
+
+/// True if `host` is `domain` or a name under it.
+pub fn domain_matches(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_matches('.').to_ascii_lowercase();
+    if domain.is_empty() {
+        return false;
+    }
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gateway(is_gateway: bool, domains: &[&str]) -> PeerInfo {
+        PeerInfo {
+            id: "id-gw".to_string(),
+            ip: "10.251.0.2".to_string(),
+            public_addr: None,
+            p2p_port: 0,
+            name: "office-gw".to_string(),
+            os: None,
+            version: None,
+            device_type: None,
+            is_gateway,
+            connected_at: None,
+            route_status: String::new(),
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn gateways_claim_private_use_domains_only() {
+        let directory = MeshDirectory::new();
+        let peer = gateway(true, &["home.arpa", "Corp.LAN.", "com", "github.com", "lan", "bad_name.lan"]);
+        assert_eq!(directory.lan_domains(&peer), ["home.arpa", "corp.lan"]);
+    }
+
+    #[test]
+    fn other_peers_claim_nothing() {
+        assert!(MeshDirectory::new().lan_domains(&gateway(false, &["home.arpa"])).is_empty());
+    }
+
+    #[test]
+    fn the_user_can_accept_other_domains() {
+        let directory = MeshDirectory::new().with_accepted_domains(&["corp.example.com".to_string(), "lan".to_string()]);
+        let peer = gateway(true, &["corp.example.com", "lan", "example.com"]);
+        assert_eq!(directory.lan_domains(&peer), ["corp.example.com", "lan"]);
+    }
+
+    fn named(name: &str, ip: &str) -> PeerInfo {
+        PeerInfo { id: format!("id-{}", name), name: name.to_string(), ip: ip.to_string(), ..gateway(false, &[]) }
+    }
+
+    #[tokio::test]
+    async fn device_names_resolve_by_label_only() {
+        let directory = MeshDirectory::new().with_domain("syuink.internal");
+        directory.set_peers(vec![named("My NAS", "10.251.0.3"), named("github.com", "10.251.0.4")]).await;
+
+        for host in ["my-nas", "MY-NAS.local", "my-nas.syuink.internal."] {
+            let target = directory.resolve(host).await.unwrap();
+            assert_eq!((target.peer_id.as_str(), target.host.as_str()), ("id-My NAS", "10.251.0.3"), "{}", host);
+        }
+        // Raw names are not matched, nor labels under other domains
+        for host in ["My NAS", "github.com", "my-nas.example.com", "github-com.local.example"] {
+            assert_eq!(directory.resolve(host).await, None, "{}", host);
+        }
+        assert_eq!(directory.resolve("github-com").await.unwrap().peer_id, "id-github.com");
+    }
+}
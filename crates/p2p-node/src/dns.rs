@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use etherparse::{Ipv4HeaderSlice, PacketBuilder, UdpHeaderSlice};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::{debug, warn};
+use crate::directory::{dns_label, domain_matches, MeshDirectory};
+use crate::signaling::SignalMessage;
+use crate::transport::PeerLink;
+use crate::PeerInfo;
+
+/// Overlay address the node answers DNS on. Packets to it never leave the TUN device.
+pub const MAGIC_DNS_IP: Ipv4Addr = Ipv4Addr::new(10, 251, 0, 253);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_REFUSED: u8 = 5;
+// Peer IPs change as devices come and go, keep caches short
+const ANSWER_TTL: u32 = 30;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Debug)]
+pub struct DnsConfig {
+    pub enabled: bool,
+    /// Peers resolve as `<device-name>.<domain>`.
+    pub domain: String,
+    /// Gateway LAN domains to take even though they are single-label or public names
+    /// (see `MeshDirectory::lan_domains`).
+    pub accept_domains: Vec<String>,
+    /// Resolvers for everything else; the system's nameservers when empty.
+    pub upstream: Vec<SocketAddr>,
+    /// Point the OS resolver at us for `domain` (split DNS).
+    pub configure_os: bool,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            domain: "syuink.internal".to_string(),
+            accept_domains: Vec::new(),
+            upstream: Vec::new(),
+            configure_os: true,
+        }
+    }
+}
+
+/// Resolver for peer names, served on `MAGIC_DNS_IP` from the live peer table.
+///
+/// Names under a gateway's LAN domains are resolved by that gateway (see
+/// `resolve_for_peer`), and the addresses it returns are reported as routes through it.
+pub struct MagicDns {
+    domain: String,
+    upstream: Vec<SocketAddr>,
+    directory: MeshDirectory,
+    // This node, which is not in the directory's peer list
+    my_id: String,
+    my_label: String,
+    my_ip: Ipv4Addr,
+    gateways: Option<GatewayLink>,
+}
+
+// How queries reach gateway peers and where learned addresses go
+struct GatewayLink {
+    link: PeerLink,
+    route_tx: mpsc::Sender<(IpAddr, String)>,
+    // Query ID -> waiting resolver
+    pending: Mutex<HashMap<u32, oneshot::Sender<Vec<u8>>>>,
+    next_query_id: AtomicU32,
+}
+
+impl MagicDns {
+    pub fn new(config: &DnsConfig, directory: MeshDirectory, my_id: &str, my_name: &str, my_ip: Ipv4Addr) -> Self {
+        let upstream = if config.upstream.is_empty() {
+            system_nameservers()
+        } else {
+            config.upstream.clone()
+        };
+        Self {
+            domain: config.domain.trim_matches('.').to_ascii_lowercase(),
+            upstream,
+            directory,
+            my_id: my_id.to_string(),
+            my_label: dns_label(my_name),
+            my_ip,
+            gateways: None,
+        }
+    }
+
+    /// Forward gateway LAN domains over `link`; answers' addresses are sent on
+    /// `route_tx` as (address, gateway peer ID).
+    pub fn with_gateways(mut self, link: PeerLink, route_tx: mpsc::Sender<(IpAddr, String)>) -> Self {
+        self.gateways = Some(GatewayLink {
+            link,
+            route_tx,
+            pending: Mutex::new(HashMap::new()),
+            next_query_id: AtomicU32::new(1),
+        });
+        self
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Domains the OS should send to us: our own plus the LAN domains gateways may claim.
+    pub fn split_domains(&self, peers: &[PeerInfo]) -> Vec<String> {
+        let mut domains: Vec<String> = peers.iter()
+            .flat_map(|p| self.directory.lan_domains(p))
+            .collect();
+        domains.sort();
+        domains.dedup();
+        domains.retain(|d| *d != self.domain);
+        domains.insert(0, self.domain.clone());
+        domains
+    }
+
+    /// A gateway's answer to one of our forwarded queries.
+    pub async fn on_answer(&self, query_id: u32, answer: Vec<u8>) {
+        if let Some(gw) = &self.gateways {
+            if let Some(tx) = gw.pending.lock().await.remove(&query_id) {
+                let _ = tx.send(answer);
+            }
+        }
+    }
+
+    /// Handles a packet read from the TUN device that was addressed to `MAGIC_DNS_IP`.
+    /// Returns the reply packet to write back, if any.
+    pub async fn handle_packet(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let ipv4 = Ipv4HeaderSlice::from_slice(packet).ok()?;
+        if ipv4.protocol() != etherparse::IpNumber::UDP {
+            return None;
+        }
+        let udp_slice = packet.get(ipv4.slice().len()..)?;
+        let udp = UdpHeaderSlice::from_slice(udp_slice).ok()?;
+        if udp.destination_port() != 53 {
+            return None;
+        }
+        let query = &udp_slice[udp.slice().len()..];
+        let response = self.resolve(query).await?;
+
+        let builder = PacketBuilder::ipv4(MAGIC_DNS_IP.octets(), ipv4.source_addr().octets(), 64)
+            .udp(53, udp.source_port());
+        let mut out = Vec::with_capacity(response.len() + 28);
+        builder.write(&mut out, &response).ok()?;
+        Some(out)
+    }
+
+    /// Answers a DNS query message. Names under our domain come from the peer table,
+    /// everything else is relayed upstream.
+    pub async fn resolve(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let question = Question::parse(query)?;
+        if !domain_matches(&question.name, &self.domain) {
+            if let Some(peer_id) = self.gateway_for(&question.name).await {
+                return match self.ask_gateway(&peer_id, query).await {
+                    Some(r) => Some(r),
+                    None => Some(question.response(query, RCODE_SERVFAIL, &[])),
+                };
+            }
+            return match forward(query, &self.upstream).await {
+                Some(r) => Some(r),
+                None => Some(question.response(query, RCODE_SERVFAIL, &[])),
+            };
+        }
+
+        let label = match question.name.strip_suffix(&self.domain) {
+            Some(rest) => rest.trim_end_matches('.'),
+            None => "",
+        };
+        if label.is_empty() {
+            // The zone apex exists but has no addresses
+            return Some(question.response(query, 0, &[]));
+        }
+
+        let ip = if label == self.my_label {
+            Some(IpAddr::V4(self.my_ip))
+        } else {
+            self.directory.peers().await.iter()
+                .find(|p| dns_label(&p.name) == label)
+                .and_then(|p| p.ip.parse::<IpAddr>().ok())
+        };
+        match ip {
+            Some(ip) => {
+                debug!("MagicDNS: {} -> {}", question.name, ip);
+                let records: Vec<IpAddr> = match (question.qtype, ip) {
+                    (TYPE_A, IpAddr::V4(_)) | (TYPE_AAAA, IpAddr::V6(_)) => vec![ip],
+                    _ => Vec::new(),
+                };
+                Some(question.response(query, 0, &records))
+            }
+            None => Some(question.response(query, RCODE_NXDOMAIN, &[])),
+        }
+    }
+
+    // Gateway advertising the longest LAN domain that covers `name`
+    async fn gateway_for(&self, name: &str) -> Option<String> {
+        self.gateways.as_ref()?;
+        let peers = self.directory.peers().await;
+        peers.iter()
+            .filter(|p| p.id != self.my_id)
+            .flat_map(|p| self.directory.lan_domains(p).into_iter().map(move |d| (p, d)))
+            .filter(|(_, d)| domain_matches(name, d))
+            .max_by_key(|(_, d)| d.len())
+            .map(|(p, _)| p.id.clone())
+    }
+
+    async fn ask_gateway(&self, peer_id: &str, query: &[u8]) -> Option<Vec<u8>> {
+        let gw = self.gateways.as_ref()?;
+        let query_id = gw.next_query_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        gw.pending.lock().await.insert(query_id, tx);
+
+        let sent = gw.link.send_unreliable(peer_id, SignalMessage::DnsQuery {
+            query_id,
+            target: peer_id.to_string(),
+            source: self.my_id.clone(),
+            data: BASE64.encode(query),
+        }).await;
+        let answer = match sent {
+            Ok(()) => tokio::time::timeout(UPSTREAM_TIMEOUT, rx).await.ok().and_then(|r| r.ok()),
+            Err(_) => None,
+        };
+        gw.pending.lock().await.remove(&query_id);
+
+        let answer = answer?;
+        if answer.len() < 2 || answer[..2] != query[..2] {
+            return None;
+        }
+        // Traffic to these addresses has to go through the gateway that resolved them
+        for ip in answer_addrs(&answer) {
+            let _ = gw.route_tx.try_send((ip, peer_id.to_string()));
+        }
+        Some(answer)
+    }
+}
+
+/// Gateway side of split DNS: answers a peer's address query with the OS resolver,
+/// which knows the LAN's own DNS, but only for names under the LAN domains we
+/// advertise. What the OS can't resolve fails; it is never asked of public resolvers.
+pub async fn resolve_for_peer(query: &[u8], lan_domains: &[String]) -> Option<Vec<u8>> {
+    let question = Question::parse(query)?;
+    if !lan_domains.iter().any(|d| domain_matches(&question.name, d)) {
+        debug!("Refusing DNS query for {} outside our LAN domains", question.name);
+        return Some(question.response(query, RCODE_REFUSED, &[]));
+    }
+    // The OS resolver only gives addresses
+    if question.qtype != TYPE_A && question.qtype != TYPE_AAAA {
+        return Some(question.response(query, 0, &[]));
+    }
+    let lookup = tokio::net::lookup_host((question.name.as_str(), 0)).await;
+    match lookup {
+        Ok(addrs) => {
+            let mut answers: Vec<IpAddr> = addrs
+                .map(|addr| addr.ip())
+                .filter(|ip| (question.qtype == TYPE_A) == ip.is_ipv4())
+                .collect();
+            answers.sort();
+            answers.dedup();
+            Some(question.response(query, 0, &answers))
+        }
+        Err(e) => {
+            debug!("LAN lookup of {} failed: {}", question.name, e);
+            Some(question.response(query, RCODE_SERVFAIL, &[]))
+        }
+    }
+}
+
+/// A and AAAA addresses in the answer section of a response.
+pub(crate) fn answer_addrs(msg: &[u8]) -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    if msg.len() < 12 {
+        return addrs;
+    }
+    let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+    let ancount = u16::from_be_bytes([msg[6], msg[7]]);
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = match skip_name(msg, pos) {
+            Some(p) => p + 4,
+            None => return addrs,
+        };
+    }
+    for _ in 0..ancount {
+        pos = match skip_name(msg, pos) {
+            Some(p) => p,
+            None => break,
+        };
+        let header = match msg.get(pos..pos + 10) {
+            Some(h) => h,
+            None => break,
+        };
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        pos += 10;
+        let rdata = match msg.get(pos..pos + rdlength) {
+            Some(d) => d,
+            None => break,
+        };
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => addrs.push(IpAddr::from(<[u8; 4]>::try_from(rdata).unwrap())),
+            (TYPE_AAAA, 16) => addrs.push(IpAddr::from(<[u8; 16]>::try_from(rdata).unwrap())),
+            _ => {}
+        }
+        pos += rdlength;
+    }
+    addrs
+}
+
+// Offset just past a possibly compressed name
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            // A pointer ends the name
+            return Some(pos + 2);
+        }
+        pos += 1 + len;
+    }
+}
+
+/// The single question of a standard query.
+pub(crate) struct Question {
+    /// Lowercased, without the trailing dot.
+    pub name: String,
+    pub qtype: u16,
+    qclass: u16,
+    // Offset just past the question in the query
+    end: usize,
+}
+
+impl Question {
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        if msg.len() < 12 {
+            return None;
+        }
+        let flags = u16::from_be_bytes([msg[2], msg[3]]);
+        let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+        // Queries only (QR = 0), standard opcode, exactly one question
+        if flags & 0x8000 != 0 || (flags >> 11) & 0x0f != 0 || qdcount != 1 {
+            return None;
+        }
+
+        let mut labels = Vec::new();
+        let mut pos = 12;
+        loop {
+            let len = *msg.get(pos)? as usize;
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            // Compression pointers never appear in a lone question
+            if len & 0xc0 != 0 {
+                return None;
+            }
+            let label = msg.get(pos..pos + len)?;
+            labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+            pos += len;
+        }
+        let qtype = u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]);
+        let qclass = u16::from_be_bytes([*msg.get(pos + 2)?, *msg.get(pos + 3)?]);
+
+        Some(Self {
+            name: labels.join("."),
+            qtype,
+            qclass,
+            end: pos + 4,
+        })
+    }
+
+    /// Authoritative response echoing the question, with `answers` as A/AAAA records.
+    pub fn response(&self, query: &[u8], rcode: u8, answers: &[IpAddr]) -> Vec<u8> {
+        let answers: Vec<&IpAddr> = answers.iter()
+            .filter(|_| self.qclass == CLASS_IN)
+            .collect();
+
+        let mut out = Vec::with_capacity(self.end + answers.len() * 28);
+        out.extend_from_slice(&query[0..2]); // ID
+        let rd = query[2] & 0x01;
+        out.push(0x80 | 0x04 | rd); // QR, AA, RD copied
+        out.push(0x80 | (rcode & 0x0f)); // RA
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0]); // NSCOUNT, ARCOUNT
+        out.extend_from_slice(&query[12..self.end]);
+
+        for ip in answers {
+            out.extend_from_slice(&[0xc0, 0x0c]); // Name: pointer to the question
+            let (rtype, rdata) = match ip {
+                IpAddr::V4(v4) => (TYPE_A, v4.octets().to_vec()),
+                IpAddr::V6(v6) => (TYPE_AAAA, v6.octets().to_vec()),
+            };
+            out.extend_from_slice(&rtype.to_be_bytes());
+            out.extend_from_slice(&CLASS_IN.to_be_bytes());
+            out.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+            out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            out.extend_from_slice(&rdata);
+        }
+        out
+    }
+}
+
+/// Relays a query to the first upstream that answers.
+pub(crate) async fn forward(query: &[u8], upstream: &[SocketAddr]) -> Option<Vec<u8>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await.ok()?;
+    let mut buf = vec![0u8; 4096];
+    for server in upstream {
+        if socket.send_to(query, server).await.is_err() {
+            continue;
+        }
+        match tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv_from(&mut buf)).await {
+            Ok(Ok((n, from))) if from == *server && n >= 2 && buf[..2] == query[..2] => {
+                return Some(buf[..n].to_vec());
+            }
+            Ok(Ok(_)) => continue,
+            Ok(Err(e)) => debug!("DNS upstream {} failed: {}", server, e),
+            Err(_) => debug!("DNS upstream {} timed out", server),
+        }
+    }
+    None
+}
+
+/// Nameservers from /etc/resolv.conf, or public resolvers when there are none.
+pub(crate) fn system_nameservers() -> Vec<SocketAddr> {
+    let mut servers: Vec<SocketAddr> = std::fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .filter(|ip| *ip != IpAddr::V4(MAGIC_DNS_IP))
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect();
+    if servers.is_empty() {
+        warn!("No system nameservers found, forwarding DNS to public resolvers");
+        servers = vec![
+            SocketAddr::from(([1, 1, 1, 1], 53)),
+            SocketAddr::from(([8, 8, 8, 8], 53)),
+        ];
+    }
+    servers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.push(0);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg
+    }
+
+    fn rcode(msg: &[u8]) -> u8 {
+        msg[3] & 0x0f
+    }
+
+    #[tokio::test]
+    async fn peer_queries_use_the_os_resolver() {
+        let answer = resolve_for_peer(&query("localhost", TYPE_A), &["localhost".to_string()]).await.unwrap();
+        assert_eq!(rcode(&answer), 0);
+        assert!(answer_addrs(&answer).contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    }
+
+    #[tokio::test]
+    async fn peer_queries_outside_our_domains_are_refused() {
+        let answer = resolve_for_peer(&query("example.com", TYPE_A), &["home.arpa".to_string()]).await.unwrap();
+        assert_eq!(rcode(&answer), RCODE_REFUSED);
+    }
+
+    #[tokio::test]
+    async fn unresolvable_lan_names_fail() {
+        let answer = resolve_for_peer(&query("no-such-host.invalid", TYPE_A), &["invalid".to_string()]).await.unwrap();
+        assert_eq!(rcode(&answer), RCODE_SERVFAIL);
+    }
+}
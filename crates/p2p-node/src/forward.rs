@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{info, debug, warn};
+use crate::directory::{MeshDirectory, MeshTarget};
+use crate::signaling::ServiceDecl;
+use crate::transport::TcpTunnels;
+
+/// `ssh -L`-style forward: connections to `listen` are tunnelled to `peer`, which
+/// connects to `target_host:target_port`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocalForward {
+    pub listen: SocketAddr,
+    /// Peer device name, DNS label or ID.
+    pub peer: String,
+    /// Host the peer connects to; the peer's own virtual IP when unset.
+    #[serde(default)]
+    pub target_host: Option<String>,
+    pub target_port: u16,
+}
+
+/// `[BIND:]PORT:PEER[/HOST]:PORT`, e.g. `5432:db-box:5432` or
+/// `0.0.0.0:8080:office-gw/intranet.lan:80`. BIND defaults to 127.0.0.1.
+impl FromStr for LocalForward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (bind, local_port, peer, target_port) = match parts.as_slice() {
+            [local, peer, remote] => ("127.0.0.1", *local, *peer, *remote),
+            [bind, local, peer, remote] => (*bind, *local, *peer, *remote),
+            _ => return Err(anyhow!("Expected [BIND:]PORT:PEER[/HOST]:PORT, got {}", s)),
+        };
+        let listen = format!("{}:{}", bind, local_port).parse::<SocketAddr>()
+            .map_err(|e| anyhow!("Bad listen address in {}: {}", s, e))?;
+        let (peer, target_host) = match peer.split_once('/') {
+            Some((peer, host)) => (peer, Some(host.to_string())),
+            None => (peer, None),
+        };
+        let target_port = target_port.parse::<u16>()
+            .map_err(|e| anyhow!("Bad target port in {}: {}", s, e))?;
+        Ok(Self {
+            listen,
+            peer: peer.to_string(),
+            target_host,
+            target_port,
+        })
+    }
+}
+
+/// Running local forwards, keyed by listen address.
+#[derive(Default)]
+pub struct LocalForwards {
+    active: HashMap<SocketAddr, JoinHandle<()>>,
+}
+
+impl LocalForwards {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds the listener and starts accepting; replaces any forward on the same address.
+    pub async fn add(&mut self, forward: LocalForward, tunnels: TcpTunnels, directory: MeshDirectory) -> Result<()> {
+        self.remove(&forward.listen);
+
+        let listener = TcpListener::bind(forward.listen).await
+            .map_err(|e| anyhow!("Failed to listen on {}: {}", forward.listen, e))?;
+        info!("Forwarding {} -> {}:{}", forward.listen, forward.peer, forward.target_port);
+
+        let listen = forward.listen;
+        let task = tokio::spawn(async move {
+            loop {
+                let (socket, addr) = match listener.accept().await {
+                    Ok(a) => a,
+                    Err(e) => {
+                        warn!("Local forward {} accept failed: {}", forward.listen, e);
+                        continue;
+                    }
+                };
+                let forward = forward.clone();
+                let tunnels = tunnels.clone();
+                let directory = directory.clone();
+                tokio::spawn(async move {
+                    // Resolve per connection so a peer that reconnects is picked up again
+                    let peer = match directory.find_peer(&forward.peer).await {
+                        Some(p) => p,
+                        None => {
+                            debug!("Local forward {}: peer {} is offline", forward.listen, forward.peer);
+                            return;
+                        }
+                    };
+                    let target = MeshTarget {
+                        peer_id: peer.id,
+                        host: forward.target_host.clone().unwrap_or(peer.ip),
+                    };
+                    match tunnels.connect(target, forward.target_port).await {
+                        Ok(tunnel) => tunnel.splice(socket).await,
+                        Err(reply) => debug!(
+                            "Local forward {} from {}: peer could not connect (reply {:#04x})",
+                            forward.listen, addr, reply
+                        ),
+                    }
+                });
+            }
+        });
+        self.active.insert(listen, task);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, listen: &SocketAddr) -> bool {
+        match self.active.remove(listen) {
+            Some(task) => {
+                task.abort();
+                info!("Stopped forwarding {}", listen);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for LocalForwards {
+    fn drop(&mut self) {
+        for (_, task) in self.active.drain() {
+            task.abort();
+        }
+    }
+}
+
+/// Reverse forward: a local service (possibly loopback-only) published to the mesh
+/// as `<virtual-ip>:port`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishedPort {
+    /// Port on this node's virtual IP.
+    pub port: u16,
+    /// Local service the connections go to.
+    pub target: SocketAddr,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// `PORT:[HOST:]TARGET_PORT`, e.g. `8080:3000` or `8080:192.168.1.5:80`.
+/// HOST defaults to 127.0.0.1.
+impl FromStr for PublishedPort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (port, target) = s.split_once(':')
+            .ok_or_else(|| anyhow!("Expected PORT:[HOST:]TARGET_PORT, got {}", s))?;
+        let port = port.parse::<u16>()
+            .map_err(|e| anyhow!("Bad port in {}: {}", s, e))?;
+        let target = match target.parse::<u16>() {
+            Ok(p) => SocketAddr::from(([127, 0, 0, 1], p)),
+            Err(_) => target.parse::<SocketAddr>()
+                .map_err(|e| anyhow!("Bad target in {}: {}", s, e))?,
+        };
+        Ok(Self {
+            port,
+            target,
+            description: String::new(),
+        })
+    }
+}
+
+impl PublishedPort {
+    pub fn service_decl(&self, virtual_ip: Ipv4Addr) -> ServiceDecl {
+        ServiceDecl {
+            ip: virtual_ip.to_string(),
+            port: self.port,
+            protocol: "tcp".to_string(),
+            service_type: "published".to_string(),
+            description: self.description.clone(),
+        }
+    }
+}
+
+/// Ports this node publishes on its virtual IP. Connections arrive either through the
+/// TUN device (peers dialing `<virtual-ip>:port`) or as tunnelled streams, which
+/// `connect` maps to the local target.
+#[derive(Clone)]
+pub struct PublishedPorts {
+    virtual_ip: Ipv4Addr,
+    ports: Arc<Mutex<PortTable>>,
+}
+
+// Port -> mapping and its listener task; listeners stop when the last handle goes
+struct PortTable(HashMap<u16, (PublishedPort, JoinHandle<()>)>);
+
+impl Drop for PortTable {
+    fn drop(&mut self) {
+        for (_, (_, task)) in self.0.drain() {
+            task.abort();
+        }
+    }
+}
+
+impl PublishedPorts {
+    pub fn new(virtual_ip: Ipv4Addr) -> Self {
+        Self {
+            virtual_ip,
+            ports: Arc::new(Mutex::new(PortTable(HashMap::new()))),
+        }
+    }
+
+    /// Starts listening on the virtual IP; replaces an earlier mapping of the same port.
+    /// If the listener can't be bound the mapping still serves tunnelled streams.
+    pub async fn publish(&self, published: PublishedPort) {
+        self.unpublish(published.port).await;
+
+        info!("Publishing {}:{} -> {}", self.virtual_ip, published.port, published.target);
+        let target = published.target;
+        let task = match TcpListener::bind((self.virtual_ip, published.port)).await {
+            Ok(listener) => tokio::spawn(async move {
+                while let Ok((mut socket, addr)) = listener.accept().await {
+                    tokio::spawn(async move {
+                        match TcpStream::connect(target).await {
+                            Ok(mut local) => {
+                                let _ = tokio::io::copy_bidirectional(&mut socket, &mut local).await;
+                            }
+                            Err(e) => debug!("Published port: {} -> {} failed: {}", addr, target, e),
+                        }
+                    });
+                }
+            }),
+            Err(e) => {
+                warn!("Failed to listen on {}:{} ({}); only tunnelled connections will reach it", self.virtual_ip, published.port, e);
+                tokio::spawn(async {})
+            }
+        };
+        self.ports.lock().await.0.insert(published.port, (published, task));
+    }
+
+    pub async fn unpublish(&self, port: u16) -> bool {
+        match self.ports.lock().await.0.remove(&port) {
+            Some((_, task)) => {
+                task.abort();
+                info!("Unpublished port {}", port);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn service_decls(&self) -> Vec<ServiceDecl> {
+        self.ports.lock().await.0.values()
+            .map(|(p, _)| p.service_decl(self.virtual_ip))
+            .collect()
+    }
+
+    /// Connects a tunnelled stream's target, going straight to the local service when
+    /// it names one of our published ports. Peers reach this machine itself only
+    /// through published ports: other ports on the virtual IP and anything that
+    /// resolves to loopback are refused with `PermissionDenied`.
+    pub async fn connect(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        use std::io::{Error, ErrorKind};
+        if host.parse::<Ipv4Addr>().ok() == Some(self.virtual_ip) {
+            return match self.ports.lock().await.0.get(&port).map(|(p, _)| p.target) {
+                Some(target) => TcpStream::connect(target).await,
+                None => Err(Error::new(ErrorKind::PermissionDenied, format!("port {} is not published", port))),
+            };
+        }
+        // host may also be a LAN hostname; resolve it with our own DNS
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await
+            .map_err(|e| Error::new(ErrorKind::HostUnreachable, e))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(Error::new(ErrorKind::HostUnreachable, format!("{} has no addresses", host)));
+        }
+        let own = |ip: IpAddr| ip.is_loopback() || ip.is_unspecified() || ip == IpAddr::V4(self.virtual_ip);
+        if addrs.iter().any(|a| own(a.ip().to_canonical())) {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("{} is this machine", host)));
+        }
+        TcpStream::connect(addrs.as_slice()).await
+    }
+}
@@ -1,13 +1,19 @@
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use tokio::net::{TcpStream, UdpSocket};
 use tokio::sync::Mutex;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice, UdpHeaderSlice, IpNumber, PacketBuilder};
-use anyhow::Result;
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice, UdpHeaderSlice, IpNumber, PacketBuilder, Icmpv4Slice, Icmpv4Header, Icmpv4Type, IcmpEchoHeader};
+use socket2::{Domain, Protocol, Socket, Type};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
 use tracing::{info, error, debug, warn};
 use tokio::sync::mpsc::{channel, Sender, Receiver};
+use crate::SharedPacketWriter;
 
 // Key for NAT table: (SrcIP, SrcPort, DstIP, DstPort, Protocol)
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
@@ -19,21 +25,182 @@ struct FlowKey {
     protocol: u8, // 6 for TCP, 17 for UDP
 }
 
+/// How outbound UDP flows are mapped onto gateway sockets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UdpMapping {
+    /// One socket per (source, destination) pair. A client port shows up on the LAN
+    /// from a different port for every remote, like a symmetric NAT.
+    EndpointDependent,
+    /// One socket per client (source IP, source port), reused for every destination and
+    /// accepting replies from any remote (full cone). Needed by most games and VoIP.
+    EndpointIndependent,
+}
+
+impl FromStr for UdpMapping {
+    type Err = anyhow::Error;
+
+    /// "dependent" or "independent", optionally spelled out as "endpoint-...".
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim_start_matches("endpoint").trim_start_matches(['-', '_']) {
+            "dependent" => Ok(Self::EndpointDependent),
+            "independent" => Ok(Self::EndpointIndependent),
+            _ => Err(anyhow!("Expected independent or dependent UDP mapping, got {}", s)),
+        }
+    }
+}
+
+/// Tunables for the userspace NAT flow tables.
+#[derive(Clone, Debug)]
+pub struct GatewayConfig {
+    /// Idle timeout for ordinary UDP flows. Games and voice chat can go quiet for a while.
+    pub udp_idle_timeout: Duration,
+    /// Idle timeout for DNS flows (destination port 53), which are one query and one answer.
+    pub dns_idle_timeout: Duration,
+    /// Idle timeout for ICMP echo flows.
+    pub icmp_idle_timeout: Duration,
+    /// Upper bound on concurrent UDP flows. The least recently used flow is evicted beyond this.
+    pub max_udp_flows: usize,
+    /// Upper bound on TCP NAT mappings. Beyond it, half-open ones are dropped and new
+    /// connections refused.
+    pub max_tcp_mappings: usize,
+    /// Upper bound on concurrent ICMP echo flows, each holding a socket. New ones are
+    /// refused beyond this until old ones go idle.
+    pub max_icmp_flows: usize,
+    pub udp_mapping: UdpMapping,
+    /// With endpoint-independent mapping, try to bind the client's own source port first
+    /// (unprivileged ports only).
+    pub preserve_ports: bool,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            udp_idle_timeout: Duration::from_secs(180),
+            dns_idle_timeout: Duration::from_secs(10),
+            icmp_idle_timeout: Duration::from_secs(30),
+            max_udp_flows: 4096,
+            max_tcp_mappings: 4096,
+            max_icmp_flows: 256,
+            udp_mapping: UdpMapping::EndpointDependent,
+            preserve_ports: true,
+        }
+    }
+}
+
+/// Point-in-time view of the gateway flow table counters.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GatewayStats {
+    pub active_udp_flows: usize,
+    pub udp_flows_created: u64,
+    pub udp_flows_expired: u64,
+    pub udp_flows_evicted: u64,
+    pub udp_packets_out: u64,
+    pub udp_packets_in: u64,
+}
+
+#[derive(Default)]
+struct GatewayCounters {
+    udp_flows_created: AtomicU64,
+    udp_flows_expired: AtomicU64,
+    udp_flows_evicted: AtomicU64,
+    udp_packets_out: AtomicU64,
+    udp_packets_in: AtomicU64,
+}
+
+struct UdpFlow {
+    socket: Arc<UdpSocket>,
+    // Milliseconds since the router's epoch, bumped by traffic in either direction.
+    last_activity: Arc<AtomicU64>,
+    // Raised to the longest timeout any packet on the flow called for
+    idle_timeout: Duration,
+    recv_task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for UdpFlow {
+    fn drop(&mut self) {
+        // Evicted or expired: stop listening for replies on this socket.
+        self.recv_task.abort();
+    }
+}
+
+// How often idle flows are swept out of the table.
+const FLOW_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+// Key for ICMP echo table: (SrcIP, DstIP, Identifier)
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+struct IcmpKey {
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    ident: u16,
+}
+
+struct IcmpFlow {
+    socket: Arc<UdpSocket>,
+    // Identifier written on the wire. Ping sockets ignore it, the kernel uses the socket's own.
+    wire_ident: u16,
+    // Milliseconds since the router's epoch, bumped by requests and replies alike.
+    last_activity: Arc<AtomicU64>,
+}
+
 pub struct GatewayRouter {
-    udp_sockets: Arc<Mutex<HashMap<FlowKey, Arc<UdpSocket>>>>,
-    tun_writer: Arc<Mutex<tokio::io::WriteHalf<tun_device::AsyncDevice>>>,
-    // TCP handling placeholder
+    config: GatewayConfig,
+    epoch: Instant,
+    udp_flows: Arc<Mutex<HashMap<FlowKey, UdpFlow>>>,
+    counters: Arc<GatewayCounters>,
+    icmp_flows: Arc<Mutex<HashMap<IcmpKey, IcmpFlow>>>,
+    next_icmp_ident: Arc<AtomicU16>,
+    tun_writer: SharedPacketWriter,
+    // TCP handling placeholder, used when TCP NAT is off
     tcp_tx: Sender<Vec<u8>>,
+    tcp_nat: Option<TcpNat>,
+}
+
+// How long a SYN may wait for the local accept, and a closed session's mapping
+// is kept so the kernel's final FIN/ACKs still get translated.
+const TCP_NAT_PENDING_TIMEOUT: Duration = Duration::from_secs(30);
+const TCP_NAT_LINGER: Duration = Duration::from_secs(10);
+
+// Original destination of one client TCP connection
+struct TcpMapping {
+    dst: SocketAddr,
+    created: Instant,
+    accepted: bool,
+}
+
+/// TCP through the gateway without a userspace TCP stack: client segments are
+/// re-addressed to a local listener and written to the TUN device, so the kernel
+/// terminates the connection; each accepted socket is then spliced to a real
+/// connection toward the original destination. Segments the kernel sends back are
+/// re-addressed to look like they came from that destination.
+struct TcpNat {
+    virtual_ip: Ipv4Addr,
+    listen_port: u16,
+    // (client IP, client port) -> original destination
+    mappings: Arc<Mutex<HashMap<(Ipv4Addr, u16), TcpMapping>>>,
 }
 
 impl GatewayRouter {
-    pub fn new(tun_writer: Arc<Mutex<tokio::io::WriteHalf<tun_device::AsyncDevice>>>) -> Self {
+    pub fn new(tun_writer: SharedPacketWriter) -> Self {
+        Self::with_config(tun_writer, GatewayConfig::default())
+    }
+
+    pub fn with_config(
+        tun_writer: SharedPacketWriter,
+        config: GatewayConfig,
+    ) -> Self {
         let (tcp_tx, mut tcp_rx) = channel(100);
         
         let router = Self {
-            udp_sockets: Arc::new(Mutex::new(HashMap::new())),
+            config,
+            epoch: Instant::now(),
+            udp_flows: Arc::new(Mutex::new(HashMap::new())),
+            counters: Arc::new(GatewayCounters::default()),
+            icmp_flows: Arc::new(Mutex::new(HashMap::new())),
+            next_icmp_ident: Arc::new(AtomicU16::new(rand::random())),
             tun_writer: tun_writer.clone(),
             tcp_tx,
+            tcp_nat: None,
         };
 
         // TCP Packet Sink (Placeholder for future TCP NAT)
@@ -46,9 +213,176 @@ impl GatewayRouter {
             }
         });
 
+        // Idle flow sweeper. Holds only a weak reference so it ends with the router.
+        let flows = Arc::downgrade(&router.udp_flows);
+        let counters = router.counters.clone();
+        let epoch = router.epoch;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(FLOW_SWEEP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let flows = match flows.upgrade() {
+                    Some(f) => f,
+                    None => break,
+                };
+                let now = elapsed_millis(epoch);
+                let mut flows = flows.lock().await;
+                let before = flows.len();
+                flows.retain(|_, flow| {
+                    let idle = now.saturating_sub(flow.last_activity.load(Ordering::Relaxed));
+                    idle < flow.idle_timeout.as_millis() as u64
+                });
+                let expired = before - flows.len();
+                if expired > 0 {
+                    counters.udp_flows_expired.fetch_add(expired as u64, Ordering::Relaxed);
+                    debug!("Expired {} idle UDP flows, {} active", expired, flows.len());
+                }
+            }
+        });
+
         router
     }
 
+    /// Enables TCP forwarding for clients, using the kernel's stack behind `virtual_ip`
+    /// (this node's TUN address). Without it TCP segments are dropped.
+    pub fn with_tcp_nat(mut self, virtual_ip: Ipv4Addr) -> Self {
+        // All interfaces: the TUN address may not be configured yet on every OS
+        let listener = std::net::TcpListener::bind("0.0.0.0:0")
+            .and_then(|l| l.set_nonblocking(true).map(|_| l))
+            .and_then(tokio::net::TcpListener::from_std);
+        let listener = match listener {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("TCP NAT disabled, failed to bind listener: {}", e);
+                return self;
+            }
+        };
+        let listen_port = match listener.local_addr() {
+            Ok(a) => a.port(),
+            Err(_) => return self,
+        };
+        info!("Gateway TCP NAT via {}:{}", virtual_ip, listen_port);
+
+        let mappings: Arc<Mutex<HashMap<(Ipv4Addr, u16), TcpMapping>>> = Arc::new(Mutex::new(HashMap::new()));
+        let accept_mappings = Arc::downgrade(&mappings);
+        tokio::spawn(async move {
+            while let Ok((mut socket, peer)) = listener.accept().await {
+                let mappings = match accept_mappings.upgrade() {
+                    Some(m) => m,
+                    None => break,
+                };
+                let client = match peer {
+                    SocketAddr::V4(a) => (*a.ip(), a.port()),
+                    _ => continue,
+                };
+                // Only connections we redirected have a mapping; anything else is dropped
+                let dst = match mappings.lock().await.get_mut(&client) {
+                    Some(m) => {
+                        m.accepted = true;
+                        m.dst
+                    }
+                    None => continue,
+                };
+                tokio::spawn(async move {
+                    match tokio::time::timeout(Duration::from_secs(10), TcpStream::connect(dst)).await {
+                        Ok(Ok(mut remote)) => {
+                            debug!("TCP NAT {}:{} -> {}", client.0, client.1, dst);
+                            let _ = tokio::io::copy_bidirectional(&mut socket, &mut remote).await;
+                        }
+                        Ok(Err(e)) => debug!("TCP NAT connect to {} failed: {}", dst, e),
+                        Err(_) => debug!("TCP NAT connect to {} timed out", dst),
+                    }
+                    drop(socket);
+                    tokio::time::sleep(TCP_NAT_LINGER).await;
+                    mappings.lock().await.remove(&client);
+                });
+            }
+        });
+
+        self.tcp_nat = Some(TcpNat { virtual_ip, listen_port, mappings });
+        self
+    }
+
+    /// For packets read from our TUN device: translates the kernel's side of a NAT'd
+    /// TCP connection back to the original destination address. Returns true if the
+    /// packet was rewritten and should now be routed to the client peer.
+    pub async fn rewrite_tcp_reply(&self, packet: &mut [u8]) -> bool {
+        let nat = match &self.tcp_nat {
+            Some(n) => n,
+            None => return false,
+        };
+        let (header_len, src, dst) = match tcp_endpoints(packet) {
+            Some(t) => t,
+            None => return false,
+        };
+        if src != (nat.virtual_ip, nat.listen_port) {
+            return false;
+        }
+        let orig = match nat.mappings.lock().await.get(&dst) {
+            Some(m) => m.dst,
+            None => return false,
+        };
+        let orig = match orig {
+            SocketAddr::V4(a) => (*a.ip(), a.port()),
+            _ => return false,
+        };
+        rewrite_tcp_addr(packet, header_len, 12, 0, orig);
+        true
+    }
+
+    async fn handle_tcp(&self, packet: &[u8]) -> Result<()> {
+        let nat = match &self.tcp_nat {
+            Some(n) => n,
+            None => {
+                let _ = self.tcp_tx.send(packet.to_vec()).await;
+                return Ok(());
+            }
+        };
+        let (header_len, src, dst) = match tcp_endpoints(packet) {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let flags = packet[header_len + 13];
+        let is_syn = flags & 0x02 != 0 && flags & 0x10 == 0;
+
+        {
+            let mut mappings = nat.mappings.lock().await;
+            if is_syn {
+                if mappings.len() >= self.config.max_tcp_mappings {
+                    mappings.retain(|_, m| m.accepted || m.created.elapsed() < TCP_NAT_PENDING_TIMEOUT);
+                }
+                if mappings.len() >= self.config.max_tcp_mappings && !mappings.contains_key(&src) {
+                    debug!("Gateway TCP mapping table full, dropping SYN from {:?}", src);
+                    return Ok(());
+                }
+                mappings.insert(src, TcpMapping {
+                    dst: SocketAddr::from(dst),
+                    created: Instant::now(),
+                    accepted: false,
+                });
+            } else if !mappings.contains_key(&src) {
+                return Ok(());
+            }
+        }
+
+        let mut packet = packet.to_vec();
+        rewrite_tcp_addr(&mut packet, header_len, 16, 2, (nat.virtual_ip, nat.listen_port));
+        let mut writer = self.tun_writer.lock().await;
+        let _ = (&mut *writer).write(&packet).await;
+        Ok(())
+    }
+
+    pub async fn stats(&self) -> GatewayStats {
+        GatewayStats {
+            active_udp_flows: self.udp_flows.lock().await.len(),
+            udp_flows_created: self.counters.udp_flows_created.load(Ordering::Relaxed),
+            udp_flows_expired: self.counters.udp_flows_expired.load(Ordering::Relaxed),
+            udp_flows_evicted: self.counters.udp_flows_evicted.load(Ordering::Relaxed),
+            udp_packets_out: self.counters.udp_packets_out.load(Ordering::Relaxed),
+            udp_packets_in: self.counters.udp_packets_in.load(Ordering::Relaxed),
+        }
+    }
+
     pub async fn handle_packet(&self, packet: &[u8]) -> Result<()> {
         let ipv4 = match Ipv4HeaderSlice::from_slice(packet) {
             Ok(h) => h,
@@ -57,11 +391,9 @@ impl GatewayRouter {
 
         let protocol = ipv4.protocol();
         match protocol {
-            etherparse::IpNumber::TCP => {
-                 let _ = self.tcp_tx.send(packet.to_vec()).await;
-                 Ok(())
-            },
+            etherparse::IpNumber::TCP => self.handle_tcp(packet).await,
             etherparse::IpNumber::UDP => self.handle_udp(packet, ipv4).await,
+            etherparse::IpNumber::ICMP => self.handle_icmp(packet, ipv4).await,
             _ => Ok(()),
         }
     }
@@ -80,33 +412,71 @@ impl GatewayRouter {
         let dst_port = udp.destination_port();
         let payload = &udp_slice[udp.slice().len()..];
 
-        let key = FlowKey {
-            src_ip: src_ip.into(),
-            src_port,
-            dst_ip: dst_ip.into(),
-            dst_port,
-            protocol: 17,
+        // Endpoint-independent flows are keyed on the client side only, so every
+        // destination shares one socket and one external port.
+        let key = match self.config.udp_mapping {
+            UdpMapping::EndpointDependent => FlowKey {
+                src_ip: src_ip.into(),
+                src_port,
+                dst_ip: dst_ip.into(),
+                dst_port,
+                protocol: 17,
+            },
+            UdpMapping::EndpointIndependent => FlowKey {
+                src_ip: src_ip.into(),
+                src_port,
+                dst_ip: Ipv4Addr::UNSPECIFIED,
+                dst_port: 0,
+                protocol: 17,
+            },
         };
 
-        let mut sockets = self.udp_sockets.lock().await;
+        // An endpoint-independent flow that began with a DNS query may carry more than DNS
+        let idle_timeout = if dst_port == 53 {
+            self.config.dns_idle_timeout
+        } else {
+            self.config.udp_idle_timeout
+        };
+
+        let mut flows = self.udp_flows.lock().await;
+        let now = elapsed_millis(self.epoch);
+        self.counters.udp_packets_out.fetch_add(1, Ordering::Relaxed);
         
-        if let Some(socket) = sockets.get(&key) {
+        if let Some(flow) = flows.get_mut(&key) {
             // Forward payload
+            flow.last_activity.store(now, Ordering::Relaxed);
+            flow.idle_timeout = flow.idle_timeout.max(idle_timeout);
             let target = format!("{}:{}", dst_ip, dst_port);
-            let _ = socket.send_to(payload, target).await;
+            let _ = flow.socket.send_to(payload, target).await;
         } else {
+            // Make room before opening another socket
+            if flows.len() >= self.config.max_udp_flows {
+                let lru = flows.iter()
+                    .min_by_key(|(_, f)| f.last_activity.load(Ordering::Relaxed))
+                    .map(|(k, _)| k.clone());
+                if let Some(lru) = lru {
+                    debug!("UDP flow table full ({}), evicting {:?}", flows.len(), lru);
+                    flows.remove(&lru);
+                    self.counters.udp_flows_evicted.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
             // New flow
             info!("New UDP Flow: {}:{} -> {}:{}", src_ip, src_port, dst_ip, dst_port);
-            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            let socket = self.bind_udp_socket(src_port).await?;
             let socket = Arc::new(socket);
+            let last_activity = Arc::new(AtomicU64::new(now));
             
             // Spawn listener for response
             let socket_clone = socket.clone();
             let tun_writer = self.tun_writer.clone();
+            let activity = last_activity.clone();
+            let counters = self.counters.clone();
+            let epoch = self.epoch;
             let src_ip_fixed = src_ip;
             let src_port_fixed = src_port;
 
-            tokio::spawn(async move {
+            let recv_task = tokio::spawn(async move {
                 let mut buf = [0u8; 4096];
                 loop {
                     match socket_clone.recv_from(&mut buf).await {
@@ -116,6 +486,8 @@ impl GatewayRouter {
                                 _ => continue,
                             };
                             let dst_octets = src_ip_fixed.octets();
+                            activity.store(elapsed_millis(epoch), Ordering::Relaxed);
+                            counters.udp_packets_in.fetch_add(1, Ordering::Relaxed);
 
                             let builder = PacketBuilder::
                                 ipv4(src_octets, dst_octets, 20)
@@ -132,11 +504,241 @@ impl GatewayRouter {
                 }
             });
 
-            sockets.insert(key, socket.clone());
+            flows.insert(key, UdpFlow {
+                socket: socket.clone(),
+                last_activity,
+                idle_timeout,
+                recv_task,
+            });
+            self.counters.udp_flows_created.fetch_add(1, Ordering::Relaxed);
             let target = format!("{}:{}", dst_ip, dst_port);
             let _ = socket.send_to(payload, target).await;
         }
 
         Ok(())
     }
+
+    async fn bind_udp_socket(&self, src_port: u16) -> Result<UdpSocket> {
+        // Never let a client claim a privileged port on the gateway host
+        if self.config.udp_mapping == UdpMapping::EndpointIndependent && self.config.preserve_ports && src_port >= 1024 {
+            match UdpSocket::bind(("0.0.0.0", src_port)).await {
+                Ok(socket) => return Ok(socket),
+                Err(e) => debug!("Port {} not available for preservation ({}), using ephemeral port", src_port, e),
+            }
+        }
+        Ok(UdpSocket::bind("0.0.0.0:0").await?)
+    }
+
+    async fn handle_icmp(&self, packet: &[u8], ipv4: Ipv4HeaderSlice<'_>) -> Result<()> {
+        let header_len = ipv4.slice().len();
+        let icmp = match Icmpv4Slice::from_slice(&packet[header_len..]) {
+            Ok(i) => i,
+            Err(_) => return Ok(()),
+        };
+
+        // Only echo is forwarded; other ICMP types have no meaning across the NAT.
+        let echo = match icmp.icmp_type() {
+            Icmpv4Type::EchoRequest(echo) => echo,
+            _ => return Ok(()),
+        };
+
+        let src_ip: Ipv4Addr = ipv4.source_addr();
+        let dst_ip: Ipv4Addr = ipv4.destination_addr();
+        let key = IcmpKey {
+            src_ip,
+            dst_ip,
+            ident: echo.id,
+        };
+
+        let mut flows = self.icmp_flows.lock().await;
+
+        if !flows.contains_key(&key) {
+            if flows.len() >= self.config.max_icmp_flows {
+                debug!("ICMP flow table full ({}), dropping echo {} -> {}", flows.len(), src_ip, dst_ip);
+                return Ok(());
+            }
+            info!("New ICMP echo flow: {} -> {} (id {})", src_ip, dst_ip, echo.id);
+            let (socket, is_raw) = open_icmp_socket()?;
+            let socket = Arc::new(socket);
+            let wire_ident = if is_raw {
+                self.next_icmp_ident.fetch_add(1, Ordering::Relaxed)
+            } else {
+                echo.id
+            };
+
+            let socket_clone = socket.clone();
+            let tun_writer = self.tun_writer.clone();
+            let icmp_flows = self.icmp_flows.clone();
+            let flow_key = key.clone();
+            let idle_timeout = self.config.icmp_idle_timeout.as_millis() as u64;
+            let last_activity = Arc::new(AtomicU64::new(elapsed_millis(self.epoch)));
+            let activity = last_activity.clone();
+            let epoch = self.epoch;
+
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                loop {
+                    let idle = elapsed_millis(epoch).saturating_sub(activity.load(Ordering::Relaxed));
+                    if idle >= idle_timeout {
+                        break;
+                    }
+                    let (n, addr) = match tokio::time::timeout(Duration::from_millis(idle_timeout - idle), socket_clone.recv_from(&mut buf)).await {
+                        Ok(Ok(r)) => r,
+                        Ok(Err(_)) => break,
+                        // Requests may have kept the flow alive meanwhile
+                        Err(_) => continue,
+                    };
+                    if addr.ip() != IpAddr::V4(flow_key.dst_ip) {
+                        continue;
+                    }
+
+                    // Raw sockets deliver the IPv4 header as well, ping sockets only the ICMP message.
+                    let icmp_bytes = if is_raw {
+                        match Ipv4HeaderSlice::from_slice(&buf[..n]) {
+                            Ok(h) => &buf[h.slice().len()..n],
+                            Err(_) => continue,
+                        }
+                    } else {
+                        &buf[..n]
+                    };
+
+                    let reply = match Icmpv4Slice::from_slice(icmp_bytes) {
+                        Ok(r) => r,
+                        Err(_) => continue,
+                    };
+                    let seq = match reply.icmp_type() {
+                        // A raw socket sees every echo reply on the host, so match our identifier.
+                        Icmpv4Type::EchoReply(echo) if !is_raw || echo.id == wire_ident => echo.seq,
+                        _ => continue,
+                    };
+
+                    activity.store(elapsed_millis(epoch), Ordering::Relaxed);
+
+                    // Restore the client's original identifier before handing the reply back.
+                    let builder = PacketBuilder::
+                        ipv4(flow_key.dst_ip.octets(), flow_key.src_ip.octets(), 64)
+                        .icmpv4_echo_reply(flow_key.ident, seq);
+
+                    let mut result = Vec::<u8>::with_capacity(reply.payload().len() + 64);
+                    if builder.write(&mut result, reply.payload()).is_ok() {
+                        let mut writer = tun_writer.lock().await;
+                        let _ = (&mut *writer).write(&result).await;
+                    }
+                }
+                debug!("ICMP echo flow {} -> {} (id {}) closed", flow_key.src_ip, flow_key.dst_ip, flow_key.ident);
+                icmp_flows.lock().await.remove(&flow_key);
+            });
+
+            flows.insert(key.clone(), IcmpFlow { socket, wire_ident, last_activity });
+        }
+
+        if let Some(flow) = flows.get(&key) {
+            flow.last_activity.store(elapsed_millis(self.epoch), Ordering::Relaxed);
+            let header = Icmpv4Header::with_checksum(
+                Icmpv4Type::EchoRequest(IcmpEchoHeader { id: flow.wire_ident, seq: echo.seq }),
+                icmp.payload(),
+            );
+            let mut request = Vec::with_capacity(header.header_len() + icmp.payload().len());
+            header.write(&mut request)?;
+            request.extend_from_slice(icmp.payload());
+
+            let target = SocketAddr::new(IpAddr::V4(dst_ip), 0);
+            let _ = flow.socket.send_to(&request, target).await;
+        }
+
+        Ok(())
+    }
+}
+
+fn elapsed_millis(epoch: Instant) -> u64 {
+    epoch.elapsed().as_millis() as u64
+}
+
+/// Opens a socket for forwarding ICMP echo. Returns the socket and whether it is raw.
+///
+/// Linux offers unprivileged "ping sockets" (SOCK_DGRAM + IPPROTO_ICMP) for groups listed in
+/// `net.ipv4.ping_group_range`; the kernel then owns the echo identifier. Everywhere else, or
+/// when that is not permitted, a raw socket is used, which needs root/Administrator.
+fn open_icmp_socket() -> Result<(UdpSocket, bool)> {
+    #[cfg(target_os = "linux")]
+    {
+        match Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4)) {
+            Ok(socket) => {
+                socket.set_nonblocking(true)?;
+                return Ok((UdpSocket::from_std(socket.into())?, false));
+            }
+            Err(e) => {
+                warn!("ICMP ping socket unavailable ({}), falling back to raw socket", e);
+            }
+        }
+    }
+
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
+    socket.set_nonblocking(true)?;
+    Ok((UdpSocket::from_std(socket.into())?, true))
+}
+
+/// True for IPv4 packets addressed outside the overlay (10.251.0.0/16), which a gateway
+/// has to NAT onto its LAN instead of writing to its own TUN device.
+pub fn is_lan_bound(packet: &[u8]) -> bool {
+    match Ipv4HeaderSlice::from_slice(packet) {
+        Ok(ipv4) => {
+            let dest = ipv4.destination_addr().octets();
+            !(dest[0] == 10 && dest[1] == 251)
+        }
+        Err(_) => false,
+    }
+}
+
+/// (IPv4 header length, source, destination) of an unfragmented IPv4 TCP segment.
+fn tcp_endpoints(packet: &[u8]) -> Option<(usize, (Ipv4Addr, u16), (Ipv4Addr, u16))> {
+    let ipv4 = Ipv4HeaderSlice::from_slice(packet).ok()?;
+    if ipv4.protocol() != IpNumber::TCP || ipv4.is_fragmenting_payload() {
+        return None;
+    }
+    let header_len = ipv4.slice().len();
+    let tcp = TcpHeaderSlice::from_slice(packet.get(header_len..)?).ok()?;
+    Some((
+        header_len,
+        (ipv4.source_addr(), tcp.source_port()),
+        (ipv4.destination_addr(), tcp.destination_port()),
+    ))
+}
+
+/// Replaces one address/port pair of a TCP segment in place, fixing both checksums
+/// incrementally. `ip_offset` is 12 (source) or 16 (destination) in the IPv4 header,
+/// `port_offset` 0 (source) or 2 (destination) in the TCP header.
+fn rewrite_tcp_addr(packet: &mut [u8], header_len: usize, ip_offset: usize, port_offset: usize, to: (Ipv4Addr, u16)) {
+    let new_ip = to.0.octets();
+    let new_port = to.1.to_be_bytes();
+    let old_ip: [u8; 4] = packet[ip_offset..ip_offset + 4].try_into().unwrap();
+    let tcp = header_len;
+    let old_port: [u8; 2] = packet[tcp + port_offset..tcp + port_offset + 2].try_into().unwrap();
+
+    let ip_sum = u16::from_be_bytes([packet[10], packet[11]]);
+    packet[10..12].copy_from_slice(&checksum_adjust(ip_sum, &old_ip, &new_ip).to_be_bytes());
+
+    // The TCP checksum covers the pseudo-header addresses as well as the ports
+    let tcp_sum = u16::from_be_bytes([packet[tcp + 16], packet[tcp + 17]]);
+    let tcp_sum = checksum_adjust(tcp_sum, &old_ip, &new_ip);
+    let tcp_sum = checksum_adjust(tcp_sum, &old_port, &new_port);
+    packet[tcp + 16..tcp + 18].copy_from_slice(&tcp_sum.to_be_bytes());
+
+    packet[ip_offset..ip_offset + 4].copy_from_slice(&new_ip);
+    packet[tcp + port_offset..tcp + port_offset + 2].copy_from_slice(&new_port);
+}
+
+/// Incremental Internet checksum update (RFC 1624) for replacing `old` with `new`.
+fn checksum_adjust(sum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut acc = (!sum) as u32;
+    for w in old.chunks(2) {
+        acc += (!u16::from_be_bytes([w[0], w[1]])) as u32;
+    }
+    for w in new.chunks(2) {
+        acc += u16::from_be_bytes([w[0], w[1]]) as u32;
+    }
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
 }
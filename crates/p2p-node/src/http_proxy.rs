@@ -0,0 +1,368 @@
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use crate::rules::{ProxyRoute, RuleEngine};
+use crate::socks5::{connect_host, constant_time_eq, reply_code_for, Socks5Credentials};
+use crate::transport::TcpTunnels;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use anyhow::{Result, anyhow};
+use tracing::{info, debug, warn};
+
+// Request heads larger than this are refused.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/// HTTP proxy listener for clients that cannot speak SOCKS: `CONNECT host:port`
+/// tunnels and plain `GET http://host/...` forwarding. Routing and tunnelling are
+/// shared with `Socks5Server`; it accepts the same credentials via Basic auth.
+pub struct HttpProxy {
+    listener: TcpListener,
+    credentials: Socks5Credentials,
+    // Loopback clients skip auth
+    trust_loopback: bool,
+}
+
+impl HttpProxy {
+    pub async fn new(preferred_port: u16, credentials: Socks5Credentials) -> Result<(Self, u16)> {
+        let listener = match TcpListener::bind(format!("127.0.0.1:{}", preferred_port)).await {
+            Ok(l) => l,
+            Err(_) => {
+                info!("HTTP proxy port {} occupied, trying random port...", preferred_port);
+                TcpListener::bind("127.0.0.1:0").await?
+            }
+        };
+
+        let port = listener.local_addr()?.port();
+        info!("HTTP proxy listening on 127.0.0.1:{}", port);
+
+        Ok((Self { listener, credentials, trust_loopback: false }, port))
+    }
+
+    /// Serves loopback clients without asking for credentials, for applications
+    /// pointed at us by the system proxy settings.
+    pub fn with_loopback_trusted(mut self, trust: bool) -> Self {
+        self.trust_loopback = trust;
+        self
+    }
+
+    pub async fn run(self: Arc<Self>, tunnels: TcpTunnels, rules: RuleEngine) {
+        loop {
+            if let Ok((socket, addr)) = self.listener.accept().await {
+                let proxy = self.clone();
+                let tunnels = tunnels.clone();
+                let rules = rules.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = proxy.handle_client(socket, tunnels, rules).await {
+                        debug!("HTTP proxy client error {}: {}", addr, e);
+                    }
+                });
+            }
+        }
+    }
+
+    async fn handle_client(&self, mut socket: TcpStream, tunnels: TcpTunnels, rules: RuleEngine) -> Result<()> {
+        // 1. Read the request head; anything after it is body to pass along
+        let (head, body) = read_head(&mut socket).await?;
+        let request = match Request::parse(&head) {
+            Some(r) => r,
+            None => {
+                socket.write_all(&status_response(400, "Bad Request")).await?;
+                return Err(anyhow!("Malformed request"));
+            }
+        };
+
+        // 2. Proxy auth
+        let trusted = self.trust_loopback && socket.peer_addr().is_ok_and(|a| a.ip().is_loopback());
+        if !trusted && !self.authorized(&request) {
+            warn!("HTTP proxy authentication failed from {:?}", socket.peer_addr().ok());
+            socket.write_all(
+                b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                  Proxy-Authenticate: Basic realm=\"syuink\"\r\n\
+                  Content-Length: 0\r\nConnection: close\r\n\r\n",
+            ).await?;
+            return Ok(());
+        }
+
+        // 3. Target and the bytes to send once connected
+        let is_connect = request.method.eq_ignore_ascii_case("CONNECT");
+        let (host, port, preamble) = if is_connect {
+            let (host, port) = match split_host_port(&request.target, 443) {
+                Some(hp) => hp,
+                None => {
+                    socket.write_all(&status_response(400, "Bad Request")).await?;
+                    return Err(anyhow!("Bad CONNECT target {}", request.target));
+                }
+            };
+            (host, port, body)
+        } else {
+            let (host, port, path) = match parse_absolute_uri(&request.target) {
+                Some(t) => t,
+                None => {
+                    socket.write_all(&status_response(400, "Bad Request")).await?;
+                    return Err(anyhow!("Not an absolute http:// URI: {}", request.target));
+                }
+            };
+            let mut preamble = request.rewrite(&path).into_bytes();
+            preamble.extend_from_slice(&body);
+            (host, port, preamble)
+        };
+
+        info!("HTTP proxy request: {} {}:{}", request.method, host, port);
+
+        // 4. Route and connect
+        match rules.route(&host, port).await {
+            ProxyRoute::Mesh(target) => match tunnels.connect(target, port).await {
+                Ok(mut tunnel) => {
+                    if is_connect {
+                        socket.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+                    }
+                    if !preamble.is_empty() {
+                        tunnel.send(&preamble).await?;
+                    }
+                    tunnel.splice(socket).await;
+                }
+                Err(reply) => {
+                    socket.write_all(&error_for_reply(reply)).await?;
+                }
+            },
+            ProxyRoute::Direct => {
+                let mut remote = match connect_host(&host, port).await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        socket.write_all(&error_for_reply(reply_code_for(&e))).await?;
+                        return Err(e.into());
+                    }
+                };
+                if is_connect {
+                    socket.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+                }
+                remote.write_all(&preamble).await?;
+                tokio::io::copy_bidirectional(&mut socket, &mut remote).await?;
+            }
+            ProxyRoute::Reject => {
+                debug!("HTTP proxy {}:{} rejected by rule", host, port);
+                socket.write_all(&status_response(403, "Forbidden")).await?;
+            }
+            ProxyRoute::Unreachable => {
+                socket.write_all(&status_response(502, "Bad Gateway")).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let value = match request.header("proxy-authorization") {
+            Some(v) => v,
+            None => return false,
+        };
+        let encoded = match value.split_once(' ') {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("basic") => rest.trim(),
+            _ => return false,
+        };
+        let decoded = match BASE64.decode(encoded) {
+            Ok(d) => d,
+            Err(_) => return false,
+        };
+        let expected = format!("{}:{}", self.credentials.username, self.credentials.password);
+        constant_time_eq(&decoded, expected.as_bytes())
+    }
+}
+
+struct Request {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?.to_string();
+        let version = parts.next()?.to_string();
+
+        let headers = lines
+            .filter(|l| !l.is_empty())
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .collect();
+
+        Some(Self { method, target, version, headers })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Origin-form request for the server: proxy headers dropped, one request per
+    /// connection since the next one may be for another host.
+    fn rewrite(&self, path: &str) -> String {
+        let mut out = format!("{} {} {}\r\n", self.method, path, self.version);
+        for (k, v) in &self.headers {
+            let lower = k.to_ascii_lowercase();
+            if lower.starts_with("proxy-") || lower == "connection" || lower == "keep-alive" {
+                continue;
+            }
+            out.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        out.push_str("Connection: close\r\n\r\n");
+        out
+    }
+}
+
+/// Reads up to the blank line ending the request head.
+async fn read_head(socket: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before request head"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let body = buf.split_off(end + 4);
+            buf.truncate(end);
+            return Ok((String::from_utf8_lossy(&buf).to_string(), body));
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            socket.write_all(&status_response(431, "Request Header Fields Too Large")).await?;
+            return Err(anyhow!("Request head too large"));
+        }
+    }
+}
+
+/// "http://host[:port]/path?q" -> (host, port, "/path?q")
+fn parse_absolute_uri(uri: &str) -> Option<(String, u16, String)> {
+    let rest = uri.strip_prefix("http://").or_else(|| uri.strip_prefix("HTTP://"))?;
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) if rest.as_bytes()[i] == b'/' => (&rest[..i], rest[i..].to_string()),
+        Some(i) => (&rest[..i], format!("/{}", &rest[i..])),
+        None => (rest, "/".to_string()),
+    };
+    // Drop any userinfo
+    let authority = authority.rsplit('@').next()?;
+    let (host, port) = split_host_port(authority, 80)?;
+    Some((host, port, path))
+}
+
+/// "example.com:443", "[2001:db8::1]:443" or a bare host with the default port.
+fn split_host_port(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, after) = rest.split_once(']')?;
+        let port = match after.strip_prefix(':') {
+            Some(p) => p.parse().ok()?,
+            None => default_port,
+        };
+        return Some((host.to_string(), port));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => Some((host.to_string(), port.parse().ok()?)),
+        _ if authority.is_empty() => None,
+        _ => Some((authority.to_string(), default_port)),
+    }
+}
+
+fn status_response(code: u16, reason: &str) -> Vec<u8> {
+    format!("HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", code, reason).into_bytes()
+}
+
+/// HTTP status for a SOCKS5 reply code from the connecting side.
+fn error_for_reply(reply: u8) -> Vec<u8> {
+    match reply {
+        0x02 => status_response(403, "Forbidden"),
+        0x06 => status_response(504, "Gateway Timeout"),
+        _ => status_response(502, "Bad Gateway"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_heads() {
+        let head = "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nProxy-Authorization:  Basic dTpw \r\nno colon here";
+        let request = Request::parse(head).unwrap();
+        assert_eq!((request.method.as_str(), request.target.as_str(), request.version.as_str()), ("CONNECT", "example.com:443", "HTTP/1.1"));
+        assert_eq!(request.header("proxy-authorization"), Some("Basic dTpw"));
+        assert_eq!(request.headers.len(), 2);
+
+        assert!(Request::parse("").is_none());
+        assert!(Request::parse("GET /").is_none());
+    }
+
+    #[test]
+    fn rewrite_drops_proxy_headers() {
+        let request = Request::parse("GET http://example.com/a HTTP/1.1\r\nHost: example.com\r\nProxy-Authorization: Basic x\r\nConnection: keep-alive").unwrap();
+        assert_eq!(request.rewrite("/a"), "GET /a HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n");
+    }
+
+    #[test]
+    fn absolute_uris() {
+        let parse = parse_absolute_uri;
+        assert_eq!(parse("http://example.com/a?b"), Some(("example.com".to_string(), 80, "/a?b".to_string())));
+        assert_eq!(parse("http://example.com:8080"), Some(("example.com".to_string(), 8080, "/".to_string())));
+        assert_eq!(parse("http://example.com?q=1"), Some(("example.com".to_string(), 80, "/?q=1".to_string())));
+        assert_eq!(parse("http://user:pw@[::1]:8080/x"), Some(("::1".to_string(), 8080, "/x".to_string())));
+        assert_eq!(parse("https://example.com/"), None);
+        assert_eq!(parse("/relative"), None);
+        assert_eq!(parse("http:///path"), None);
+    }
+
+    #[test]
+    fn host_and_port() {
+        assert_eq!(split_host_port("example.com:443", 80), Some(("example.com".to_string(), 443)));
+        assert_eq!(split_host_port("example.com", 443), Some(("example.com".to_string(), 443)));
+        assert_eq!(split_host_port("[::1]:8080", 80), Some(("::1".to_string(), 8080)));
+        assert_eq!(split_host_port("[2001:db8::1]", 443), Some(("2001:db8::1".to_string(), 443)));
+        // A bare IPv6 address has no port to split off
+        assert_eq!(split_host_port("2001:db8::1", 443), Some(("2001:db8::1".to_string(), 443)));
+        assert_eq!(split_host_port("example.com:", 80), None);
+        assert_eq!(split_host_port("example.com:99999", 80), None);
+        assert_eq!(split_host_port("[::1", 80), None);
+        assert_eq!(split_host_port("", 80), None);
+    }
+
+    #[tokio::test]
+    async fn proxy_authorization_is_basic_with_the_session_credentials() {
+        let credentials = Socks5Credentials { username: "user".to_string(), password: "p:ss".to_string() };
+        let (proxy, _) = HttpProxy::new(0, credentials).await.unwrap();
+        let with = |value: &str| Request::parse(&format!("GET / HTTP/1.1\r\nProxy-Authorization: {}", value)).unwrap();
+
+        assert!(proxy.authorized(&with(&format!("Basic {}", BASE64.encode("user:p:ss")))));
+        assert!(proxy.authorized(&with(&format!("basic   {}", BASE64.encode("user:p:ss")))));
+        assert!(!proxy.authorized(&with(&format!("Basic {}", BASE64.encode("user:wrong")))));
+        assert!(!proxy.authorized(&with(&format!("Bearer {}", BASE64.encode("user:p:ss")))));
+        assert!(!proxy.authorized(&with("Basic not-base64!")));
+        assert!(!proxy.authorized(&Request::parse("GET / HTTP/1.1").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn oversized_heads_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            let line = format!("X-Filler: {}\r\n", "a".repeat(1000));
+            let mut head = "GET http://example.com/ HTTP/1.1\r\n".to_string();
+            while head.len() <= MAX_HEAD_LEN {
+                head.push_str(&line);
+            }
+            let _ = socket.write_all(head.as_bytes()).await;
+            let mut reply = Vec::new();
+            let _ = socket.read_to_end(&mut reply).await;
+            String::from_utf8_lossy(&reply).to_string()
+        });
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        assert!(read_head(&mut socket).await.is_err());
+        drop(socket);
+        assert!(client.await.unwrap().starts_with("HTTP/1.1 431 "));
+    }
+}
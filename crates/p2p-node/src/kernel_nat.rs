@@ -0,0 +1,170 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use anyhow::{Result, anyhow};
+use ipnetwork::Ipv4Network;
+use tracing::{info, error, warn};
+
+const NFT_TABLE: &str = "syuink";
+const IP_FORWARD_PATH: &str = "/proc/sys/net/ipv4/ip_forward";
+
+/// Kernel gateway backend for Linux: turns on IP forwarding and masquerades overlay
+/// traffic toward the advertised LAN prefixes with nftables. Overlay traffic to
+/// anywhere else is dropped rather than forwarded.
+///
+/// Packets relayed to this node are written to the TUN device as-is and the kernel
+/// forwards them, so TCP works too and nothing is copied through userspace.
+pub struct KernelNat {
+    overlay: Ipv4Network,
+    prefixes: Vec<Ipv4Network>,
+    // ip_forward value before we touched it, restored on cleanup
+    previous_ip_forward: Option<String>,
+    installed: bool,
+}
+
+impl KernelNat {
+    /// Enables forwarding and installs the masquerade rules. An empty `prefixes`
+    /// list masquerades everything leaving the overlay (full gateway).
+    pub fn install(overlay: Ipv4Network, prefixes: Vec<Ipv4Network>) -> Result<Self> {
+        if !cfg!(target_os = "linux") {
+            return Err(anyhow!("Kernel NAT is only available on Linux"));
+        }
+
+        let previous_ip_forward = std::fs::read_to_string(IP_FORWARD_PATH)
+            .ok()
+            .map(|v| v.trim().to_string());
+
+        if previous_ip_forward.as_deref() != Some("1") {
+            info!("Enabling IPv4 forwarding");
+            std::fs::write(IP_FORWARD_PATH, "1")
+                .map_err(|e| anyhow!("Failed to enable IP forwarding: {}", e))?;
+        }
+
+        let mut nat = Self {
+            overlay,
+            prefixes,
+            previous_ip_forward,
+            installed: false,
+        };
+        nat.apply()?;
+        Ok(nat)
+    }
+
+    /// Replaces the masquerade rules after the advertised prefixes changed.
+    pub fn update_prefixes(&mut self, prefixes: Vec<Ipv4Network>) -> Result<()> {
+        if prefixes == self.prefixes {
+            return Ok(());
+        }
+        self.prefixes = prefixes;
+        self.apply()
+    }
+
+    fn apply(&mut self) -> Result<()> {
+        let ruleset = self.ruleset();
+        info!("Installing nftables masquerade and forward filter for {} -> {} prefixes", self.overlay, self.prefixes.len());
+        run_nft_script(&ruleset)?;
+        self.installed = true;
+        Ok(())
+    }
+
+    fn ruleset(&self) -> String {
+        // Recreate the table atomically: "add" makes the delete safe on first install.
+        let mut script = format!(
+            "add table ip {table}\n\
+             delete table ip {table}\n\
+             table ip {table} {{\n\
+             \tchain postrouting {{\n\
+             \t\ttype nat hook postrouting priority srcnat; policy accept;\n",
+            table = NFT_TABLE,
+        );
+        if self.prefixes.is_empty() {
+            script.push_str(&format!(
+                "\t\tip saddr {overlay} ip daddr != {overlay} masquerade\n",
+                overlay = self.overlay,
+            ));
+        } else {
+            for prefix in &self.prefixes {
+                script.push_str(&format!(
+                    "\t\tip saddr {} ip daddr {} masquerade\n",
+                    self.overlay, prefix,
+                ));
+            }
+        }
+        script.push_str("\t}\n");
+
+        // Forwarding is on for the whole host now: only let the overlay reach the
+        // advertised prefixes, plus replies to connections already allowed
+        script.push_str(
+            "\tchain forward {\n\
+             \t\ttype filter hook forward priority filter; policy accept;\n\
+             \t\tct state established,related accept\n",
+        );
+        if self.prefixes.is_empty() {
+            script.push_str(&format!(
+                "\t\tip saddr {overlay} ip daddr != {overlay} accept\n",
+                overlay = self.overlay,
+            ));
+        } else {
+            for prefix in &self.prefixes {
+                script.push_str(&format!(
+                    "\t\tip saddr {} ip daddr {} accept\n",
+                    self.overlay, prefix,
+                ));
+            }
+        }
+        script.push_str(&format!("\t\tip saddr {} drop\n", self.overlay));
+        script.push_str("\t}\n}\n");
+        script
+    }
+
+    pub fn cleanup(&mut self) {
+        if self.installed {
+            info!("Removing nftables table {}", NFT_TABLE);
+            let output = Command::new("nft")
+                .args(["delete", "table", "ip", NFT_TABLE])
+                .output();
+            match output {
+                Ok(o) if !o.status.success() => {
+                    warn!("nft delete table failed: {}", String::from_utf8_lossy(&o.stderr));
+                }
+                Err(e) => error!("Failed to run nft: {}", e),
+                _ => {}
+            }
+            self.installed = false;
+        }
+
+        if let Some(previous) = self.previous_ip_forward.take() {
+            if previous != "1" {
+                info!("Restoring net.ipv4.ip_forward={}", previous);
+                if let Err(e) = std::fs::write(IP_FORWARD_PATH, &previous) {
+                    warn!("Failed to restore IP forwarding: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for KernelNat {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
+
+fn run_nft_script(script: &str) -> Result<()> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run nft (is nftables installed?): {}", e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(script.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("nft rejected ruleset: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
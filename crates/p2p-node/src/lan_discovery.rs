@@ -0,0 +1,210 @@
+//! LAN discovery: nodes multicast a small signed announcement to the local network,
+//! so peers in the same group find each other's LAN address and connect directly,
+//! with or without a signaling server. Off unless enabled.
+//!
+//! Announcements are signed with the node's key and only taken from peers whose key
+//! we already hold, pinned by a static config or signalled by the server; the QUIC
+//! dial that follows checks the same key.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use crate::broadcast::create_multicast_socket;
+use crate::p2p::P2PManager;
+use crate::static_peers::{verify_signature, NodeKey};
+
+// Administratively scoped, never routed off the site
+const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 77, 77);
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+/// A peer that has not announced itself for this long has left the LAN.
+pub const LAN_PEER_TIMEOUT: Duration = Duration::from_secs(20);
+/// How often to retry the direct connection to a LAN peer that stays at one address.
+pub const LAN_REDIAL_INTERVAL: Duration = Duration::from_secs(60);
+// Announcements stamped further from our clock than this are stale or replayed
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Announcements are only trusted from peers whose key is already pinned, and
+/// keys the server vouched for are not kept across restarts. Discovery with
+/// the server unreachable from the start therefore only finds static peers.
+#[derive(Clone, Debug)]
+pub struct LanDiscoveryConfig {
+    pub enabled: bool,
+    /// UDP port announcements go to; every node on the LAN must use the same one.
+    pub port: u16,
+}
+
+impl Default for LanDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 41640,
+        }
+    }
+}
+
+/// A peer of our group heard on the LAN.
+#[derive(Clone, Debug)]
+pub struct LanPeer {
+    pub id: String,
+    pub name: String,
+    pub ip: Ipv4Addr,
+    /// Its QUIC endpoint, at the LAN address the announcement came from.
+    pub addr: SocketAddr,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Announcement {
+    id: String,
+    name: String,
+    ip: Ipv4Addr,
+    port: u16,
+    /// Sender's clock, ms since the Unix epoch.
+    ts: u64,
+    /// Base64 signature over `signed`, by the key of `id`.
+    sig: String,
+}
+
+impl Announcement {
+    // Covers the group too, so an announcement can't be replayed into another one
+    fn signed(&self, group: &str) -> Vec<u8> {
+        format!("syuink-lan\n{}\n{}\n{}\n{}\n{}\n{}", group, self.id, self.name, self.ip, self.port, self.ts).into_bytes()
+    }
+}
+
+pub struct LanDiscovery {
+    socket: UdpSocket,
+    port: u16,
+    group: String,
+    key: NodeKey,
+    // Holds the keys announcements are checked against
+    p2p: Arc<P2PManager>,
+    me: Announcement,
+    // Newest timestamp taken from each peer; anything not newer is a replay
+    last_ts: HashMap<String, u64>,
+}
+
+impl LanDiscovery {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: &LanDiscoveryConfig,
+        group: &str,
+        my_id: &str,
+        name: &str,
+        ip: Ipv4Addr,
+        p2p_port: u16,
+        key: NodeKey,
+        p2p: Arc<P2PManager>,
+    ) -> Result<Self> {
+        let socket = create_multicast_socket(DISCOVERY_GROUP, config.port)?;
+        let me = Announcement {
+            id: my_id.to_string(),
+            name: name.to_string(),
+            ip,
+            port: p2p_port,
+            ts: 0,
+            sig: String::new(),
+        };
+        info!("[LAN] Announcing {} on {}:{}", ip, DISCOVERY_GROUP, config.port);
+        Ok(Self {
+            socket,
+            port: config.port,
+            group: group.to_string(),
+            key,
+            p2p,
+            me,
+            last_ts: HashMap::new(),
+        })
+    }
+
+    /// Announces us every `ANNOUNCE_INTERVAL` and reports every valid announcement
+    /// from other nodes of the group, until `tx` closes.
+    pub async fn run(mut self, tx: mpsc::Sender<LanPeer>) {
+        let mut ticker = tokio::time::interval(ANNOUNCE_INTERVAL);
+        let mut buf = [0u8; 1500];
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    let announcement = match self.announcement() {
+                        Ok(a) => a,
+                        Err(e) => {
+                            warn!("[LAN] Cannot sign announcement: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = self.socket.send_to(&announcement, (DISCOVERY_GROUP, self.port)).await {
+                        debug!("[LAN] Announcement failed: {}", e);
+                    }
+                }
+                res = self.socket.recv_from(&mut buf) => {
+                    let (len, from) = match res {
+                        Ok(r) => r,
+                        Err(e) => {
+                            debug!("[LAN] Receive failed: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(peer) = self.parse(&buf[..len], from).await {
+                        if tx.send(peer).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Ours, stamped and signed now
+    fn announcement(&mut self) -> Result<Vec<u8>> {
+        self.me.ts = now_ms();
+        let sig = self.key.sign(&self.me.signed(&self.group))?;
+        self.me.sig = BASE64.encode(sig);
+        Ok(serde_json::to_vec(&self.me)?)
+    }
+
+    async fn parse(&mut self, data: &[u8], from: SocketAddr) -> Option<LanPeer> {
+        let a: Announcement = serde_json::from_slice(data).ok()?;
+        // Our own, looped back
+        if a.id == self.me.id {
+            return None;
+        }
+        // Only peers a static config or the server vouched for; offline
+        // from startup that leaves the static peers alone
+        let key = match self.p2p.pinned_key(&a.id).await {
+            Some(key) => key,
+            None => {
+                debug!("[LAN] Ignoring {} from {}: no key known for it", a.id, from);
+                return None;
+            }
+        };
+        // Another group, or a forgery
+        let sig = BASE64.decode(&a.sig).ok()?;
+        if !verify_signature(&key, &a.signed(&self.group), &sig) {
+            debug!("[LAN] Ignoring announcement from {}: bad signature", from);
+            return None;
+        }
+        let fresh = now_ms().abs_diff(a.ts) <= MAX_CLOCK_SKEW.as_millis() as u64;
+        let newer = !self.last_ts.get(&a.id).is_some_and(|last| a.ts <= *last);
+        if !fresh || !newer {
+            debug!("[LAN] Ignoring stale announcement for {} from {}", a.id, from);
+            return None;
+        }
+        self.last_ts.insert(a.id.clone(), a.ts);
+        Some(LanPeer {
+            addr: SocketAddr::new(from.ip(), a.port),
+            id: a.id,
+            name: a.name,
+            ip: a.ip,
+        })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
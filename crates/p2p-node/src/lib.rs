@@ -5,26 +5,54 @@ pub mod route_manager;
 pub mod socks5;
 pub mod p2p;
 pub mod webrtc;
+pub mod config;
+pub mod kernel_nat;
+pub mod transport;
+pub mod directory;
+pub mod rules;
+pub mod http_proxy;
+pub mod forward;
+pub mod dns;
+pub mod split_dns;
+pub mod relay;
+pub mod static_peers;
+pub mod lan_discovery;
 
 
 use std::net::{Ipv4Addr, SocketAddr, IpAddr};
 
-use tun_device::TunDevice;
+use tun_device::{TunDevice, PacketWriter};
 use broadcast::BroadcastReflector;
 use p2p::P2PEvent;
 use webrtc::WebRTCManager;
 use signaling::{SignalingClient, SignalMessage, ServiceDecl};
-use gateway::GatewayRouter;
+pub use signaling::{ConnectionState, SignalingEvent};
+use gateway::{GatewayRouter, GatewayStats};
+use kernel_nat::KernelNat;
+pub use config::{NodeConfig, GatewayBackend};
+pub use rules::{RuleSet, RuleAction};
+pub use forward::{LocalForward, PublishedPort};
+pub use dns::{DnsConfig, MAGIC_DNS_IP};
+pub use tun_device::{PacketDevice, MemoryDevice, MemoryDeviceHandle};
+pub use static_peers::{NodeKey, StaticConfig, StaticPeer};
+pub use lan_discovery::LanDiscoveryConfig;
 use route_manager::RouteManager;
-use socks5::{Socks5Server, SocksMsg};
+use socks5::{Socks5Server, Socks5Credentials, UdpAssociateExit};
+use http_proxy::HttpProxy;
+use transport::{PeerLink, TcpTunnels, TunnelMsg};
+use directory::MeshDirectory;
+use rules::RuleEngine;
+use forward::{LocalForwards, PublishedPorts};
+use dns::MagicDns;
+use relay::{RelayClient, RelayMap};
+use lan_discovery::{LanDiscovery, LanPeer, LAN_PEER_TIMEOUT, LAN_REDIAL_INTERVAL};
 use anyhow::Result;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
 use tracing::{info, error, warn, debug};
 use uuid::Uuid;
 
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
@@ -44,16 +72,56 @@ pub struct PeerInfo {
     pub is_gateway: bool,
     pub connected_at: Option<u64>,
     pub route_status: String,
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+/// The node's packet device writer, shared by everything that delivers packets locally.
+pub type SharedPacketWriter = Arc<tokio::sync::Mutex<PacketWriter>>;
+
+/// Sent once the node is up: where it landed and how to reach its local proxy.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IpReport {
+    pub ip: String,
+    pub socks5_port: u16,
+    pub socks5_auth: Socks5Credentials,
+    /// HTTP proxy port, same credentials as SOCKS5 (0 if it failed to start).
+    pub http_proxy_port: u16,
 }
 
 pub enum NodeCommand {
     UpdateServices(Vec<ServiceDecl>),
+    /// Replace the proxy rules; applies to connections opened afterwards.
+    SetRules(RuleSet),
+    /// Start (or replace) a local port forward to a peer.
+    AddLocalForward(LocalForward),
+    /// Stop the local port forward listening on this address.
+    RemoveLocalForward(SocketAddr),
+    /// Publish (or remap) a local service on a port of our virtual IP.
+    PublishPort(PublishedPort),
+    /// Stop publishing this virtual IP port.
+    UnpublishPort(u16),
 }
 
+/// Node status changes for the UI and CLI.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeEvent {
+    Signaling(ConnectionState),
+    /// Heartbeat round trip to the signaling server.
+    SignalingRtt { rtt_ms: u64 },
+    /// Userspace gateway flow table counters, every `GATEWAY_STATS_INTERVAL`.
+    GatewayStats(GatewayStats),
+}
+
+const GATEWAY_STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
 pub struct P2PNode {
     virtual_ip: Ipv4Addr,
     netmask: Ipv4Addr,
     device_name: String,
+    config: NodeConfig,
+    events: Option<tokio::sync::mpsc::Sender<NodeEvent>>,
 }
 
 impl P2PNode {
@@ -62,9 +130,22 @@ impl P2PNode {
             virtual_ip,
             netmask,
             device_name,
+            config: NodeConfig::default(),
+            events: None,
         }
     }
 
+    pub fn with_config(mut self, config: NodeConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Receive `NodeEvent`s, e.g. signaling disconnects and reconnects.
+    pub fn with_events(mut self, events: tokio::sync::mpsc::Sender<NodeEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
     pub fn init_tun(&self) -> Result<(Ipv4Addr, TunDevice)> {
         let mut current_ip = self.virtual_ip;
         let mut retry_count = 0;
@@ -90,44 +171,122 @@ impl P2PNode {
                     if octets[3] == 0 || octets[3] == 255 {
                           octets[3] = 1;
                     }
+                    // Reserved for the MagicDNS resolver
+                    if Ipv4Addr::from(octets) == MAGIC_DNS_IP {
+                          octets[3] += 1;
+                    }
                     current_ip = Ipv4Addr::from(octets);
                 }
             }
         }
     }
 
+    /// Creates the TUN device and runs the node until shutdown. `signaling_urls` are
+    /// tried in order; the ones after the first are fallbacks.
+    #[allow(clippy::too_many_arguments)]
     pub async fn start(
         self, 
-        mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
-        ip_report_tx: Option<tokio::sync::mpsc::Sender<(String, u16)>>,
+        shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+        ip_report_tx: Option<tokio::sync::mpsc::Sender<IpReport>>,
         peer_update_tx: Option<tokio::sync::mpsc::Sender<Vec<PeerInfo>>>,
-        signaling_url: String,
+        signaling_urls: Vec<String>,
         token: Option<String>,
         my_id: String,
         my_meta: (Option<String>, Option<String>, Option<String>, bool),
         my_services: Vec<ServiceDecl>,
-        mut command_rx: tokio::sync::mpsc::Receiver<NodeCommand>,
+        command_rx: tokio::sync::mpsc::Receiver<NodeCommand>,
     ) -> Result<(String, u16)> {
         // 1. Setup TUN
         let (current_ip, tun) = self.init_tun()?;
+        self.start_with_device(
+            Box::new(tun), current_ip,
+            shutdown_rx, ip_report_tx, peer_update_tx, signaling_urls, token,
+            my_id, my_meta, my_services, command_rx,
+        ).await
+    }
+
+    /// Like `start`, on a packet device the caller created, e.g. a `MemoryDevice`.
+    /// `current_ip` is the virtual IP the device carries traffic for.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn start_with_device(
+        self,
+        device: Box<dyn PacketDevice>,
+        current_ip: Ipv4Addr,
+        mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+        ip_report_tx: Option<tokio::sync::mpsc::Sender<IpReport>>,
+        peer_update_tx: Option<tokio::sync::mpsc::Sender<Vec<PeerInfo>>>,
+        signaling_urls: Vec<String>,
+        token: Option<String>,
+        my_id: String,
+        my_meta: (Option<String>, Option<String>, Option<String>, bool),
+        my_services: Vec<ServiceDecl>,
+        mut command_rx: tokio::sync::mpsc::Receiver<NodeCommand>,
+    ) -> Result<(String, u16)> {
         let allocated_ip = current_ip.to_string();
+        // OS-level setup (routes, DNS, kernel NAT) only applies to a real interface
+        let is_kernel = device.is_kernel();
+        // macOS utun frames packets with a 4-byte PI header: [0, 0, 0, 2] for IPv4
+        let packet_info = device.packet_information();
+
+        let (mut tun_reader, tun_writer) = device.into_split();
+        let tun_writer: SharedPacketWriter = Arc::new(tokio::sync::Mutex::new(tun_writer));
         
-        let (mut tun_reader, tun_writer) = tun.split();
-        let tun_writer = std::sync::Arc::new(tokio::sync::Mutex::new(tun_writer));
-        
+        // Services as last declared; published ports are registered alongside them
+        let mut declared_services = my_services.clone();
+        let published = PublishedPorts::new(current_ip);
+
         // Initialize Gateway Router if we are a gateway OR have services declared
-        let gateway = if my_meta.3 || !my_services.is_empty() {
+        let wants_gateway = my_meta.3 || !my_services.is_empty();
+        let mut kernel_nat: Option<KernelNat> = None;
+        if wants_gateway && is_kernel && self.config.gateway_backend == GatewayBackend::KernelNat {
+            info!("Initializing kernel NAT gateway (nftables)...");
+            match KernelNat::install(overlay_network(current_ip, self.netmask), service_prefixes(&my_services)) {
+                // Dropped (and torn down) when this function returns
+                Ok(nat) => kernel_nat = Some(nat),
+                Err(e) => warn!("Kernel NAT unavailable: {}. Falling back to userspace gateway.", e),
+            }
+        }
+
+        let gateway = if wants_gateway && kernel_nat.is_none() {
             info!("Initializing Gateway Router (NAT)...");
-            Some(GatewayRouter::new(tun_writer.clone()))
+            Some(Arc::new(
+                GatewayRouter::with_config(tun_writer.clone(), self.config.gateway.clone())
+                    .with_tcp_nat(current_ip)
+            ))
         } else {
             None
         };
         
+        // Signaling messages from the server, or sent directly by peers over P2P
+        let (signal_tx, mut signal_rx) = tokio::sync::mpsc::channel(32);
+
         // 6. Setup P2P Manager
         let (p2p_event_tx, mut p2p_event_rx) = tokio::sync::mpsc::channel(32);
-        let p2p_manager = Arc::new(p2p::P2PManager::new(0, tun_writer.clone(), p2p_event_tx.clone(), my_id.clone())?); // Listen on random UDP port
+        // TCP streams peers open to us over direct QUIC connections
+        let (p2p_stream_tx, mut p2p_stream_rx) = tokio::sync::mpsc::channel::<p2p::IncomingStream>(32);
+        let node_key = match self.config.key.clone() {
+            Some(key) => key,
+            None => NodeKey::generate()?,
+        };
+        info!("Node public key: {}", node_key.public_key());
+        let p2p_manager = Arc::new(p2p::P2PManager::new(
+            self.config.p2p_port.unwrap_or(0), tun_writer.clone(), p2p_event_tx.clone(), my_id.clone(),
+            signal_tx.clone(), p2p_stream_tx, gateway.clone(), node_key.clone(), signaling_urls.is_empty(),
+        )?);
         let p2p_port = p2p_manager.local_port();
 
+        // Static peers must present their configured key from the first handshake on
+        let mut configured_peers = Vec::new();
+        for peer in self.config.static_peers.clone() {
+            match peer.public_key_bytes() {
+                Ok(key) => {
+                    p2p_manager.pin_peer(&peer.id, key).await;
+                    configured_peers.push(peer);
+                }
+                Err(e) => error!("Skipping static peer {}: {}", peer.name, e),
+            }
+        }
+
         let webrtc_manager = Arc::new(WebRTCManager::new(my_id.clone(), tun_writer.clone(), p2p_event_tx.clone()).await?);
 
         let mut background_tasks = Vec::new();
@@ -147,72 +306,113 @@ impl P2PNode {
 
         // 3. Setup Signaling
         // Use provided my_id instead of generating new one
-        let (signal_tx, mut signal_rx) = tokio::sync::mpsc::channel(32);
         
-        info!("Connecting to Signaling Server: {}", signaling_url);
+        let (signaling_event_tx, mut signaling_event_rx) = tokio::sync::mpsc::unbounded_channel::<SignalingEvent>();
         let group_id = token.clone().unwrap_or_else(|| "default-group".to_string());
+
+        // Packet relay for peers without a direct path; the server hands out relays after join
+        let relay = Arc::new(RelayClient::new(
+            group_id.clone(), my_id.clone(), tun_writer.clone(), signal_tx.clone(), gateway.clone(), packet_info,
+        ));
+
+        // Peers of our group on the local network, found without the server; only
+        // those whose key a static config or the server gave us
+        let (lan_tx, mut lan_rx) = tokio::sync::mpsc::channel::<LanPeer>(32);
+        if self.config.lan_discovery.enabled {
+            match LanDiscovery::new(
+                &self.config.lan_discovery, &group_id, &my_id, &self.device_name, current_ip, p2p_port,
+                node_key.clone(), p2p_manager.clone(),
+            ) {
+                Ok(discovery) => background_tasks.push(tokio::spawn(discovery.run(lan_tx))),
+                Err(e) => warn!("LAN discovery unavailable: {}", e),
+            }
+        }
         
-        let signal_client = match SignalingClient::connect(
-            &signaling_url,
-            &group_id,
-            token,
-            my_id.clone(),
-            allocated_ip.clone(),
-            self.device_name.clone(),
-            p2p_port,
-            my_meta,
-            signal_tx,
-        ).await {
-            Ok(client) => {
-                info!("Signaling connected successfully!");
-                if !my_services.is_empty() {
-                    let _ = client.send(SignalMessage::RegisterServices {
-                        id: my_id.clone(),
-                        services: my_services,
-                    }).await;
+        let signal_client = if signaling_urls.is_empty() {
+            info!("No signaling server configured; reaching static peers only");
+            None
+        } else {
+            info!("Connecting to Signaling Server: {}", signaling_urls.join(", "));
+            match SignalingClient::connect(
+                &signaling_urls,
+                &group_id,
+                token,
+                my_id.clone(),
+                allocated_ip.clone(),
+                self.device_name.clone(),
+                p2p_port,
+                my_meta,
+                self.config.lan_domains.clone(),
+                node_key.public_key(),
+                signal_tx,
+                signaling_event_tx,
+            ).await {
+                Ok(client) => {
+                    info!("Signaling connected successfully!");
+                    if !my_services.is_empty() {
+                        let _ = client.send(SignalMessage::RegisterServices {
+                            id: my_id.clone(),
+                            services: my_services,
+                        }).await;
+                    }
+                    Some(Arc::new(client))
+                },
+                Err(e) => {
+                    error!("Failed to connect to signaling server: {}", e);
+                    None
                 }
-                Some(Arc::new(client))
-            },
-            Err(e) => {
-                error!("Failed to connect to signaling server: {}", e);
-                None
             }
         };
 
         info!("Network interfaces initialized. Running on {}", allocated_ip);
 
+        let peer_link = (signal_client.is_some() || !configured_peers.is_empty())
+            .then(|| PeerLink::new(signal_client.clone(), p2p_manager.clone(), relay.clone()));
+        let udp_exit = UdpAssociateExit::new();
+        let tunnels = peer_link.as_ref().map(|l| TcpTunnels::new(l.clone(), my_id.clone()));
+
         // 4. Setup SOCKS5 & Route Table
         // Route Table (Target IP -> Peer ID)
         let mut routes: HashMap<Ipv4Addr, String> = HashMap::new();
-        // Shared Route Table for SOCKS5
-        let shared_routes = Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+        // Shared routes and peers for SOCKS5 lookups
+        let directory = MeshDirectory::new()
+            .with_domain(&self.config.dns.domain)
+            .with_accepted_domains(&self.config.dns.accept_domains);
+        let rule_engine = RuleEngine::new(self.config.rules.clone(), directory.clone());
         
         // Incoming TCP Streams (Target Side): (SourcePeerID, StreamID) -> Sender<Data>
         let mut incoming_tcp: HashMap<(String, u32), tokio::sync::mpsc::Sender<Vec<u8>>> = HashMap::new();
 
-        // Try to start SOCKS5 server, fallback to random port if 1080 is taken
         let (socks5_server, socks5_port) = match Socks5Server::new(1080).await {
             Ok((s, p)) => (Arc::new(s), p),
-            Err(_) => {
-                warn!("Port 1080 is taken, trying to allocate a random port for SOCKS5...");
-                match Socks5Server::new(0).await {
-                    Ok((s, p)) => (Arc::new(s), p),
-                    Err(e) => {
-                        error!("Failed to start SOCKS5 server even on random port: {}", e);
-                        return Err(anyhow::anyhow!("SOCKS5 Server init failed: {}", e));
-                    }
-                }
+            Err(e) => {
+                error!("Failed to start SOCKS5 server: {}", e);
+                return Err(anyhow::anyhow!("SOCKS5 Server init failed: {}", e));
+            }
+        };
+
+        // HTTP proxy next to it, sharing the SOCKS5 credentials
+        let http_proxy = match HttpProxy::new(self.config.http_proxy_port.unwrap_or(1081), socks5_server.credentials()).await {
+            Ok((p, port)) => Some((Arc::new(p.with_loopback_trusted(self.config.http_proxy_trust_loopback)), port)),
+            Err(e) => {
+                error!("Failed to start HTTP proxy: {}", e);
+                None
             }
         };
 
         // Send Initial IP Report with Port
         if let Some(tx) = ip_report_tx {
-            let _ = tx.send((allocated_ip.clone(), socks5_port)).await;
+            let _ = tx.send(IpReport {
+                ip: allocated_ip.clone(),
+                socks5_port,
+                socks5_auth: socks5_server.credentials(),
+                http_proxy_port: http_proxy.as_ref().map(|(_, port)| *port).unwrap_or(0),
+            }).await;
         }
 
         // On Windows, explicitly configure the Wintun interface BEFORE entering the main loop
         #[cfg(target_os = "windows")]
-        {
+        if is_kernel {
             info!("Pre-configuring Wintun interface 'Syuink' with IP {}...", allocated_ip);
             
             // Ensure interface is enabled
@@ -246,7 +446,7 @@ impl P2PNode {
 
         // On macOS, ensure the utun interface has the correct routing
         #[cfg(target_os = "macos")]
-        {
+        if is_kernel {
             info!("Configuring macOS routing for 10.251.0.0/24 via {}...", allocated_ip);
             // We use 'route add' to ensure the subnet is routed through the VPN
             let _ = std::process::Command::new("sudo")
@@ -259,6 +459,44 @@ impl P2PNode {
         let mut peers: HashMap<String, PeerInfo> = HashMap::new();
 
         let mut route_manager = RouteManager::new(allocated_ip.clone());
+        if !is_kernel {
+            route_manager.detach();
+        }
+
+        // Static peers: always listed, with their prefixes routed to them, and a dialer
+        // each that keeps the direct connection up
+        let static_ids: HashSet<String> = configured_peers.iter().map(|p| p.id.clone()).collect();
+        let mut static_routes: Vec<(ipnetwork::Ipv4Network, String)> = Vec::new();
+        for peer in configured_peers {
+            info!("Static peer {} ({}) at {}", peer.name, peer.ip, if peer.endpoints.is_empty() { "-".to_string() } else { peer.endpoints.join(", ") });
+            static_routes.extend(peer.routes.iter().map(|net| (*net, peer.id.clone())));
+            peers.insert(peer.id.clone(), PeerInfo {
+                id: peer.id.clone(),
+                ip: peer.ip.to_string(),
+                public_addr: None,
+                p2p_port: 0,
+                name: peer.name.clone(),
+                os: None,
+                version: None,
+                device_type: None,
+                is_gateway: !peer.routes.is_empty(),
+                connected_at: None,
+                route_status: "relay".to_string(),
+                domains: Vec::new(),
+            });
+            background_tasks.push(tokio::spawn(static_peers::keep_connected(p2p_manager.clone(), peer)));
+        }
+        // Most specific prefix first
+        static_routes.sort_by_key(|(net, _)| std::cmp::Reverse(net.prefix()));
+        if !static_ids.is_empty() {
+            directory.set_peers(peers.values().cloned().collect()).await;
+            p2p_manager.set_known_peers(peers.keys().cloned().collect()).await;
+            directory.set_prefixes(static_routes.clone()).await;
+            if let Some(ref tx) = peer_update_tx {
+                let list: Vec<PeerInfo> = peers.values().cloned().collect();
+                let _ = tx.send(list).await;
+            }
+        }
         
         // Remove the redundant re-declaration later in the file
         // let mut background_tasks = Vec::new();
@@ -266,15 +504,84 @@ impl P2PNode {
 
         
         
-        if let Some(client) = &signal_client {
+        if let Some(tunnels) = &tunnels {
              let s = socks5_server.clone();
-             let c = client.clone();
-             let m = my_id.clone();
-             let r = shared_routes.clone();
+             let t = tunnels.clone();
+             let r = rule_engine.clone();
              let task = tokio::spawn(async move {
-                 s.run(c, m, r).await;
+                 s.run(t, r).await;
              });
              background_tasks.push(task);
+
+             if let Some((proxy, _)) = &http_proxy {
+                 let p = proxy.clone();
+                 let t = tunnels.clone();
+                 let r = rule_engine.clone();
+                 background_tasks.push(tokio::spawn(async move {
+                     p.run(t, r).await;
+                 }));
+             }
+        }
+
+        // Published ports (reverse forwards); the virtual IP is configured by now
+        if !self.config.published_ports.is_empty() {
+             for port in self.config.published_ports.clone() {
+                 published.publish(port).await;
+             }
+             if let Some(client) = &signal_client {
+                 let mut services = declared_services.clone();
+                 services.extend(published.service_decls().await);
+                 let _ = client.send(SignalMessage::RegisterServices {
+                     id: my_id.clone(),
+                     services,
+                 }).await;
+             }
+        }
+
+        // MagicDNS: peer names on a reserved overlay IP, answered from the TUN reader
+        // Addresses gateways resolved for their LAN domains: (IP, gateway peer ID)
+        let (dns_route_tx, mut dns_route_rx) = tokio::sync::mpsc::channel::<(IpAddr, String)>(64);
+        let mut dns_routes: HashMap<IpAddr, String> = HashMap::new();
+        // Peers heard on the LAN: ID -> (QUIC address there, last announcement)
+        let mut lan_peers: HashMap<String, (SocketAddr, std::time::Instant)> = HashMap::new();
+        // When we last dialed each LAN peer there
+        let mut lan_dialed: HashMap<String, std::time::Instant> = HashMap::new();
+        // Peers only the LAN told us about, dropped once they stop announcing
+        let mut lan_only: HashSet<String> = HashSet::new();
+        let mut lan_expiry = tokio::time::interval(LAN_PEER_TIMEOUT / 2);
+        let mut gateway_stats_tick = tokio::time::interval(GATEWAY_STATS_INTERVAL);
+        // After a signaling (re)join: peers the server has announced again so far.
+        // Starts at the client's in-band marker; the first service update after
+        // the join marks the end of the replay.
+        let mut resync: Option<HashSet<String>> = None;
+        let magic_dns = if self.config.dns.enabled {
+            let mut dns = MagicDns::new(&self.config.dns, directory.clone(), &my_id, &self.device_name, current_ip);
+            if let Some(link) = &peer_link {
+                dns = dns.with_gateways(link.clone(), dns_route_tx);
+            }
+            Some(Arc::new(dns))
+        } else {
+            None
+        };
+        // Reverted when dropped on the way out
+        let mut dns_configurator: Option<Box<dyn split_dns::DnsConfigurator>> = None;
+        let mut split_domains: Vec<String> = Vec::new();
+        if let Some(dns) = &magic_dns {
+            info!("MagicDNS serving *.{} on {}", dns.domain(), MAGIC_DNS_IP);
+            if self.config.dns.configure_os && is_kernel {
+                dns_configurator = Some(split_dns::platform_configurator("Syuink"));
+                refresh_split_dns(&mut dns_configurator, &mut split_domains, dns.split_domains(&[]));
+            }
+        }
+
+        // Local port forwards (ssh -L style)
+        let mut local_forwards = LocalForwards::new();
+        if let Some(tunnels) = &tunnels {
+             for fwd in self.config.local_forwards.clone() {
+                 if let Err(e) = local_forwards.add(fwd, tunnels.clone(), directory.clone()).await {
+                     error!("{}", e);
+                 }
+             }
         }
 
         loop {
@@ -288,6 +595,7 @@ impl P2PNode {
                                 task.abort();
                             }
                             route_manager.cleanup();
+                            relay.close();
                             break Ok((allocated_ip, socks5_port));
                         }
                         Err(tokio::sync::broadcast::error::RecvError::Closed) => {
@@ -296,6 +604,7 @@ impl P2PNode {
                                 task.abort();
                             }
                             route_manager.cleanup();
+                            relay.close();
                             break Ok((allocated_ip, socks5_port));
                         }
                         Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
@@ -309,24 +618,101 @@ impl P2PNode {
                     match cmd {
                         NodeCommand::UpdateServices(decls) => {
                              info!("Updating services: {} entries", decls.len());
+                             if let Some(nat) = kernel_nat.as_mut() {
+                                 if let Err(e) = nat.update_prefixes(service_prefixes(&decls)) {
+                                     error!("Failed to update kernel NAT rules: {}", e);
+                                 }
+                             }
+                             declared_services = decls;
+                             if let Some(client) = &signal_client {
+                                 let mut services = declared_services.clone();
+                                 services.extend(published.service_decls().await);
+                                 let _ = client.send(SignalMessage::RegisterServices {
+                                     id: my_id.clone(),
+                                     services,
+                                 }).await;
+                             }
+                        }
+                        NodeCommand::SetRules(rules) => {
+                             info!("Updating proxy rules: {} rules, default {:?}", rules.rules.len(), rules.default_action);
+                             rule_engine.set_rules(rules).await;
+                        }
+                        NodeCommand::AddLocalForward(fwd) => {
+                             match &tunnels {
+                                 Some(tunnels) => {
+                                     if let Err(e) = local_forwards.add(fwd, tunnels.clone(), directory.clone()).await {
+                                         error!("{}", e);
+                                     }
+                                 }
+                                 None => warn!("Cannot forward {} without a signaling connection", fwd.listen),
+                             }
+                        }
+                        NodeCommand::RemoveLocalForward(listen) => {
+                             if !local_forwards.remove(&listen) {
+                                 warn!("No local forward on {}", listen);
+                             }
+                        }
+                        NodeCommand::PublishPort(port) => {
+                             published.publish(port).await;
                              if let Some(client) = &signal_client {
+                                 let mut services = declared_services.clone();
+                                 services.extend(published.service_decls().await);
                                  let _ = client.send(SignalMessage::RegisterServices {
                                      id: my_id.clone(),
-                                     services: decls,
+                                     services,
                                  }).await;
                              }
                         }
+                        NodeCommand::UnpublishPort(port) => {
+                             if published.unpublish(port).await {
+                                 if let Some(client) = &signal_client {
+                                     let mut services = declared_services.clone();
+                                     services.extend(published.service_decls().await);
+                                     let _ = client.send(SignalMessage::RegisterServices {
+                                         id: my_id.clone(),
+                                         services,
+                                     }).await;
+                                 }
+                             }
+                        }
+                    }
+                }
+
+                // Signaling connection state and heartbeat RTT
+                Some(event) = signaling_event_rx.recv() => {
+                    let event = match event {
+                        SignalingEvent::State(state) => NodeEvent::Signaling(state),
+                        SignalingEvent::Rtt(rtt) => NodeEvent::SignalingRtt { rtt_ms: rtt.as_millis() as u64 },
+                    };
+                    if let Some(tx) = &self.events {
+                        let _ = tx.send(event).await;
                     }
                 }
 
                 // Handle Signaling Messages
                 Some(msg) = signal_rx.recv() => {
                     match msg {
-                        SignalMessage::PeerJoined { id, ip, public_addr, p2p_port, name, os, version, device_type, is_gateway, connected_at } => {
+                        SignalMessage::Resync => resync = Some(HashSet::new()),
+                        SignalMessage::PeerJoined { id, ip, public_addr, p2p_port, name, os, version, device_type, is_gateway, connected_at, domains, public_key } => {
                             info!("New Peer Joined: {} ({}) - {} [Public: {:?}:{}]", name, ip, id, public_addr, p2p_port);
-                            
+                            if let Some(seen) = resync.as_mut() {
+                                seen.insert(id.clone());
+                            }
+                            lan_only.remove(&id);
+                            // Direct connections must present the key the server vouches for;
+                            // a static config's pin wins
+                            if !static_ids.contains(&id) {
+                                if let Some(key) = public_key.filter(|k| !k.is_empty()).and_then(|k| BASE64.decode(k).ok()) {
+                                    p2p_manager.pin_peer(&id, key).await;
+                                }
+                            }
+                            // Re-announced after a reconnect while we still have a direct path
+                            let direct = peers.get(&id).is_some_and(|p| p.route_status != "relay");
+
                             // Try P2P (QUIC) connection if public address and port are available
-                            if let (Some(ref pa), port) = (&public_addr, p2p_port) {
+                            if direct {
+                                debug!("Keeping existing direct connection to {}", name);
+                            } else if let (Some(ref pa), port) = (&public_addr, p2p_port) {
                                 if port > 0 && pa != "unknown" {
                                     if let Ok(ip_addr) = pa.parse::<IpAddr>() {
                                         let addr = SocketAddr::new(ip_addr, port);
@@ -344,7 +730,7 @@ impl P2PNode {
                             }
 
                             // Also try WebRTC connection in parallel
-                            if let Some(sc) = &signal_client {
+                            if let (false, Some(sc)) = (direct, &signal_client) {
                                 info!("Attempting P2P (WebRTC) connection to {}", name);
                                 let wm = webrtc_manager.clone();
                                 let sc = sc.clone();
@@ -372,9 +758,16 @@ impl P2PNode {
                                 is_gateway,
                                 connected_at,
                                 route_status: existing_status,
+                                domains,
                             };
 
                             peers.insert(id, peer_info);
+                            directory.set_peers(peers.values().cloned().collect()).await;
+                            p2p_manager.set_known_peers(peers.keys().cloned().collect()).await;
+                            if let Some(dns) = &magic_dns {
+                                let wanted = dns.split_domains(&peers.values().cloned().collect::<Vec<_>>());
+                                refresh_split_dns(&mut dns_configurator, &mut split_domains, wanted);
+                            }
 
                             if let Some(ref tx) = peer_update_tx {
                                 let list: Vec<PeerInfo> = peers.values().cloned().collect();
@@ -383,7 +776,21 @@ impl P2PNode {
                         }
                         SignalMessage::PeerLeft { id } => {
                             info!("Peer Left: {}", id);
+                            // Gone from the server, but still reachable next to us
+                            if lan_peers.contains_key(&id) {
+                                info!("Keeping {} while it is on the LAN", id);
+                                lan_only.insert(id);
+                                continue;
+                            }
                             peers.remove(&id);
+                            directory.set_peers(peers.values().cloned().collect()).await;
+                            p2p_manager.set_known_peers(peers.keys().cloned().collect()).await;
+                            if let Some(dns) = &magic_dns {
+                                let wanted = dns.split_domains(&peers.values().cloned().collect::<Vec<_>>());
+                                refresh_split_dns(&mut dns_configurator, &mut split_domains, wanted);
+                            }
+                            // Addresses its DNS resolved are only reachable through it
+                            dns_routes.retain(|_, peer_id| *peer_id != id);
                             
                             if let Some(ref tx) = peer_update_tx {
                                 let list: Vec<PeerInfo> = peers.values().cloned().collect();
@@ -392,53 +799,86 @@ impl P2PNode {
                         }
                         SignalMessage::ServiceUpdate { services } => {
                              info!("Received Service Update: {} entries", services.len());
+                             // Peers the server did not re-announce after a reconnect left while we were away
+                             if let Some(seen) = resync.take() {
+                                 let before = peers.len();
+                                 peers.retain(|id, _| seen.contains(id) || static_ids.contains(id) || lan_peers.contains_key(id));
+                                 lan_only.extend(peers.keys().filter(|id| !seen.contains(*id) && !static_ids.contains(*id)).cloned());
+                                 if peers.len() != before {
+                                     info!("Dropped {} peers that left during the signaling outage", before - peers.len());
+                                     dns_routes.retain(|_, peer_id| peers.contains_key(peer_id));
+                                     directory.set_peers(peers.values().cloned().collect()).await;
+                                     p2p_manager.set_known_peers(peers.keys().cloned().collect()).await;
+                                     if let Some(dns) = &magic_dns {
+                                         let wanted = dns.split_domains(&peers.values().cloned().collect::<Vec<_>>());
+                                         refresh_split_dns(&mut dns_configurator, &mut split_domains, wanted);
+                                     }
+                                     if let Some(ref tx) = peer_update_tx {
+                                         let list: Vec<PeerInfo> = peers.values().cloned().collect();
+                                         let _ = tx.send(list).await;
+                                     }
+                                 }
+                             }
                              routes.clear();
+                             let mut proxy_routes: HashMap<IpAddr, String> = HashMap::new();
                              let mut new_ips = Vec::new();
                              for (peer_id, decl) in services {
-                                 if let Ok(ip) = decl.ip.parse::<Ipv4Addr>() {
-                                     if peer_id == my_id { continue; }
-                                     routes.insert(ip, peer_id);
-                                     new_ips.push(ip);
+                                 if peer_id == my_id { continue; }
+                                 match decl.ip.parse::<IpAddr>() {
+                                     Ok(IpAddr::V4(ip)) => {
+                                         routes.insert(ip, peer_id.clone());
+                                         proxy_routes.insert(IpAddr::V4(ip), peer_id);
+                                         new_ips.push(ip);
+                                     }
+                                     // IPv6 services are only reachable through the proxies
+                                     Ok(ip) => {
+                                         proxy_routes.insert(ip, peer_id);
+                                     }
+                                     Err(_) => {}
+                                 }
+                             }
+                             // Keep routes learned from gateway DNS answers unless a service claims the IP
+                             for (ip, peer_id) in &dns_routes {
+                                 if proxy_routes.contains_key(ip) { continue; }
+                                 proxy_routes.insert(*ip, peer_id.clone());
+                                 if let IpAddr::V4(v4) = ip {
+                                     routes.insert(*v4, peer_id.clone());
+                                     new_ips.push(*v4);
                                  }
                              }
                              route_manager.update_routes(&new_ips);
                              
                              // Update shared routes for SOCKS5
-                             let mut sr = shared_routes.lock().await;
-                             *sr = routes.clone();
+                             directory.set_routes(proxy_routes).await;
                         }
-                        SignalMessage::Broadcast { source, data } => {
+                        SignalMessage::Broadcast { source, data: raw } => {
                             if source == my_id { continue; }
-                            if let Ok(raw) = BASE64.decode(&data) {
-                                // info!("Received Broadcast from {}, writing {} bytes to TUN", source, raw.len());
-                                let mut writer = tun_writer.lock().await;
-                                let _ = writer.write_all(&raw).await;
-                            }
+                            // info!("Received Broadcast from {}, writing {} bytes to TUN", source, raw.len());
+                            let mut writer = tun_writer.lock().await;
+                            let _ = writer.write_all(&raw).await;
                         }
-                        SignalMessage::TunPacket { source, data, .. } => {
-                             if let Ok(raw) = BASE64.decode(&data) {
-                                 info!("[Relay] Received TunPacket ({} bytes) from {}", raw.len(), source);
-                                 let mut writer = tun_writer.lock().await;
-                                 
-                                 #[cfg(target_os = "macos")]
-                                 {
-                                     // macOS utun requires a 4-byte PI header: [0, 0, 0, 2] for IPv4
-                                     let mut pi_packet = Vec::with_capacity(raw.len() + 4);
-                                     pi_packet.extend_from_slice(&[0, 0, 0, 2]);
-                                     pi_packet.extend_from_slice(&raw);
-                                     if let Err(e) = writer.write_all(&pi_packet).await {
-                                         error!("[Relay] Failed to write TunPacket to macOS TUN: {}", e);
-                                     }
+                        SignalMessage::TunPacket { source, data: raw, .. } => {
+                             info!("[Relay] Received TunPacket ({} bytes) from {}", raw.len(), source);
+
+                             // Packets for a LAN behind us go through the gateway NAT, not our own TUN.
+                             if let Some(gw) = &gateway {
+                                 if gateway::is_lan_bound(&raw) {
+                                     let _ = gw.handle_packet(&raw).await;
+                                     continue;
                                  }
-                                 
-                                 #[cfg(not(target_os = "macos"))]
-                                 {
-                                     if let Err(e) = writer.write_all(&raw).await {
-                                         error!("[Relay] Failed to write TunPacket to TUN: {}", e);
-                                     }
+                             }
+
+                             let mut writer = tun_writer.lock().await;
+                             
+                             if packet_info {
+                                 let mut pi_packet = Vec::with_capacity(raw.len() + 4);
+                                 pi_packet.extend_from_slice(&[0, 0, 0, 2]);
+                                 pi_packet.extend_from_slice(&raw);
+                                 if let Err(e) = writer.write_all(&pi_packet).await {
+                                     error!("[Relay] Failed to write TunPacket to macOS TUN: {}", e);
                                  }
-                             } else {
-                                 error!("[Relay] Failed to decode TunPacket data from {}", source);
+                             } else if let Err(e) = writer.write_all(&raw).await {
+                                 error!("[Relay] Failed to write TunPacket to TUN: {}", e);
                              }
                         }
                         SignalMessage::TcpConnect { stream_id, source: source_peer, target_ip, target_port, .. } => {
@@ -448,17 +888,20 @@ impl P2PNode {
                                 let my_id = my_id.clone();
                                 let source_peer = source_peer.clone();
                                 let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(32);
+                                let published = published.clone();
                                 
                                 incoming_tcp.insert((source_peer.clone(), stream_id), tx);
                                 
                                 tokio::spawn(async move {
-                                    match TcpStream::connect(format!("{}:{}", target_ip, target_port)).await {
+                                    match published.connect(&target_ip, target_port).await {
                                         Ok(socket) => {
                                             let _ = client.send(SignalMessage::TcpConnected {
                                                 stream_id,
                                                 target: source_peer.clone(),
                                                 source: my_id.clone(),
                                                 success: true,
+                                                reply: 0x00,
+                                                bind_addr: socket.local_addr().ok().map(|a| a.to_string()),
                                             }).await;
                                             
                                             let (mut rd, mut wr) = socket.into_split();
@@ -473,12 +916,11 @@ impl P2PNode {
                                                     match rd.read(&mut buf).await {
                                                         Ok(0) => break,
                                                         Ok(n) => {
-                                                            let b64 = BASE64.encode(&buf[..n]);
                                                             let _ = c.send(SignalMessage::TcpData {
                                                                 stream_id,
                                                                 target: sp.clone(),
                                                                 source: mp.clone(),
-                                                                data: b64,
+                                                                data: buf[..n].to_vec(),
                                                             }).await;
                                                         }
                                                         Err(_) => break,
@@ -499,29 +941,81 @@ impl P2PNode {
                                                 target: source_peer,
                                                 source: my_id,
                                                 success: false,
+                                                reply: socks5::reply_code_for(&e),
+                                                bind_addr: None,
                                             }).await;
                                         }
                                     }
                                 });
                             }
                         }
-                        SignalMessage::TcpConnected { stream_id, success, .. } => {
-                            socks5_server.on_msg(stream_id, SocksMsg::Connected(success)).await;
-                        }
-                        SignalMessage::TcpData { stream_id, data, source: source_peer, .. } => {
-                            if let Ok(bytes) = BASE64.decode(&data) {
-                                 // Try Socks5 (Initiator)
-                                 socks5_server.on_msg(stream_id, SocksMsg::Data(bytes.clone())).await;
-                                 // Try Incoming (Target)
-                                 if let Some(tx) = incoming_tcp.get(&(source_peer, stream_id)) {
-                                     let _ = tx.send(bytes).await;
-                                 }
+                        SignalMessage::TcpConnected { stream_id, success, reply, bind_addr, .. } => {
+                            // Peers predating reply codes only say success or not
+                            let reply = match (success, reply) {
+                                (true, _) => 0x00,
+                                (false, 0x00) => 0x04,
+                                (false, code) => code,
+                            };
+                            let bind_addr = bind_addr.and_then(|a| a.parse::<SocketAddr>().ok());
+                            if let Some(t) = &tunnels {
+                                t.on_msg(stream_id, TunnelMsg::Connected { reply, bind_addr }).await;
                             }
                         }
+                        SignalMessage::TcpData { stream_id, data: bytes, source: source_peer, .. } => {
+                             // Try local tunnels (Initiator)
+                             if let Some(t) = &tunnels {
+                                 t.on_msg(stream_id, TunnelMsg::Data(bytes.clone())).await;
+                             }
+                             // Try Incoming (Target)
+                             if let Some(tx) = incoming_tcp.get(&(source_peer, stream_id)) {
+                                 let _ = tx.send(bytes).await;
+                             }
+                        }
                         SignalMessage::TcpClose { stream_id, source: source_peer, .. } => {
-                             socks5_server.on_msg(stream_id, SocksMsg::Closed).await;
+                             if let Some(t) = &tunnels {
+                                 t.on_msg(stream_id, TunnelMsg::Closed).await;
+                             }
                              incoming_tcp.remove(&(source_peer, stream_id));
                         }
+                        SignalMessage::UdpData { assoc_id, source: source_peer, dst_ip, dst_port, data, .. } => {
+                            if let (Some(link), Ok(bytes)) = (&peer_link, BASE64.decode(&data)) {
+                                if let Err(e) = udp_exit.handle_data(link, &my_id, source_peer, assoc_id, &dst_ip, dst_port, &bytes).await {
+                                    debug!("SOCKS5 UDP exit to {}:{} failed: {}", dst_ip, dst_port, e);
+                                }
+                            }
+                        }
+                        SignalMessage::UdpReply { assoc_id, src_ip, src_port, data, .. } => {
+                            if let (Ok(ip), Ok(bytes)) = (src_ip.parse::<IpAddr>(), BASE64.decode(&data)) {
+                                socks5_server.on_udp_reply(assoc_id, SocketAddr::new(ip, src_port), &bytes).await;
+                            }
+                        }
+                        SignalMessage::DnsQuery { query_id, source: source_peer, data, .. } => {
+                            // A peer resolving a name under one of our LAN domains
+                            if let (Some(link), Ok(query)) = (&peer_link, BASE64.decode(&data)) {
+                                let link = link.clone();
+                                let my_id = my_id.clone();
+                                let lan_domains = self.config.lan_domains.clone();
+                                tokio::spawn(async move {
+                                    if let Some(answer) = dns::resolve_for_peer(&query, &lan_domains).await {
+                                        let _ = link.send_unreliable(&source_peer, SignalMessage::DnsAnswer {
+                                            query_id,
+                                            target: source_peer.clone(),
+                                            source: my_id,
+                                            data: BASE64.encode(answer),
+                                        }).await;
+                                    }
+                                });
+                            }
+                        }
+                        SignalMessage::RelayMap { relays, token, cert_sha256 } => {
+                            info!("Packet relays: {}", relays.join(", "));
+                            relay.set_relays(RelayMap { urls: relays, token, cert_sha256 });
+                        }
+                        SignalMessage::DnsAnswer { query_id, data, .. } => {
+                            if let (Some(dns), Ok(answer)) = (&magic_dns, BASE64.decode(&data)) {
+                                dns.on_answer(query_id, answer).await;
+                            }
+                        }
                         SignalMessage::Offer { source, sdp, .. } => {
                             info!("Received WebRTC Offer from {}", source);
                             if let Some(sc) = &signal_client {
@@ -559,6 +1053,31 @@ impl P2PNode {
                 }
 
                 // Handle P2P Events
+                // Direct TCP stream from a peer (Target Side)
+                Some(mut stream) = p2p_stream_rx.recv() => {
+                    // Only peers of our group, known from signaling, static config or the LAN
+                    if !peers.contains_key(&stream.peer_id) {
+                        warn!("Refusing P2P TCP stream from unknown peer {}", stream.peer_id);
+                        tokio::spawn(async move { let _ = stream.reply(0x02).await; });
+                        continue;
+                    }
+                    info!("Incoming P2P TCP stream from {}: {}:{}", stream.peer_id, stream.host, stream.port);
+                    let published = published.clone();
+                    tokio::spawn(async move {
+                        match published.connect(&stream.host, stream.port).await {
+                            Ok(socket) => {
+                                if stream.reply(0x00).await.is_ok() {
+                                    transport::splice_quic(socket, stream.send, stream.recv).await;
+                                }
+                            }
+                            Err(e) => {
+                                error!("Failed to connect local target: {}", e);
+                                let _ = stream.reply(socks5::reply_code_for(&e)).await;
+                            }
+                        }
+                    });
+                }
+
                 Some(event) = p2p_event_rx.recv() => {
                     match event {
                         P2PEvent::Connected(id, transport) => {
@@ -599,14 +1118,18 @@ impl P2PNode {
                                 task.abort();
                             }
                             route_manager.cleanup();
+                            relay.close();
                             break Ok((allocated_ip, socks5_port));
                         }
                         Ok(n) => {
-                            #[cfg(target_os = "macos")]
-                            let packet_data = if n >= 4 { &buf[4..n] } else { &buf[..n] };
-                            
-                            #[cfg(not(target_os = "macos"))]
-                            let packet_data = &buf[..n];
+                            // Our kernel's half of a gateway TCP NAT session: make it look like
+                            // the LAN host answered, then route it to the client peer below.
+                            if let Some(gw) = &gateway {
+                                let start = if packet_info && n >= 4 { 4 } else { 0 };
+                                gw.rewrite_tcp_reply(&mut buf[start..n]).await;
+                            }
+
+                            let packet_data = if packet_info && n >= 4 { &buf[4..n] } else { &buf[..n] };
                             
                             if let Ok(ipv4) = Ipv4HeaderSlice::from_slice(packet_data) {
                                 let dest_ip = std::net::Ipv4Addr::from(ipv4.destination_addr());
@@ -618,6 +1141,24 @@ impl P2PNode {
                                     info!("[TUN] Outbound: {} -> {} ({} bytes)", src_ip, dest_ip, n);
                                 }
                                 
+                                // DNS queries to MagicDNS never leave this node
+                                if dest_ip == MAGIC_DNS_IP {
+                                    if let Some(dns) = &magic_dns {
+                                        let dns = dns.clone();
+                                        let writer = tun_writer.clone();
+                                        let query = packet_data.to_vec();
+                                        tokio::spawn(async move {
+                                            if let Some(mut reply) = dns.handle_packet(&query).await {
+                                                if packet_info {
+                                                    reply.splice(0..0, [0u8, 0, 0, 2]);
+                                                }
+                                                let _ = writer.lock().await.write_all(&reply).await;
+                                            }
+                                        });
+                                    }
+                                    continue;
+                                }
+
                                 let is_broadcast = dest_ip.is_broadcast() || dest_ip.is_multicast() || dest_ip.octets()[3] == 255;
                                 let mut handled = false;
 
@@ -629,21 +1170,23 @@ impl P2PNode {
                                         info!("[Route] Forwarding to peer: {}", peer.name);
                                         let mut sent_p2p = false;
                                         
-                                        // Try P2P first
+                                        // Try P2P first, then the packet relay
                                         if let Some(conn) = p2p_manager.get_connection(&peer.id).await {
                                             if let Ok(_) = conn.send_datagram(packet_data.to_vec().into()) {
                                                 sent_p2p = true;
                                             }
                                         }
+                                        if !sent_p2p {
+                                            sent_p2p = relay.send_packet(&peer.id, packet_data).await;
+                                        }
 
                                         if !sent_p2p {
                                             if let Some(client) = &signal_client {
                                                 debug!("[Relay] Forwarding {} bytes to {} ({}) via Server", packet_data.len(), peer.name, peer.ip);
-                                                let b64 = BASE64.encode(packet_data);
                                                 let _ = client.send(SignalMessage::TunPacket {
                                                     target: peer.id.clone(),
                                                     source: my_id.clone(),
-                                                    data: b64,
+                                                    data: packet_data.to_vec(),
                                                 }).await;
                                             }
                                         }
@@ -657,14 +1200,24 @@ impl P2PNode {
 
                                 // 2. Try routing for external subnets (via gateway/services)
                                 if !handled && !is_vpn_traffic && !is_broadcast {
-                                    if let Some(target_peer_id) = routes.get(&dest_ip) {
-                                         if ipv4.protocol() == etherparse::IpNumber::UDP {
+                                    let target = routes.get(&dest_ip).or_else(|| {
+                                        static_routes.iter().find(|(net, _)| net.contains(dest_ip)).map(|(_, id)| id)
+                                    });
+                                    if let Some(target_peer_id) = target {
+                                         // Any protocol: the owning peer's gateway NATs it onto its LAN
+                                         let mut sent_p2p = false;
+                                         if let Some(conn) = p2p_manager.get_connection(target_peer_id).await {
+                                             sent_p2p = conn.send_datagram(packet_data.to_vec().into()).is_ok();
+                                         }
+                                         if !sent_p2p {
+                                             sent_p2p = relay.send_packet(target_peer_id, packet_data).await;
+                                         }
+                                         if !sent_p2p {
                                              if let Some(client) = &signal_client {
-                                                 let b64 = BASE64.encode(packet_data);
                                                  let _ = client.send(SignalMessage::TunPacket {
                                                      target: target_peer_id.clone(),
                                                      source: my_id.clone(),
-                                                     data: b64,
+                                                     data: packet_data.to_vec(),
                                                  }).await;
                                              }
                                          }
@@ -682,10 +1235,9 @@ impl P2PNode {
                                 // 3. Fallback to Broadcast for broadcast/multicast or unknown VPN destinations
                                 if !handled && (is_broadcast || is_vpn_traffic) {
                                     if let Some(client) = &signal_client {
-                                        let b64 = BASE64.encode(packet_data);
                                         let _ = client.send(SignalMessage::Broadcast {
                                             source: my_id.clone(),
-                                            data: b64,
+                                            data: packet_data.to_vec(),
                                         }).await;
                                     }
                                 }
@@ -697,11 +1249,115 @@ impl P2PNode {
                                 task.abort();
                             }
                             route_manager.cleanup();
+                            relay.close();
                             break Ok((allocated_ip, socks5_port));
                         }
                     }
                 }
 
+                // A peer announcing itself on the LAN: its LAN address beats any other path
+                Some(lan) = lan_rx.recv() => {
+                    let previous = lan_peers.insert(lan.id.clone(), (lan.addr, std::time::Instant::now()));
+                    let moved = previous.map(|(addr, _)| addr) != Some(lan.addr);
+                    if moved {
+                        info!("[LAN] Found {} ({}) at {}", lan.name, lan.ip, lan.addr);
+                    }
+                    if !peers.contains_key(&lan.id) {
+                        lan_only.insert(lan.id.clone());
+                        peers.insert(lan.id.clone(), PeerInfo {
+                            id: lan.id.clone(),
+                            ip: lan.ip.to_string(),
+                            public_addr: None,
+                            p2p_port: lan.addr.port(),
+                            name: lan.name.clone(),
+                            os: None,
+                            version: None,
+                            device_type: None,
+                            is_gateway: false,
+                            connected_at: None,
+                            route_status: "relay".to_string(),
+                            domains: Vec::new(),
+                        });
+                        directory.set_peers(peers.values().cloned().collect()).await;
+                        p2p_manager.set_known_peers(peers.keys().cloned().collect()).await;
+                        if let Some(dns) = &magic_dns {
+                            let wanted = dns.split_domains(&peers.values().cloned().collect::<Vec<_>>());
+                            refresh_split_dns(&mut dns_configurator, &mut split_domains, wanted);
+                        }
+                        if let Some(ref tx) = peer_update_tx {
+                            let list: Vec<PeerInfo> = peers.values().cloned().collect();
+                            let _ = tx.send(list).await;
+                        }
+                    }
+                    // Dial on a new address, otherwise retry now and then; not on every announcement
+                    let due = !lan_dialed.get(&lan.id).is_some_and(|dialed| dialed.elapsed() < LAN_REDIAL_INTERVAL);
+                    if moved || due {
+                        lan_dialed.insert(lan.id.clone(), std::time::Instant::now());
+                        let pm = p2p_manager.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = pm.prefer_path(lan.id, lan.addr).await {
+                                debug!("[LAN] Direct connection to {} failed: {}", lan.name, e);
+                            }
+                        });
+                    }
+                }
+
+                // Gateway counters for the UI and CLI
+                _ = gateway_stats_tick.tick(), if gateway.is_some() && self.events.is_some() => {
+                    if let (Some(gw), Some(tx)) = (&gateway, &self.events) {
+                        let _ = tx.send(NodeEvent::GatewayStats(gw.stats().await)).await;
+                    }
+                }
+
+                // Forget LAN peers that stopped announcing themselves
+                _ = lan_expiry.tick() => {
+                    let now = std::time::Instant::now();
+                    let silent: Vec<String> = lan_peers.iter()
+                        .filter(|(_, (_, heard))| now.duration_since(*heard) > LAN_PEER_TIMEOUT)
+                        .map(|(id, _)| id.clone())
+                        .collect();
+                    let mut changed = false;
+                    for id in silent {
+                        lan_peers.remove(&id);
+                        lan_dialed.remove(&id);
+                        if lan_only.remove(&id) {
+                            info!("[LAN] Lost {}", id);
+                            peers.remove(&id);
+                            dns_routes.retain(|_, peer_id| *peer_id != id);
+                            changed = true;
+                        }
+                    }
+                    if changed {
+                        directory.set_peers(peers.values().cloned().collect()).await;
+                        p2p_manager.set_known_peers(peers.keys().cloned().collect()).await;
+                        if let Some(dns) = &magic_dns {
+                            let wanted = dns.split_domains(&peers.values().cloned().collect::<Vec<_>>());
+                            refresh_split_dns(&mut dns_configurator, &mut split_domains, wanted);
+                        }
+                        if let Some(ref tx) = peer_update_tx {
+                            let list: Vec<PeerInfo> = peers.values().cloned().collect();
+                            let _ = tx.send(list).await;
+                        }
+                    }
+                }
+
+                // Address a gateway resolved for one of its LAN names: route it through that gateway
+                Some((ip, peer_id)) = dns_route_rx.recv() => {
+                    let is_overlay = matches!(ip, IpAddr::V4(v4) if v4.octets()[0] == 10 && v4.octets()[1] == 251);
+                    if is_overlay || ip.is_loopback() || ip.is_unspecified() || dns_routes.contains_key(&ip) {
+                        continue;
+                    }
+                    info!("[DNS] Routing {} via gateway {}", ip, peer_id);
+                    dns_routes.insert(ip, peer_id.clone());
+                    if let IpAddr::V4(v4) = ip {
+                        if !routes.contains_key(&v4) {
+                            routes.insert(v4, peer_id.clone());
+                            route_manager.update_routes(&routes.keys().copied().collect::<Vec<_>>());
+                        }
+                    }
+                    directory.add_route(ip, peer_id).await;
+                }
+
                 // Read from Broadcast Reflector
                 Some((payload, port)) = broadcast_rx.recv() => {
                     let dst_ip = match port {
@@ -723,10 +1379,9 @@ impl P2PNode {
                     let mut packet = Vec::with_capacity(payload.len() + 64);
                     if let Ok(_) = builder.write(&mut packet, &payload) {
                          if let Some(client) = &signal_client {
-                             let b64 = BASE64.encode(&packet);
                              let _ = client.send(SignalMessage::Broadcast {
                                  source: my_id.clone(),
-                                 data: b64,
+                                 data: packet,
                              }).await;
                          }
                     }
@@ -734,4 +1389,37 @@ impl P2PNode {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// The overlay subnet our virtual IP lives in, e.g. 10.251.0.0/24.
+fn overlay_network(ip: Ipv4Addr, netmask: Ipv4Addr) -> ipnetwork::Ipv4Network {
+    let prefix = ipnetwork::ipv4_mask_to_prefix(netmask).unwrap_or(24);
+    let network = Ipv4Addr::from(u32::from(ip) & u32::from(netmask));
+    ipnetwork::Ipv4Network::new(network, prefix).expect("prefix derived from a valid mask")
+}
+
+/// Host prefixes for the service IPs a gateway advertises.
+fn service_prefixes(services: &[ServiceDecl]) -> Vec<ipnetwork::Ipv4Network> {
+    let mut prefixes: Vec<ipnetwork::Ipv4Network> = services.iter()
+        .filter_map(|s| s.ip.parse::<Ipv4Addr>().ok())
+        .map(ipnetwork::Ipv4Network::from)
+        .collect();
+    prefixes.sort();
+    prefixes.dedup();
+    prefixes
+}
+
+/// Re-points the OS resolver when the set of split DNS domains changes.
+fn refresh_split_dns(configurator: &mut Option<Box<dyn split_dns::DnsConfigurator>>, applied: &mut Vec<String>, wanted: Vec<String>) {
+    let configurator = match configurator {
+        Some(c) => c,
+        None => return,
+    };
+    if *applied == wanted {
+        return;
+    }
+    match configurator.apply(MAGIC_DNS_IP, &wanted) {
+        Ok(()) => *applied = wanted,
+        Err(e) => warn!("Failed to configure split DNS: {}", e),
+    }
+}
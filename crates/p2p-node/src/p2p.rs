@@ -1,10 +1,19 @@
 use std::{net::SocketAddr, sync::Arc};
 use anyhow::{Result, Context};
-use quinn::{Endpoint, Connection};
-use tracing::{info, warn};
+use quinn::{Endpoint, Connection, RecvStream, SendStream};
+use tracing::{debug, info, warn};
 use tokio::sync::Mutex;
-use std::collections::HashMap;
-use tokio::io::AsyncWriteExt;
+use std::collections::{HashMap, HashSet};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use crate::signaling::SignalMessage;
+use crate::gateway::{is_lan_bound, GatewayRouter};
+use crate::static_peers::{cert_public_key, NodeKey};
+use crate::SharedPacketWriter;
+
+// Datagrams normally carry raw IP packets, whose first nibble is 4 or 6.
+// A leading MSG_TAG byte marks a JSON `SignalMessage` sent peer-to-peer instead.
+const MSG_TAG: u8 = 0x01;
 
 #[derive(Debug, Clone)]
 pub enum P2PTransport {
@@ -12,6 +21,35 @@ pub enum P2PTransport {
     WebRTC,
 }
 
+/// Header of a bidirectional QUIC stream carrying one TCP connection, sent
+/// length-prefixed (u16 BE) before the payload. The acceptor answers with a single
+/// SOCKS5 reply code byte and then relays bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StreamHeader {
+    host: String,
+    port: u16,
+}
+
+/// A peer opened a direct TCP stream to `host:port` through us.
+pub struct IncomingStream {
+    pub peer_id: String,
+    pub host: String,
+    pub port: u16,
+    pub send: SendStream,
+    pub recv: RecvStream,
+}
+
+impl IncomingStream {
+    /// Answers the connect request; anything but 0 also ends the stream.
+    pub async fn reply(&mut self, code: u8) -> Result<()> {
+        self.send.write_all(&[code]).await?;
+        if code != 0 {
+            let _ = self.send.finish().await;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum P2PEvent {
     Connected(String, P2PTransport),
@@ -21,26 +59,52 @@ pub enum P2PEvent {
 pub struct P2PManager {
     endpoint: Endpoint,
     connections: Arc<Mutex<HashMap<String, Connection>>>,
+    // Our certificate key, presented to peers we dial too
+    key: NodeKey,
+    // Peer ID -> public key it must present (static peers)
+    pinned: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    // Peer IDs in the mesh's peer map; with the pinned ones, the only peers whose
+    // packets and messages we take
+    known: Arc<Mutex<HashSet<String>>>,
+    // Without a signaling server nobody vouches for unpinned IDs; refuse them
+    pinned_only: bool,
     event_tx: tokio::sync::mpsc::Sender<P2PEvent>,
     my_id: String,
+    tun_writer: SharedPacketWriter,
+    message_tx: tokio::sync::mpsc::Sender<SignalMessage>,
+    stream_tx: tokio::sync::mpsc::Sender<IncomingStream>,
+    gateway: Option<Arc<GatewayRouter>>,
 }
 
 impl P2PManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         bind_port: u16, 
-        tun_writer: Arc<tokio::sync::Mutex<tokio::io::WriteHalf<tun_device::AsyncDevice>>>,
+        tun_writer: SharedPacketWriter,
         event_tx: tokio::sync::mpsc::Sender<P2PEvent>,
         my_id: String,
+        message_tx: tokio::sync::mpsc::Sender<SignalMessage>,
+        stream_tx: tokio::sync::mpsc::Sender<IncomingStream>,
+        gateway: Option<Arc<GatewayRouter>>,
+        key: NodeKey,
+        pinned_only: bool,
     ) -> Result<Self> {
-        let (endpoint, _cert) = make_server_endpoint(SocketAddr::from(([0, 0, 0, 0], bind_port)))?;
+        let endpoint = make_server_endpoint(SocketAddr::from(([0, 0, 0, 0], bind_port)), &key)?;
         let connections = Arc::new(Mutex::new(HashMap::new()));
+        let pinned = Arc::new(Mutex::new(HashMap::new()));
+        let known = Arc::new(Mutex::new(HashSet::new()));
         
         let endpoint_clone = endpoint.clone();
         let connections_clone = connections.clone();
+        let pinned_clone = pinned.clone();
+        let known_clone = known.clone();
         let etx = event_tx.clone();
         let tw = tun_writer.clone();
+        let mtx = message_tx.clone();
+        let stx = stream_tx.clone();
+        let gw = gateway.clone();
         tokio::spawn(async move {
-            Self::accept_loop(endpoint_clone, connections_clone, tw, etx).await;
+            Self::accept_loop(endpoint_clone, connections_clone, pinned_clone, known_clone, pinned_only, tw, etx, mtx, stx, gw).await;
         });
 
         info!("[P2P] QUIC listening on {}", endpoint.local_addr()?);
@@ -48,8 +112,16 @@ impl P2PManager {
         Ok(Self {
             endpoint,
             connections,
+            key,
+            pinned,
+            known,
+            pinned_only,
             event_tx,
             my_id,
+            tun_writer,
+            message_tx,
+            stream_tx,
+            gateway,
         })
     }
 
@@ -59,6 +131,21 @@ impl P2PManager {
         self.endpoint.local_addr().unwrap().port()
     }
 
+    /// Requires `peer_id` to present `public_key`, whichever side dials.
+    pub async fn pin_peer(&self, peer_id: &str, public_key: Vec<u8>) {
+        self.pinned.lock().await.insert(peer_id.to_string(), public_key);
+    }
+
+    /// Replaces the peers whose traffic is taken besides the pinned ones.
+    pub async fn set_known_peers(&self, ids: HashSet<String>) {
+        *self.known.lock().await = ids;
+    }
+
+    /// The key `peer_id` must present, if one is pinned.
+    pub async fn pinned_key(&self, peer_id: &str) -> Option<Vec<u8>> {
+        self.pinned.lock().await.get(peer_id).cloned()
+    }
+
     pub async fn connect_to(&self, peer_id: String, addr: SocketAddr) -> Result<()> {
         {
             let conns = self.connections.lock().await;
@@ -66,10 +153,33 @@ impl P2PManager {
                 return Ok(());
             }
         }
-        
+        self.dial(peer_id, addr).await
+    }
+
+    /// Direct connection to `peer_id` over `addr`, replacing one over any other path,
+    /// e.g. to move a peer found on the LAN off its public address.
+    pub async fn prefer_path(&self, peer_id: String, addr: SocketAddr) -> Result<()> {
+        let current = self.connections.lock().await.get(&peer_id).cloned();
+        match current {
+            None => self.dial(peer_id, addr).await,
+            Some(conn) if conn.remote_address() == addr => Ok(()),
+            Some(old) => {
+                info!("[P2P] Moving {} from {} to {}", peer_id, old.remote_address(), addr);
+                self.dial(peer_id, addr).await?;
+                old.close(0u32.into(), b"path replaced");
+                Ok(())
+            }
+        }
+    }
+
+    async fn dial(&self, peer_id: String, addr: SocketAddr) -> Result<()> {
         info!("[P2P] Attempting direct QUIC connection to peer {} at {}", peer_id, addr);
         
-        let client_cfg = make_client_config();
+        let pinned = self.pinned.lock().await.get(&peer_id).cloned();
+        if pinned.is_none() && self.pinned_only {
+            return Err(anyhow::anyhow!("No pinned key for {}", peer_id));
+        }
+        let client_cfg = make_peer_client_config(&self.key, pinned)?;
         // Set a shorter timeout for P2P attempts to fail fast and fallback to relay
         let conn_res = self.endpoint.connect_with(client_cfg, addr, "syuink-p2p");
         
@@ -108,6 +218,10 @@ impl P2PManager {
         }
         
         let _ = self.event_tx.send(P2PEvent::Connected(peer_id.clone(), P2PTransport::Udp)).await;
+
+        // Datagrams and TCP streams flow both ways once connected
+        spawn_datagram_reader(conn.clone(), peer_id.clone(), self.tun_writer.clone(), self.message_tx.clone(), self.gateway.clone(), self.pinned.clone(), self.known.clone());
+        spawn_stream_acceptor(conn.clone(), peer_id.clone(), self.stream_tx.clone());
         
         // Monitor disconnection
         let etx = self.event_tx.clone();
@@ -117,8 +231,11 @@ impl P2PManager {
             let _ = conn.closed().await;
             info!("[P2P] Connection to {} closed", pid);
             let mut conns = connections.lock().await;
-            conns.remove(&pid);
-            let _ = etx.send(P2PEvent::Disconnected(pid)).await;
+            // Both sides may have dialed; a newer connection replaced this one
+            if conns.get(&pid).is_some_and(|c| c.stable_id() == conn.stable_id()) {
+                conns.remove(&pid);
+                let _ = etx.send(P2PEvent::Disconnected(pid)).await;
+            }
         });
 
         Ok(())
@@ -131,17 +248,75 @@ impl P2PManager {
         conns.get(peer_id).cloned()
     }
 
+    /// Sends a signaling message straight to a peer as a QUIC datagram.
+    /// Delivery is unreliable; returns false if there is no direct connection or the
+    /// message doesn't fit, so the caller can fall back to the signaling relay.
+    pub async fn send_message(&self, peer_id: &str, msg: &SignalMessage) -> bool {
+        let conn = match self.get_connection(peer_id).await {
+            Some(c) => c,
+            None => return false,
+        };
+        let json = match serde_json::to_vec(msg) {
+            Ok(j) => j,
+            Err(_) => return false,
+        };
+        if conn.max_datagram_size().map_or(true, |max| json.len() + 1 > max) {
+            return false;
+        }
+        let mut frame = Vec::with_capacity(json.len() + 1);
+        frame.push(MSG_TAG);
+        frame.extend_from_slice(&json);
+        conn.send_datagram(frame.into()).is_ok()
+    }
+
+    /// Opens a TCP stream to `host:port` on the peer's side over the direct QUIC
+    /// connection. `None` if there is no direct connection; otherwise the peer's
+    /// SOCKS5 reply code and, on success, the stream.
+    pub async fn open_stream(&self, peer_id: &str, host: &str, port: u16) -> Option<std::result::Result<(SendStream, RecvStream), u8>> {
+        let conn = self.get_connection(peer_id).await?;
+        let result = async {
+            let (mut send, mut recv) = conn.open_bi().await?;
+            let header = serde_json::to_vec(&StreamHeader { host: host.to_string(), port })?;
+            send.write_all(&(header.len() as u16).to_be_bytes()).await?;
+            send.write_all(&header).await?;
+            let mut code = [0u8; 1];
+            recv.read_exact(&mut code).await?;
+            anyhow::Ok((send, recv, code[0]))
+        }.await;
+        match result {
+            Ok((send, recv, 0x00)) => Some(Ok((send, recv))),
+            Ok((_, _, code)) => Some(Err(code)),
+            Err(e) => {
+                // The connection is likely going away; let the caller use the relay
+                warn!("[P2P] Failed to open TCP stream to {}: {}", peer_id, e);
+                None
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn accept_loop(
         endpoint: Endpoint, 
         connections: Arc<Mutex<HashMap<String, Connection>>>, 
-        tun_writer: Arc<tokio::sync::Mutex<tokio::io::WriteHalf<tun_device::AsyncDevice>>>,
+        pinned: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        known: Arc<Mutex<HashSet<String>>>,
+        pinned_only: bool,
+        tun_writer: SharedPacketWriter,
         event_tx: tokio::sync::mpsc::Sender<P2PEvent>,
+        message_tx: tokio::sync::mpsc::Sender<SignalMessage>,
+        stream_tx: tokio::sync::mpsc::Sender<IncomingStream>,
+        gateway: Option<Arc<GatewayRouter>>,
     ) {
         info!("[P2P] Accept loop started, waiting for incoming UDP/QUIC connections...");
         while let Some(conn) = endpoint.accept().await {
             let tun_writer = tun_writer.clone();
             let connections = connections.clone();
+            let pinned = pinned.clone();
+            let known = known.clone();
             let event_tx = event_tx.clone();
+            let message_tx = message_tx.clone();
+            let stream_tx = stream_tx.clone();
+            let gateway = gateway.clone();
 
             tokio::spawn(async move {
                 let remote_addr = conn.remote_address();
@@ -166,6 +341,27 @@ impl P2PManager {
                             }
                         };
 
+                        // A static peer must present the key it is configured with
+                        let expected = pinned.lock().await.get(&peer_id).cloned();
+                        match expected {
+                            Some(expected) => {
+                                let presented = connection.peer_identity()
+                                    .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+                                    .and_then(|certs| certs.first().and_then(|c| cert_public_key(&c.0)));
+                                if presented.as_deref() != Some(expected.as_slice()) {
+                                    warn!("[P2P] Rejecting {} from {}: key does not match its static config", peer_id, remote_addr);
+                                    connection.close(0u32.into(), b"bad key");
+                                    return;
+                                }
+                            }
+                            None if pinned_only => {
+                                warn!("[P2P] Rejecting {} from {}: not a configured peer", peer_id, remote_addr);
+                                connection.close(0u32.into(), b"unknown peer");
+                                return;
+                            }
+                            None => {}
+                        }
+
                         info!("[P2P] Handshake successful from peer: {}", peer_id);
                         {
                             let mut conns = connections.lock().await;
@@ -182,32 +378,27 @@ impl P2PManager {
                             let _ = conn_clone.closed().await;
                             info!("[P2P] Connection from {} closed", pid);
                             let mut conns = conns_clone.lock().await;
-                            conns.remove(&pid);
-                            let _ = etx.send(P2PEvent::Disconnected(pid)).await;
+                            if conns.get(&pid).is_some_and(|c| c.stable_id() == conn_clone.stable_id()) {
+                                conns.remove(&pid);
+                                let _ = etx.send(P2PEvent::Disconnected(pid)).await;
+                            }
                         });
 
                         // Handle Datagrams (Fast path for IP packets)
-                        let tw_dg = tun_writer.clone();
-                        let conn_dg = connection.clone();
-                        tokio::spawn(async move {
-                            loop {
-                                match conn_dg.read_datagram().await {
-                                    Ok(dg) => {
-                                        let mut writer = tw_dg.lock().await;
-                                        let _ = writer.write_all(&dg).await;
-                                    }
-                                    Err(_) => break,
-                                }
-                            }
-                        });
+                        spawn_datagram_reader(connection.clone(), peer_id.clone(), tun_writer.clone(), message_tx.clone(), gateway, pinned.clone(), known.clone());
+                        spawn_stream_acceptor(connection.clone(), peer_id.clone(), stream_tx);
 
                         // Handle Unidirectional Streams (Fallback/Large packets)
                         loop {
                             match connection.accept_uni().await {
                                 Ok(mut recv) => {
                                     let tun_writer = tun_writer.clone();
+                                    let (pinned, known, peer_id) = (pinned.clone(), known.clone(), peer_id.clone());
                                     tokio::spawn(async move {
                                         if let Ok(buf) = recv.read_to_end(65535).await {
+                                            if !is_known(&pinned, &known, &peer_id).await {
+                                                return;
+                                            }
                                             let mut writer = tun_writer.lock().await;
                                             let _ = writer.write_all(&buf).await;
                                         }
@@ -230,13 +421,105 @@ impl P2PManager {
 
 
 
-fn make_client_config() -> quinn::ClientConfig {
-    let mut crypto = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
-        .with_no_client_auth();
-    
-    crypto.alpn_protocols = vec![b"syuink-p2p".to_vec()];
+// A pinned peer, or one in the mesh's peer map
+async fn is_known(pinned: &Mutex<HashMap<String, Vec<u8>>>, known: &Mutex<HashSet<String>>, peer_id: &str) -> bool {
+    known.lock().await.contains(peer_id) || pinned.lock().await.contains_key(peer_id)
+}
+
+fn spawn_datagram_reader(
+    conn: Connection,
+    peer_id: String,
+    tun_writer: SharedPacketWriter,
+    message_tx: tokio::sync::mpsc::Sender<SignalMessage>,
+    gateway: Option<Arc<GatewayRouter>>,
+    pinned: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    known: Arc<Mutex<HashSet<String>>>,
+) {
+    tokio::spawn(async move {
+        loop {
+            match conn.read_datagram().await {
+                Ok(dg) => {
+                    // Messages and packets only from peers that are part of the mesh
+                    if !is_known(&pinned, &known, &peer_id).await {
+                        debug!("[P2P] Dropping datagram from unknown peer {}", peer_id);
+                        continue;
+                    }
+                    if dg.first() == Some(&MSG_TAG) {
+                        match serde_json::from_slice::<SignalMessage>(&dg[1..]) {
+                            // Only peer-to-peer kinds, and only in the name of the peer on the other end
+                            Ok(msg) if msg.peer_source() == Some(peer_id.as_str()) => {
+                                let _ = message_tx.send(msg).await;
+                            }
+                            Ok(_) => warn!("[P2P] Dropping message datagram from {} not sent in its own name", peer_id),
+                            Err(e) => warn!("[P2P] Dropping malformed message datagram: {}", e),
+                        }
+                        continue;
+                    }
+                    // Packets for a LAN behind us go through the gateway NAT, not our own TUN.
+                    if let Some(gw) = &gateway {
+                        if is_lan_bound(&dg) {
+                            let _ = gw.handle_packet(&dg).await;
+                            continue;
+                        }
+                    }
+                    let mut writer = tun_writer.lock().await;
+                    let _ = writer.write_all(&dg).await;
+                }
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+fn spawn_stream_acceptor(
+    conn: Connection,
+    peer_id: String,
+    stream_tx: tokio::sync::mpsc::Sender<IncomingStream>,
+) {
+    tokio::spawn(async move {
+        while let Ok((send, mut recv)) = conn.accept_bi().await {
+            let peer_id = peer_id.clone();
+            let stream_tx = stream_tx.clone();
+            // Read each header in its own task so a slow stream can't hold up the rest
+            tokio::spawn(async move {
+                let mut len = [0u8; 2];
+                recv.read_exact(&mut len).await.ok()?;
+                let mut header = vec![0u8; u16::from_be_bytes(len) as usize];
+                recv.read_exact(&mut header).await.ok()?;
+                let header: StreamHeader = match serde_json::from_slice(&header) {
+                    Ok(h) => h,
+                    Err(e) => {
+                        warn!("[P2P] Dropping stream with bad header from {}: {}", peer_id, e);
+                        return None;
+                    }
+                };
+                stream_tx.send(IncomingStream {
+                    peer_id,
+                    host: header.host,
+                    port: header.port,
+                    send,
+                    recv,
+                }).await.ok()
+            });
+        }
+    });
+}
+
+// Direct peers: present our key, and check theirs when it is pinned
+fn make_peer_client_config(key: &NodeKey, pinned: Option<Vec<u8>>) -> Result<quinn::ClientConfig> {
+    let (cert_der, key_der) = key.certificate()?;
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let builder = match pinned {
+        Some(expected) => builder.with_custom_certificate_verifier(Arc::new(PinnedServerVerification(expected))),
+        None => builder.with_custom_certificate_verifier(Arc::new(SkipServerVerification)),
+    };
+    let crypto = builder.with_client_auth_cert(vec![rustls::Certificate(cert_der)], rustls::PrivateKey(key_der))?;
+    Ok(finish_client_config(crypto, b"syuink-p2p"))
+}
+
+/// QUIC client config around `crypto`, for our self-signed endpoints (direct peers, relays).
+pub(crate) fn finish_client_config(mut crypto: rustls::ClientConfig, alpn: &[u8]) -> quinn::ClientConfig {
+    crypto.alpn_protocols = vec![alpn.to_vec()];
     let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
     
     let mut transport_config = quinn::TransportConfig::default();
@@ -247,14 +530,15 @@ fn make_client_config() -> quinn::ClientConfig {
 }
 
 
-fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, Vec<u8>)> {
-    let (cert_der, key_der) = generate_self_signed_cert()?;
-    let cert = rustls::Certificate(cert_der.clone());
+fn make_server_endpoint(bind_addr: SocketAddr, node_key: &NodeKey) -> Result<Endpoint> {
+    let (cert_der, key_der) = node_key.certificate()?;
+    let cert = rustls::Certificate(cert_der);
     let key = rustls::PrivateKey(key_der);
     
+    // Client certificates are optional; the accept loop checks them for static peers
     let mut crypto = rustls::ServerConfig::builder()
         .with_safe_defaults()
-        .with_no_client_auth()
+        .with_client_cert_verifier(Arc::new(AnyClientCert))
         .with_single_cert(vec![cert], key)?;
     crypto.alpn_protocols = vec![b"syuink-p2p".to_vec()];
     
@@ -270,13 +554,7 @@ fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, Vec<u8>)> {
     server_config.transport_config(Arc::new(transport_config));
     
     let endpoint = Endpoint::server(server_config, bind_addr)?;
-    Ok((endpoint, cert_der))
-}
-
-
-fn generate_self_signed_cert() -> Result<(Vec<u8>, Vec<u8>)> {
-    let cert = rcgen::generate_simple_self_signed(vec!["syuink-p2p".into()])?;
-    Ok((cert.serialize_der()?, cert.serialize_private_key_der()))
+    Ok(endpoint)
 }
 
 struct SkipServerVerification;
@@ -294,3 +572,51 @@ impl rustls::client::ServerCertVerifier for SkipServerVerification {
         Ok(rustls::client::ServerCertVerified::assertion())
     }
 }
+
+// Accepts only a certificate for one pinned public key
+struct PinnedServerVerification(Vec<u8>);
+
+impl rustls::client::ServerCertVerifier for PinnedServerVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        if cert_public_key(&end_entity.0).as_deref() == Some(self.0.as_slice()) {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("peer key does not match its static config".into()))
+        }
+    }
+}
+
+// Takes any client certificate, or none; what it must be depends on the peer ID
+// claimed after the handshake
+struct AnyClientCert;
+
+impl rustls::server::ClientCertVerifier for AnyClientCert {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[rustls::DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::server::ClientCertVerified, rustls::Error> {
+        Ok(rustls::server::ClientCertVerified::assertion())
+    }
+}
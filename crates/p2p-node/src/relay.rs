@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow, bail};
+use quinn::{Connection, Endpoint, SendStream};
+use sha2::{Digest, Sha256};
+use shared_proto::relay::{read_frame, write_frame, Frame, HelloStatus, RelayHello, ALPN, KIND_MESSAGE, KIND_PACKET, KIND_UNREACHABLE};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use crate::gateway::{is_lan_bound, GatewayRouter};
+use crate::signaling::SignalMessage;
+use crate::SharedPacketWriter;
+
+/// Port assumed when a relay URL doesn't name one.
+pub const DEFAULT_RELAY_PORT: u16 = 8788;
+
+// A peer the relay reported missing is sent around it for this long.
+const UNREACHABLE_HOLD: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Connection to a packet relay (`quic://host:port`), the fallback for peers we
+/// have no direct path to. Packets travel as binary QUIC datagrams addressed by
+/// node id, so unlike the signaling relay there is no JSON/base64 and no shared
+/// WebSocket to queue behind. Relays and the token for them come from the
+/// signaling server; until then, and whenever the relay is down, every send
+/// returns false and the caller uses the signaling relay.
+pub struct RelayClient {
+    group: String,
+    my_id: String,
+    inbound: Inbound,
+    state: Arc<Mutex<State>>,
+    // The relay map the task is serving
+    task: std::sync::Mutex<Option<(RelayMap, JoinHandle<()>)>>,
+}
+
+/// Relays to use, in order of preference, as handed out by the signaling server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayMap {
+    pub urls: Vec<String>,
+    /// What the relays accept from us.
+    pub token: String,
+    /// SHA-256 of the relays' certificate, hex; anything else presenting itself at
+    /// those addresses is refused.
+    pub cert_sha256: String,
+}
+
+#[derive(Default)]
+struct State {
+    conn: Option<Connection>,
+    // Lazily opened for frames too large for a datagram
+    overflow: Option<SendStream>,
+    unreachable: HashMap<String, Instant>,
+}
+
+// Where frames from the relay go
+#[derive(Clone)]
+struct Inbound {
+    tun_writer: SharedPacketWriter,
+    message_tx: mpsc::Sender<SignalMessage>,
+    gateway: Option<Arc<GatewayRouter>>,
+    packet_info: bool,
+    state: Arc<Mutex<State>>,
+}
+
+impl RelayClient {
+    pub fn new(
+        group: String,
+        my_id: String,
+        tun_writer: SharedPacketWriter,
+        message_tx: mpsc::Sender<SignalMessage>,
+        gateway: Option<Arc<GatewayRouter>>,
+        packet_info: bool,
+    ) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        Self {
+            group,
+            my_id,
+            inbound: Inbound { tun_writer, message_tx, gateway, packet_info, state: state.clone() },
+            state,
+            task: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Uses the first relay that accepts us, moving down the list (and back to the
+    /// top) whenever the connection drops. Replaces any earlier map; the same map
+    /// again, as sent after a signaling reconnect, keeps the current connection.
+    pub fn set_relays(&self, map: RelayMap) {
+        let mut current = self.task.lock().unwrap();
+        if current.as_ref().is_some_and(|(m, _)| *m == map) {
+            return;
+        }
+        let hello = RelayHello { group: self.group.clone(), id: self.my_id.clone(), token: map.token.clone() };
+        let inbound = self.inbound.clone();
+        let relays = map.urls.clone();
+        let cert_sha256 = unhex(&map.cert_sha256);
+        let task = tokio::spawn(async move {
+            if relays.is_empty() {
+                return;
+            }
+            let cert_sha256 = match cert_sha256 {
+                Some(fingerprint) if fingerprint.len() == 32 => fingerprint,
+                _ => {
+                    warn!("[Relay] Relay map carries no certificate fingerprint; not using packet relays");
+                    return;
+                }
+            };
+            loop {
+                for url in &relays {
+                    match connect(url, &hello, &cert_sha256).await {
+                        Ok((endpoint, conn)) => {
+                            info!("[Relay] Connected to packet relay {}", url);
+                            {
+                                let mut state = inbound.state.lock().await;
+                                state.conn = Some(conn.clone());
+                                state.overflow = None;
+                                state.unreachable.clear();
+                            }
+                            serve(conn, inbound.clone()).await;
+                            inbound.state.lock().await.conn = None;
+                            drop(endpoint);
+                            warn!("[Relay] Lost packet relay {}", url);
+                        }
+                        Err(e) => warn!("[Relay] Packet relay {} unavailable: {}", url, e),
+                    }
+                }
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        });
+        if let Some((_, old)) = current.replace((map, task)) {
+            old.abort();
+        }
+    }
+
+    /// Disconnects and stops reconnecting.
+    pub fn close(&self) {
+        if let Some((_, task)) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        if let Ok(mut state) = self.state.try_lock() {
+            if let Some(conn) = state.conn.take() {
+                conn.close(0u32.into(), b"bye");
+            }
+        }
+    }
+
+    /// Sends a raw IP packet to `peer_id` through the relay. False if it couldn't.
+    pub async fn send_packet(&self, peer_id: &str, packet: &[u8]) -> bool {
+        self.send_frame(Frame::new(KIND_PACKET, peer_id, packet)).await
+    }
+
+    /// Sends a signaling message to `peer_id` through the relay. False if it couldn't.
+    pub async fn send_message(&self, peer_id: &str, msg: &SignalMessage) -> bool {
+        match serde_json::to_vec(msg) {
+            Ok(json) => self.send_frame(Frame::new(KIND_MESSAGE, peer_id, &json)).await,
+            Err(_) => false,
+        }
+    }
+
+    async fn send_frame(&self, frame: Frame<'_>) -> bool {
+        let mut state = self.state.lock().await;
+        let conn = match &state.conn {
+            Some(c) => c.clone(),
+            None => return false,
+        };
+        if let Some(since) = state.unreachable.get(frame.peer) {
+            if since.elapsed() < UNREACHABLE_HOLD {
+                return false;
+            }
+            state.unreachable.remove(frame.peer);
+        }
+        let Some(bytes) = frame.encode() else {
+            return false;
+        };
+
+        if conn.max_datagram_size().is_some_and(|max| bytes.len() <= max) {
+            return conn.send_datagram(bytes.into()).is_ok();
+        }
+        if state.overflow.is_none() {
+            match conn.open_uni().await {
+                Ok(s) => state.overflow = Some(s),
+                Err(_) => return false,
+            }
+        }
+        let stream = state.overflow.as_mut().expect("opened above");
+        if write_frame(stream, &bytes).await.is_err() {
+            state.overflow = None;
+            return false;
+        }
+        true
+    }
+}
+
+impl Drop for RelayClient {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Inbound {
+    async fn deliver(&self, frame: Frame<'_>) {
+        match frame.kind {
+            KIND_PACKET => {
+                self.state.lock().await.unreachable.remove(frame.peer);
+                let packet = frame.payload;
+                // Packets for a LAN behind us go through the gateway NAT, not our own TUN.
+                if let Some(gw) = &self.gateway {
+                    if is_lan_bound(packet) {
+                        let _ = gw.handle_packet(packet).await;
+                        return;
+                    }
+                }
+                let mut writer = self.tun_writer.lock().await;
+                let result = if self.packet_info {
+                    let mut pi_packet = Vec::with_capacity(packet.len() + 4);
+                    pi_packet.extend_from_slice(&[0, 0, 0, 2]);
+                    pi_packet.extend_from_slice(packet);
+                    writer.write_all(&pi_packet).await
+                } else {
+                    writer.write_all(packet).await
+                };
+                if let Err(e) = result {
+                    warn!("[Relay] Failed to write packet from {} to TUN: {}", frame.peer, e);
+                }
+            }
+            KIND_MESSAGE => match serde_json::from_slice::<SignalMessage>(frame.payload) {
+                // The relay names the authenticated sender; only peer-to-peer kinds, in its name
+                Ok(msg) if msg.peer_source() == Some(frame.peer) => {
+                    let _ = self.message_tx.send(msg).await;
+                }
+                Ok(_) => warn!("[Relay] Dropping message from {} not sent in its own name", frame.peer),
+                Err(e) => warn!("[Relay] Dropping malformed message from {}: {}", frame.peer, e),
+            },
+            KIND_UNREACHABLE => {
+                debug!("[Relay] Peer {} is not on the relay", frame.peer);
+                self.state.lock().await.unreachable.insert(frame.peer.to_string(), Instant::now());
+            }
+            other => debug!("[Relay] Ignoring frame kind {}", other),
+        }
+    }
+}
+
+async fn connect(url: &str, hello: &RelayHello, cert_sha256: &[u8]) -> Result<(Endpoint, Connection)> {
+    let addr = resolve(url).await?;
+    let mut endpoint = Endpoint::client(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedRelayCert(cert_sha256.to_vec())))
+        .with_no_client_auth();
+    endpoint.set_default_client_config(crate::p2p::finish_client_config(crypto, ALPN));
+
+    let conn = tokio::time::timeout(CONNECT_TIMEOUT, endpoint.connect(addr, "syuink-relay")?)
+        .await
+        .map_err(|_| anyhow!("timed out"))??;
+
+    // Hello, answered with one status byte
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&hello.encode()).await?;
+    send.finish().await?;
+    let mut status = [0u8; 1];
+    recv.read_exact(&mut status).await?;
+    if status[0] != HelloStatus::Ok as u8 {
+        conn.close(0u32.into(), b"unauthorized");
+        bail!("relay refused our token");
+    }
+    Ok((endpoint, conn))
+}
+
+// Reads datagrams and overflow streams until the connection closes
+async fn serve(conn: Connection, inbound: Inbound) {
+    let streams_conn = conn.clone();
+    let streams_inbound = inbound.clone();
+    let streams = tokio::spawn(async move {
+        while let Ok(mut recv) = streams_conn.accept_uni().await {
+            let inbound = streams_inbound.clone();
+            tokio::spawn(async move {
+                while let Ok(bytes) = read_frame(&mut recv).await {
+                    if let Some(frame) = Frame::decode(&bytes) {
+                        inbound.deliver(frame).await;
+                    }
+                }
+            });
+        }
+    });
+
+    while let Ok(dg) = conn.read_datagram().await {
+        if let Some(frame) = Frame::decode(&dg) {
+            inbound.deliver(frame).await;
+        }
+    }
+    streams.abort();
+}
+
+/// "quic://host[:port]" -> socket address
+async fn resolve(url: &str) -> Result<SocketAddr> {
+    let authority = url.strip_prefix("quic://").unwrap_or(url).trim_end_matches('/');
+    let has_port = authority.rsplit_once(':')
+        .is_some_and(|(host, port)| port.parse::<u16>().is_ok() && (!host.contains(':') || host.ends_with(']')));
+    let target = if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:{}", authority, DEFAULT_RELAY_PORT)
+    };
+    let addr = tokio::net::lookup_host(&target).await?
+        .next()
+        .ok_or_else(|| anyhow!("{} did not resolve", target));
+    addr
+}
+
+// The relay's certificate is self-signed; it must be the one the signaling server named
+struct PinnedRelayCert(Vec<u8>);
+
+impl rustls::client::ServerCertVerifier for PinnedRelayCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        if Sha256::digest(&end_entity.0).as_slice() == self.0.as_slice() {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("relay certificate does not match the relay map".into()))
+        }
+    }
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
@@ -6,6 +6,8 @@ pub struct RouteManager {
     added_routes: Vec<Ipv4Addr>,
     local_vpn_ip: String,
     interface_index: Option<u32>, // Useful for Windows if we can get it
+    // No OS interface behind the node (in-memory device): never touch the routing table
+    detached: bool,
 }
 
 impl RouteManager {
@@ -14,10 +16,19 @@ impl RouteManager {
             added_routes: Vec::new(),
             local_vpn_ip,
             interface_index: None,
+            detached: false,
         }
     }
 
+    /// Stop managing OS routes, for nodes running on an in-process packet device.
+    pub fn detach(&mut self) {
+        self.detached = true;
+    }
+
     pub fn update_routes(&mut self, new_targets: &[Ipv4Addr]) {
+        if self.detached {
+            return;
+        }
         // 1. Remove routes that are no longer present
         let to_remove: Vec<Ipv4Addr> = self.added_routes.iter()
             .filter(|ip| !new_targets.contains(ip))
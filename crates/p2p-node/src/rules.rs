@@ -0,0 +1,320 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use ipnetwork::IpNetwork;
+use tokio::sync::Mutex;
+use crate::directory::{domain_matches, peer_matches, MeshDirectory, MeshTarget};
+use crate::PeerInfo;
+
+/// What to do with a proxied connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Connect from this machine, outside the mesh.
+    Direct,
+    /// Exit through the named peer (device name, DNS label or peer ID).
+    Via(String),
+    /// Refuse the connection.
+    Reject,
+}
+
+/// Condition a rule tests against the requested destination.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleMatch {
+    /// Exact hostname.
+    Domain(String),
+    /// Hostname equal to or under the domain.
+    DomainSuffix(String),
+    /// Hostname containing the keyword.
+    DomainKeyword(String),
+    /// IP literal inside the network. Hostnames are not resolved for this.
+    IpCidr(IpNetwork),
+    /// Destination port within the inclusive range.
+    DstPort(u16, u16),
+    /// Destination that the mesh resolves to the named peer.
+    Peer(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub matcher: RuleMatch,
+    pub action: RuleAction,
+}
+
+/// Ordered proxy rules; the first matching rule wins.
+///
+/// Destinations no rule matches go to the mesh peer that owns them when there is
+/// one, and to `default_action` otherwise.
+///
+/// Text format, one rule per line, `#` starts a comment:
+///
+/// ```text
+/// DOMAIN-SUFFIX,corp.example,VIA:office-gw
+/// DOMAIN-KEYWORD,ads,REJECT
+/// IP-CIDR,192.168.50.0/24,VIA:home-nas
+/// DST-PORT,25,REJECT
+/// PEER,build-box,DIRECT
+/// FINAL,DIRECT
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+    pub default_action: RuleAction,
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Self::with_default(RuleAction::Direct)
+    }
+}
+
+impl RuleSet {
+    /// No rules: mesh destinations through their peer, everything else via `default_action`.
+    pub fn with_default(default_action: RuleAction) -> Self {
+        Self {
+            rules: Vec::new(),
+            default_action,
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read rules {}: {}", path.display(), e))?;
+        text.parse()
+    }
+}
+
+impl FromStr for RuleSet {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut set = RuleSet::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let err = |msg: &str| anyhow!("Rule line {}: {}: {}", n + 1, msg, line);
+
+            match fields.as_slice() {
+                [kind, action] if kind.eq_ignore_ascii_case("FINAL") => {
+                    set.default_action = parse_action(action).map_err(|e| err(&e))?;
+                }
+                [kind, value, action] => {
+                    let matcher = parse_match(kind, value).map_err(|e| err(&e))?;
+                    let action = parse_action(action).map_err(|e| err(&e))?;
+                    set.rules.push(Rule { matcher, action });
+                }
+                _ => return Err(err("expected TYPE,VALUE,ACTION")),
+            }
+        }
+        Ok(set)
+    }
+}
+
+fn parse_match(kind: &str, value: &str) -> std::result::Result<RuleMatch, String> {
+    let value_lower = value.trim_matches('.').to_ascii_lowercase();
+    match kind.to_ascii_uppercase().as_str() {
+        "DOMAIN" => Ok(RuleMatch::Domain(value_lower)),
+        "DOMAIN-SUFFIX" => Ok(RuleMatch::DomainSuffix(value_lower)),
+        "DOMAIN-KEYWORD" => Ok(RuleMatch::DomainKeyword(value_lower)),
+        "IP-CIDR" | "IP-CIDR6" => value.parse::<IpNetwork>()
+            .map(RuleMatch::IpCidr)
+            .map_err(|e| format!("bad network ({})", e)),
+        "DST-PORT" => {
+            let (lo, hi) = value.split_once('-').unwrap_or((value, value));
+            match (lo.trim().parse::<u16>(), hi.trim().parse::<u16>()) {
+                (Ok(lo), Ok(hi)) if lo <= hi => Ok(RuleMatch::DstPort(lo, hi)),
+                _ => Err("bad port or port range".to_string()),
+            }
+        }
+        "PEER" => Ok(RuleMatch::Peer(value.to_string())),
+        other => Err(format!("unknown rule type {}", other)),
+    }
+}
+
+fn parse_action(action: &str) -> std::result::Result<RuleAction, String> {
+    match action.to_ascii_uppercase().as_str() {
+        "DIRECT" => Ok(RuleAction::Direct),
+        "REJECT" => Ok(RuleAction::Reject),
+        upper if upper.starts_with("VIA:") => {
+            let peer = action[4..].trim();
+            if peer.is_empty() {
+                return Err("VIA needs a peer name".to_string());
+            }
+            Ok(RuleAction::Via(peer.to_string()))
+        }
+        _ => Err(format!("unknown action {}", action)),
+    }
+}
+
+/// Where a proxy should send one connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProxyRoute {
+    /// Connect locally.
+    Direct,
+    /// Tunnel to a mesh peer, which connects to `host`.
+    Mesh(MeshTarget),
+    /// Refused by a rule.
+    Reject,
+    /// A rule named a peer that is not online.
+    Unreachable,
+}
+
+/// Evaluates the current rule set against the mesh directory for the local proxies.
+/// Cloning shares the rules, so `set_rules` takes effect for connections opened after it.
+#[derive(Clone, Default)]
+pub struct RuleEngine {
+    rules: Arc<Mutex<RuleSet>>,
+    directory: MeshDirectory,
+}
+
+impl RuleEngine {
+    pub fn new(rules: RuleSet, directory: MeshDirectory) -> Self {
+        Self {
+            rules: Arc::new(Mutex::new(rules)),
+            directory,
+        }
+    }
+
+    pub async fn set_rules(&self, rules: RuleSet) {
+        *self.rules.lock().await = rules;
+    }
+
+    pub async fn route(&self, host: &str, port: u16) -> ProxyRoute {
+        let mesh = self.directory.resolve(host).await;
+        let peers = self.directory.peers().await;
+        let rules = self.rules.lock().await;
+
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let ip = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok();
+        let owner = mesh.as_ref().and_then(|t| peers.iter().find(|p| p.id == t.peer_id));
+
+        let action = rules.rules.iter()
+            .find(|r| rule_matches(&r.matcher, &host, ip, port, owner))
+            .map(|r| &r.action);
+
+        match (action, mesh) {
+            (Some(RuleAction::Direct), _) => ProxyRoute::Direct,
+            (Some(RuleAction::Reject), _) => ProxyRoute::Reject,
+            (Some(RuleAction::Via(name)), mesh) => via(&peers, name, &host, mesh),
+            (None, Some(target)) => ProxyRoute::Mesh(target),
+            (None, None) => match &rules.default_action {
+                RuleAction::Direct => ProxyRoute::Direct,
+                RuleAction::Reject => ProxyRoute::Reject,
+                RuleAction::Via(name) => via(&peers, name, &host, None),
+            },
+        }
+    }
+}
+
+fn rule_matches(matcher: &RuleMatch, host: &str, ip: Option<IpAddr>, port: u16, owner: Option<&PeerInfo>) -> bool {
+    match matcher {
+        RuleMatch::Domain(d) => ip.is_none() && host == d,
+        RuleMatch::DomainSuffix(d) => ip.is_none() && domain_matches(host, d),
+        RuleMatch::DomainKeyword(k) => ip.is_none() && host.contains(k.as_str()),
+        RuleMatch::IpCidr(net) => ip.map(|ip| net.contains(ip)).unwrap_or(false),
+        RuleMatch::DstPort(lo, hi) => (*lo..=*hi).contains(&port),
+        RuleMatch::Peer(name) => owner.map(|p| peer_matches(p, name)).unwrap_or(false),
+    }
+}
+
+// Exit through `name`; keep the mesh rewrite of the host if that peer owns it anyway.
+fn via(peers: &[PeerInfo], name: &str, host: &str, mesh: Option<MeshTarget>) -> ProxyRoute {
+    let peer = match peers.iter().find(|p| peer_matches(p, name)) {
+        Some(p) => p,
+        None => return ProxyRoute::Unreachable,
+    };
+    let host = match mesh {
+        Some(target) if target.peer_id == peer.id => target.host,
+        _ => host.to_string(),
+    };
+    ProxyRoute::Mesh(MeshTarget { peer_id: peer.id.clone(), host })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str, name: &str, ip: &str) -> PeerInfo {
+        PeerInfo {
+            id: id.to_string(),
+            ip: ip.to_string(),
+            public_addr: None,
+            p2p_port: 0,
+            name: name.to_string(),
+            os: None,
+            version: None,
+            device_type: None,
+            is_gateway: false,
+            connected_at: None,
+            route_status: String::new(),
+            domains: Vec::new(),
+        }
+    }
+
+    async fn engine(rules: &str) -> RuleEngine {
+        let directory = MeshDirectory::new();
+        directory.set_peers(vec![
+            peer("id-gw", "office-gw", "10.10.0.2"),
+            peer("id-nas", "home-nas", "10.10.0.3"),
+        ]).await;
+        RuleEngine::new(rules.parse().unwrap(), directory)
+    }
+
+    fn via(peer_id: &str, host: &str) -> ProxyRoute {
+        ProxyRoute::Mesh(MeshTarget { peer_id: peer_id.to_string(), host: host.to_string() })
+    }
+
+    #[tokio::test]
+    async fn domain_suffix_matches_the_domain_and_below() {
+        let rules = engine("DOMAIN-SUFFIX,corp.example,VIA:office-gw").await;
+        assert_eq!(rules.route("corp.example", 443).await, via("id-gw", "corp.example"));
+        assert_eq!(rules.route("wiki.corp.example.", 443).await, via("id-gw", "wiki.corp.example"));
+        assert_eq!(rules.route("notcorp.example", 443).await, ProxyRoute::Direct);
+    }
+
+    #[tokio::test]
+    async fn ip_cidr_matches_literals_only() {
+        let rules = engine("IP-CIDR,192.168.50.0/24,VIA:home-nas\nIP-CIDR6,2001:db8::/32,REJECT").await;
+        assert_eq!(rules.route("192.168.50.7", 80).await, via("id-nas", "192.168.50.7"));
+        assert_eq!(rules.route("[2001:db8::1]", 80).await, ProxyRoute::Reject);
+        assert_eq!(rules.route("192.168.51.7", 80).await, ProxyRoute::Direct);
+    }
+
+    #[tokio::test]
+    async fn peer_rule_matches_destinations_the_peer_owns() {
+        let rules = engine("PEER,home-nas,DIRECT").await;
+        assert_eq!(rules.route("10.10.0.3", 22).await, ProxyRoute::Direct);
+        // Unmatched mesh destinations still go to their owner
+        assert_eq!(rules.route("10.10.0.2", 22).await, via("id-gw", "10.10.0.2"));
+    }
+
+    #[tokio::test]
+    async fn reject_and_first_match_wins() {
+        let rules = engine("DOMAIN-KEYWORD,ads,REJECT\nDST-PORT,25,REJECT\nDOMAIN,ads.example,DIRECT").await;
+        assert_eq!(rules.route("ads.example", 443).await, ProxyRoute::Reject);
+        assert_eq!(rules.route("mail.example", 25).await, ProxyRoute::Reject);
+        assert_eq!(rules.route("mail.example", 587).await, ProxyRoute::Direct);
+    }
+
+    #[tokio::test]
+    async fn final_sets_the_default_action() {
+        let rules = engine("DOMAIN,direct.example,DIRECT\nFINAL,VIA:office-gw").await;
+        assert_eq!(rules.route("direct.example", 80).await, ProxyRoute::Direct);
+        assert_eq!(rules.route("elsewhere.example", 80).await, via("id-gw", "elsewhere.example"));
+        assert_eq!(engine("FINAL,REJECT").await.route("elsewhere.example", 80).await, ProxyRoute::Reject);
+        assert_eq!(engine("FINAL,VIA:gone").await.route("elsewhere.example", 80).await, ProxyRoute::Unreachable);
+    }
+
+    #[test]
+    fn bad_lines_are_rejected() {
+        assert!("DST-PORT,90-80,DIRECT".parse::<RuleSet>().is_err());
+        assert!("IP-CIDR,not-a-net,DIRECT".parse::<RuleSet>().is_err());
+        assert!("DOMAIN,example.com,VIA:".parse::<RuleSet>().is_err());
+        assert!("DOMAIN,example.com".parse::<RuleSet>().is_err());
+    }
+}
@@ -1,9 +1,12 @@
-use anyhow::Result;
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow};
 use futures::{SinkExt, StreamExt};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use shared_proto::data_frame::{DataFrame, KIND_BROADCAST, KIND_TCP_DATA, KIND_TUN_PACKET};
 use tokio::sync::mpsc;
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 use url::Url;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -34,6 +37,30 @@ pub enum SignalMessage {
         device_type: Option<String>,
         #[serde(default)]
         is_gateway: bool,
+        // LAN DNS domains a gateway can resolve, e.g. "home.arpa"
+        #[serde(default)]
+        domains: Vec<String>,
+        // We can take data-plane messages as binary frames (see `data_frame`)
+        #[serde(default)]
+        binary_frames: bool,
+        // Base64 key of our QUIC certificate, so peers can pin it
+        #[serde(default)]
+        public_key: Option<String>,
+    },
+    // Server -> node after join: what the server supports for this session
+    #[serde(rename = "features")]
+    Features {
+        #[serde(default)]
+        binary_frames: bool,
+        // Echoes `keep_alive`; otherwise we ping with WebSocket ping frames
+        #[serde(default)]
+        keep_alive: bool,
+    },
+    // Heartbeat: sent by a node and echoed back unchanged by the server.
+    // `ts` is the sender's clock in ms, so the echo gives the round trip.
+    #[serde(rename = "keep_alive")]
+    KeepAlive {
+        ts: u64,
     },
     #[serde(rename = "register_services")]
     RegisterServices {
@@ -64,6 +91,20 @@ pub enum SignalMessage {
         is_gateway: bool,
         #[serde(default)]
         connected_at: Option<u64>,
+        #[serde(default)]
+        domains: Vec<String>,
+        #[serde(default)]
+        public_key: Option<String>,
+    },
+    // Server -> node after join: packet relays to use, in order of preference,
+    // and the token they accept from this node
+    #[serde(rename = "relay_map")]
+    RelayMap {
+        relays: Vec<String>,
+        token: String,
+        // hex SHA-256 of the relays' certificate
+        #[serde(default)]
+        cert_sha256: String,
     },
     #[serde(rename = "peer_left")]
     PeerLeft {
@@ -91,13 +132,15 @@ pub enum SignalMessage {
     #[serde(rename = "broadcast")]
     Broadcast {
         source: String,
-        data: String, // Base64 encoded packet
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
     },
     #[serde(rename = "tun_packet")]
     TunPacket {
         target: String,
         source: String,
-        data: String,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
     },
     #[serde(rename = "tcp_connect")]
     TcpConnect {
@@ -113,13 +156,20 @@ pub enum SignalMessage {
         target: String,
         source: String,
         success: bool,
+        // SOCKS5 reply code for the outcome (RFC 1928), 0 on success
+        #[serde(default)]
+        reply: u8,
+        // Local address of the target-side socket
+        #[serde(default)]
+        bind_addr: Option<String>,
     },
     #[serde(rename = "tcp_data")]
     TcpData {
         stream_id: u32,
         target: String,
         source: String,
-        data: String,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
     },
     #[serde(rename = "tcp_close")]
     TcpClose {
@@ -127,16 +177,142 @@ pub enum SignalMessage {
         target: String,
         source: String,
     },
+    // SOCKS5 UDP ASSOCIATE: datagram for the owning peer to emit toward dst
+    #[serde(rename = "udp_data")]
+    UdpData {
+        assoc_id: u32,
+        target: String,
+        source: String,
+        dst_ip: String,
+        dst_port: u16,
+        data: String,
+    },
+    // ...and a datagram that came back to it from src
+    #[serde(rename = "udp_reply")]
+    UdpReply {
+        assoc_id: u32,
+        target: String,
+        source: String,
+        src_ip: String,
+        src_port: u16,
+        data: String,
+    },
+    // Split DNS: a query under one of the target gateway's LAN domains
+    #[serde(rename = "dns_query")]
+    DnsQuery {
+        query_id: u32,
+        target: String,
+        source: String,
+        data: String, // Base64 DNS message
+    },
+    // ...and the gateway's response to it
+    #[serde(rename = "dns_answer")]
+    DnsAnswer {
+        query_id: u32,
+        target: String,
+        source: String,
+        data: String,
+    },
+    // Never on the wire: the client passes this on right before each (re)join, so
+    // everything after it on the same channel is the server's replay of the group
+    #[serde(skip)]
+    Resync,
+}
+
+impl SignalMessage {
+    /// The sender of a message peers exchange among themselves, which may also arrive
+    /// over a direct connection or the packet relay. `None` for messages only the
+    /// signaling server may send.
+    pub fn peer_source(&self) -> Option<&str> {
+        match self {
+            SignalMessage::Broadcast { source, .. }
+            | SignalMessage::TunPacket { source, .. }
+            | SignalMessage::TcpConnect { source, .. }
+            | SignalMessage::TcpConnected { source, .. }
+            | SignalMessage::TcpData { source, .. }
+            | SignalMessage::TcpClose { source, .. }
+            | SignalMessage::UdpData { source, .. }
+            | SignalMessage::UdpReply { source, .. }
+            | SignalMessage::DnsQuery { source, .. }
+            | SignalMessage::DnsAnswer { source, .. } => Some(source),
+            _ => None,
+        }
+    }
 }
 
+// Packet and stream payloads: raw bytes in memory and in binary frames, base64
+// only in JSON messages
+mod base64_bytes {
+    use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        BASE64.decode(text).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Signaling connection state, surfaced to the UI and CLI as it changes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connected { url: String },
+    Disconnected { reason: String },
+    Reconnecting { attempt: u32, delay_ms: u64 },
+}
+
+/// What the signaling task reports besides messages.
+#[derive(Debug, Clone)]
+pub enum SignalingEvent {
+    State(ConnectionState),
+    /// Round trip of a heartbeat to the signaling server.
+    Rtt(Duration),
+}
+
+const RECONNECT_BASE: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// While on a fallback server, how often to check whether a preferred one is back
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+// Nothing heard for this long means the connection is dead, even if TCP hasn't noticed
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(45);
+
 #[derive(Clone)]
 pub struct SignalingClient {
     tx: mpsc::Sender<SignalMessage>,
 }
 
+// One signaling endpoint
+#[derive(Clone)]
+struct Server {
+    // As configured, for logs and events (the URL carries the token)
+    name: String,
+    url: Url,
+}
+
+// How a connection ended
+enum SessionEnd {
+    // The server or network went away; reconnect
+    Lost(String),
+    // A server earlier in the list is reachable again; move to it
+    Failback(usize, WsStream),
+    // Our side dropped the client; stop
+    Dropped,
+}
+
 impl SignalingClient {
+    /// Connects to the first reachable of `server_urls`, in order, and keeps the
+    /// connection up: when it drops, reconnects (again trying the list in order) with
+    /// jittered exponential backoff and re-sends `Join` and the last `RegisterServices`.
+    /// While on a fallback, the servers before it are probed and used once they are
+    /// back. Only the first connection attempt is reported as an error.
     pub async fn connect(
-        server_url: &str,
+        server_urls: &[String],
         group_id: &str,
         token: Option<String>,
         my_id: String,
@@ -144,27 +320,29 @@ impl SignalingClient {
         my_name: String,
         p2p_port: u16,
         my_meta: (Option<String>, Option<String>, Option<String>, bool), // os, ver, type, gateway
+        domains: Vec<String>,
+        public_key: String,
         incoming_tx: mpsc::Sender<SignalMessage>,
+        events_tx: mpsc::UnboundedSender<SignalingEvent>,
     ) -> Result<Self> {
-        // Construct base URL: {server_url}/wapi/{group_id}
-        // Ensure server_url doesn't end with slash to avoid double slash (though parser handles it)
-        let base_str = format!("{}/wapi/{}", server_url.trim_end_matches('/'), group_id);
-        let mut url = Url::parse(&base_str)?;
-
-        // If token provided, add to query params
-        if let Some(t) = token {
-            url.query_pairs_mut().append_pair("token", &t);
-        }
+        let mut servers = Vec::with_capacity(server_urls.len());
+        for server_url in server_urls {
+            // Construct base URL: {server_url}/wapi/{group_id}
+            // Ensure server_url doesn't end with slash to avoid double slash (though parser handles it)
+            let base_str = format!("{}/wapi/{}", server_url.trim_end_matches('/'), group_id);
+            let mut url = Url::parse(&base_str)?;
 
-        info!("Connecting to signaling server: {}", url);
+            // If token provided, add to query params
+            if let Some(t) = &token {
+                url.query_pairs_mut().append_pair("token", t);
+            }
+            servers.push(Server { name: server_url.clone(), url });
+        }
 
-        let (ws_stream, _) = connect_async(url).await?;
-        info!("WebSocket connected");
+        let (index, ws_stream) = connect_first(&servers).await?;
 
-        let (mut write, mut read) = ws_stream.split();
-        let (tx, mut rx) = mpsc::channel::<SignalMessage>(32);
+        let (tx, rx) = mpsc::channel::<SignalMessage>(32);
 
-        // Send JOIN immediately
         let join_msg = SignalMessage::Join {
             id: my_id.clone(),
             ip: my_ip,
@@ -174,71 +352,333 @@ impl SignalingClient {
             version: my_meta.1,
             device_type: my_meta.2,
             is_gateway: my_meta.3,
+            domains,
+            binary_frames: true,
+            public_key: Some(public_key),
         };
-        let json = serde_json::to_string(&join_msg)?;
-        write.send(Message::Text(json)).await?;
-
-        // Background task to handle WS I/O
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    // Outbound messages (Local -> Server)
-                    msg = rx.recv() => {
-                        match msg {
-                            Some(msg) => {
-                                if let Ok(json) = serde_json::to_string(&msg) {
-                                    if let Err(e) = write.send(Message::Text(json)).await {
-                                        error!("Failed to send WS message: {}", e);
-                                        break;
-                                    }
+
+        // Background task to handle WS I/O, across reconnects
+        tokio::spawn(run(servers, join_msg, (index, ws_stream), rx, incoming_tx, events_tx));
+
+        Ok(Self { tx })
+    }
+
+    pub async fn send(&self, msg: SignalMessage) -> Result<()> {
+        self.tx.send(msg).await?;
+        Ok(())
+    }
+}
+
+type WsStream = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn run(
+    servers: Vec<Server>,
+    join: SignalMessage,
+    first: (usize, WsStream),
+    mut rx: mpsc::Receiver<SignalMessage>,
+    incoming_tx: mpsc::Sender<SignalMessage>,
+    events_tx: mpsc::UnboundedSender<SignalingEvent>,
+) {
+    // Replayed on every reconnect so the new server session knows our services
+    let mut services: Option<SignalMessage> = None;
+    let mut ws = Some(first);
+
+    loop {
+        // 1. Connect, backing off while no server is reachable
+        let (index, stream) = match ws.take() {
+            Some(connected) => connected,
+            None => {
+                let mut attempt = 0u32;
+                loop {
+                    attempt += 1;
+                    let delay = backoff(attempt);
+                    let _ = events_tx.send(SignalingEvent::State(ConnectionState::Reconnecting { attempt, delay_ms: delay.as_millis() as u64 }));
+                    info!("Reconnecting to signaling server in {:?} (attempt {})", delay, attempt);
+                    if drain_while(&mut rx, &mut services, tokio::time::sleep(delay)).await.is_none() {
+                        info!("Signaling client dropped while reconnecting");
+                        return;
+                    }
+                    match drain_while(&mut rx, &mut services, connect_first(&servers)).await {
+                        None => {
+                            info!("Signaling client dropped while reconnecting");
+                            return;
+                        }
+                        Some(Ok(connected)) => break connected,
+                        Some(Err(e)) => warn!("Signaling reconnect failed: {}", e),
+                    }
+                }
+            }
+        };
+        info!("WebSocket connected to {}", servers[index].name);
+        let _ = events_tx.send(SignalingEvent::State(ConnectionState::Connected { url: servers[index].name.clone() }));
+
+        // 2. On a fallback, watch for the servers ahead of it to come back
+        let (probe_tx, mut probe_rx) = mpsc::channel(1);
+        let prober = (index > 0).then(|| tokio::spawn(probe(servers[..index].to_vec(), probe_tx)));
+
+        // 3. Serve until it drops
+        let end = session(stream, &join, &mut services, &mut rx, &mut probe_rx, &incoming_tx, &events_tx).await;
+        if let Some(prober) = prober {
+            prober.abort();
+        }
+        match end {
+            SessionEnd::Lost(reason) => {
+                warn!("Signaling connection lost: {}", reason);
+                let _ = events_tx.send(SignalingEvent::State(ConnectionState::Disconnected { reason }));
+            }
+            SessionEnd::Failback(preferred, stream) => {
+                info!("Signaling server {} is reachable again, moving back to it", servers[preferred].name);
+                ws = Some((preferred, stream));
+            }
+            SessionEnd::Dropped => {
+                let _ = events_tx.send(SignalingEvent::State(ConnectionState::Disconnected { reason: "closed".into() }));
+                break;
+            }
+        }
+    }
+    info!("Signaling loop exited. Dropping WebSocket.");
+}
+
+async fn session(
+    ws_stream: WsStream,
+    join: &SignalMessage,
+    services: &mut Option<SignalMessage>,
+    rx: &mut mpsc::Receiver<SignalMessage>,
+    probe_rx: &mut mpsc::Receiver<(usize, WsStream)>,
+    incoming_tx: &mpsc::Sender<SignalMessage>,
+    events_tx: &mpsc::UnboundedSender<SignalingEvent>,
+) -> SessionEnd {
+    let (mut write, mut read) = ws_stream.split();
+    // Heartbeat timestamps are ms since the session started
+    let epoch = Instant::now();
+    let mut last_heard = Instant::now();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    // Mark the start of the replay in-band, ahead of anything the server sends back
+    if incoming_tx.send(SignalMessage::Resync).await.is_err() {
+        return SessionEnd::Dropped;
+    }
+
+    // Send JOIN immediately, then whatever services we last registered
+    for msg in std::iter::once(join).chain(services.as_ref()) {
+        let Ok(json) = serde_json::to_string(msg) else { continue };
+        if let Err(e) = write.send(Message::Text(json)).await {
+            return SessionEnd::Lost(e.to_string());
+        }
+    }
+
+    // Set once the server confirms binary framing; JSON until then
+    let mut binary = false;
+    // Set once the server says it echoes `keep_alive`
+    let mut keep_alive = false;
+
+    loop {
+        tokio::select! {
+            // Heartbeat, and the liveness check
+            _ = heartbeat.tick() => {
+                if last_heard.elapsed() > LIVENESS_TIMEOUT {
+                    return SessionEnd::Lost(format!("no response for {:?}", last_heard.elapsed()));
+                }
+                let ts = epoch.elapsed().as_millis() as u64;
+                let ping = if keep_alive {
+                    match serde_json::to_string(&SignalMessage::KeepAlive { ts }) {
+                        Ok(json) => Message::Text(json),
+                        Err(_) => continue,
+                    }
+                } else {
+                    Message::Ping(ts.to_be_bytes().to_vec())
+                };
+                // A dead peer can leave the write blocked on a full socket buffer
+                match tokio::time::timeout(LIVENESS_TIMEOUT, write.send(ping)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => return SessionEnd::Lost(e.to_string()),
+                    Err(_) => return SessionEnd::Lost("heartbeat send timed out".into()),
+                }
+            }
+
+            // A preferred server answered the probe
+            Some((preferred, stream)) = probe_rx.recv() => {
+                let _ = write.close().await;
+                return SessionEnd::Failback(preferred, stream);
+            }
+
+            // Outbound messages (Local -> Server)
+            msg = rx.recv() => {
+                match msg {
+                    Some(msg) => {
+                        if matches!(msg, SignalMessage::RegisterServices { .. }) {
+                            *services = Some(msg.clone());
+                        }
+                        if binary {
+                            if let Some(frame) = to_data_frame(&msg) {
+                                if let Err(e) = write.send(Message::Binary(frame)).await {
+                                    error!("Failed to send WS message: {}", e);
+                                    return SessionEnd::Lost(e.to_string());
                                 }
+                                continue;
                             }
-                            None => {
-                                info!("Signaling client dropped, closing WebSocket");
-                                let _ = write.close().await;
-                                break;
+                        }
+                        if let Ok(json) = serde_json::to_string(&msg) {
+                            if let Err(e) = write.send(Message::Text(json)).await {
+                                error!("Failed to send WS message: {}", e);
+                                return SessionEnd::Lost(e.to_string());
                             }
                         }
                     }
-                    
-                    // Inbound messages (Server -> Local)
-                    Some(msg) = read.next() => {
-                        match msg {
-                            Ok(Message::Text(text)) => {
-                                if let Ok(signal) = serde_json::from_str::<SignalMessage>(&text) {
-                                    if let Err(_) = incoming_tx.send(signal).await {
-                                        break; // Receiver dropped
-                                    }
-                                } else {
-                                    warn!("Received unknown message format: {}", text);
-                                }
+                    None => {
+                        info!("Signaling client dropped, closing WebSocket");
+                        let _ = write.close().await;
+                        return SessionEnd::Dropped;
+                    }
+                }
+            }
+
+            // Inbound messages (Server -> Local)
+            msg = read.next() => {
+                if let Some(Ok(_)) = &msg {
+                    last_heard = Instant::now();
+                }
+                match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        if let Ok(signal) = serde_json::from_str::<SignalMessage>(&text) {
+                            if let SignalMessage::Features { binary_frames, keep_alive: echoes } = &signal {
+                                info!("Signaling server binary framing: {}, keep_alive: {}", binary_frames, echoes);
+                                binary = *binary_frames;
+                                keep_alive = *echoes;
                             }
-                            Ok(Message::Close(_)) => {
-                                info!("Signaling connection closed by server");
-                                break;
+                            if let SignalMessage::KeepAlive { ts } = signal {
+                                report_rtt(events_tx, epoch, ts);
+                                continue;
                             }
-                            Err(e) => {
-                                error!("WS read error: {}", e);
-                                break;
+                            if incoming_tx.send(signal).await.is_err() {
+                                return SessionEnd::Dropped; // Receiver dropped
                             }
-                            _ => {}
+                        } else {
+                            warn!("Received unknown message format: {}", text);
                         }
                     }
-                    
-                    else => {
-                        info!("Signaling loop select else branch hit (likely read stream finished)");
-                        break;
+                    Some(Ok(Message::Binary(bytes))) => {
+                        match from_data_frame(&bytes) {
+                            Some(signal) => {
+                                if incoming_tx.send(signal).await.is_err() {
+                                    return SessionEnd::Dropped;
+                                }
+                            }
+                            None => warn!("Received malformed binary frame ({} bytes)", bytes.len()),
+                        }
                     }
+                    Some(Ok(Message::Pong(payload))) => {
+                        if let Ok(ts) = <[u8; 8]>::try_from(payload.as_slice()) {
+                            report_rtt(events_tx, epoch, u64::from_be_bytes(ts));
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) => {
+                        info!("Signaling connection closed by server");
+                        return SessionEnd::Lost("closed by server".into());
+                    }
+                    Some(Err(e)) => {
+                        error!("WS read error: {}", e);
+                        return SessionEnd::Lost(e.to_string());
+                    }
+                    Some(Ok(_)) => {}
+                    None => return SessionEnd::Lost("stream ended".into()),
                 }
             }
-            info!("Signaling loop exited. Dropping WebSocket.");
-        });
+        }
+    }
+}
 
-        Ok(Self { tx })
+// The first of `servers` that accepts a connection, with its index
+async fn connect_first(servers: &[Server]) -> Result<(usize, WsStream)> {
+    let mut last_error = anyhow!("no signaling servers configured");
+    for (index, server) in servers.iter().enumerate() {
+        info!("Connecting to signaling server: {}", server.name);
+        match tokio::time::timeout(CONNECT_TIMEOUT, connect_async(server.url.clone())).await {
+            Ok(Ok((stream, _))) => return Ok((index, stream)),
+            Ok(Err(e)) => {
+                warn!("Signaling server {} unavailable: {}", server.name, e);
+                last_error = e.into();
+            }
+            Err(_) => {
+                warn!("Signaling server {} timed out", server.name);
+                last_error = anyhow!("connection to {} timed out", server.name);
+            }
+        }
     }
+    Err(last_error)
+}
 
-    pub async fn send(&self, msg: SignalMessage) -> Result<()> {
-        self.tx.send(msg).await?;
-        Ok(())
+// Retries `servers` (those preferred over the current one) until one accepts,
+// and hands over the connection
+async fn probe(servers: Vec<Server>, found: mpsc::Sender<(usize, WsStream)>) {
+    loop {
+        tokio::time::sleep(PROBE_INTERVAL).await;
+        if let Ok(connected) = connect_first(&servers).await {
+            let _ = found.send(connected).await;
+            return;
+        }
     }
 }
+
+fn report_rtt(events_tx: &mpsc::UnboundedSender<SignalingEvent>, epoch: Instant, ts: u64) {
+    let now = epoch.elapsed().as_millis() as u64;
+    // Anything from before this session, or not ours, is not a round trip
+    if let Some(rtt) = now.checked_sub(ts).filter(|rtt| *rtt <= LIVENESS_TIMEOUT.as_millis() as u64) {
+        debug!("Signaling RTT: {} ms", rtt);
+        let _ = events_tx.send(SignalingEvent::Rtt(Duration::from_millis(rtt)));
+    }
+}
+
+/// Runs `fut` while consuming outbound messages, so senders don't block while we
+/// are offline. The latest `RegisterServices` is kept for replay; everything else
+/// is dropped. `None` if the client was dropped meanwhile.
+async fn drain_while<F: std::future::Future>(
+    rx: &mut mpsc::Receiver<SignalMessage>,
+    services: &mut Option<SignalMessage>,
+    fut: F,
+) -> Option<F::Output> {
+    tokio::pin!(fut);
+    loop {
+        tokio::select! {
+            out = &mut fut => return Some(out),
+            msg = rx.recv() => match msg {
+                Some(msg @ SignalMessage::RegisterServices { .. }) => *services = Some(msg),
+                Some(_) => {}
+                None => return None,
+            },
+        }
+    }
+}
+
+// 1s, 2s, 4s, ... capped at 30s, each picked uniformly from [d/2, d]
+fn backoff(attempt: u32) -> Duration {
+    let exp = RECONNECT_BASE.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+    let max = exp.min(RECONNECT_MAX).as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(max / 2..=max))
+}
+
+
+/// Binary frame for a data-plane message; `None` for control messages, which stay JSON.
+fn to_data_frame(msg: &SignalMessage) -> Option<Vec<u8>> {
+    let (kind, source, target, stream_id, data) = match msg {
+        SignalMessage::TunPacket { target, source, data } => (KIND_TUN_PACKET, source, target.as_str(), 0, data),
+        SignalMessage::Broadcast { source, data } => (KIND_BROADCAST, source, "", 0, data),
+        SignalMessage::TcpData { stream_id, target, source, data } => (KIND_TCP_DATA, source, target.as_str(), *stream_id, data),
+        _ => return None,
+    };
+    DataFrame { kind, source, target, stream_id, payload: data }.encode()
+}
+
+fn from_data_frame(bytes: &[u8]) -> Option<SignalMessage> {
+    let frame = DataFrame::decode(bytes)?;
+    let source = frame.source.to_string();
+    let target = frame.target.to_string();
+    let data = frame.payload.to_vec();
+    Some(match frame.kind {
+        KIND_TUN_PACKET => SignalMessage::TunPacket { target, source, data },
+        KIND_BROADCAST => SignalMessage::Broadcast { source, data },
+        KIND_TCP_DATA => SignalMessage::TcpData { stream_id: frame.stream_id, target, source, data },
+        _ => return None,
+    })
+}
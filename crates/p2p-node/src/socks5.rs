@@ -1,25 +1,53 @@
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::net::{SocketAddr, Ipv4Addr};
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use std::collections::HashMap;
-use crate::signaling::{SignalMessage, SignalingClient};
+use std::collections::{HashMap, HashSet};
+use crate::signaling::SignalMessage;
+use crate::transport::{PeerLink, TcpTunnels};
+use crate::directory::MeshTarget;
+use crate::rules::{ProxyRoute, RuleEngine};
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use anyhow::{Result, anyhow};
-use tracing::{info, error, debug};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use tracing::{info, error, debug, warn};
 
-pub enum SocksMsg {
-    Connected(bool),
-    Data(Vec<u8>),
-    Closed,
+/// Username/password for the local SOCKS5 listener (RFC 1929).
+/// Generated fresh for every session so only the app that started the node can use it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Socks5Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Socks5Credentials {
+    pub fn generate() -> Self {
+        let password: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect();
+        Self {
+            username: "syuink".to_string(),
+            password,
+        }
+    }
+}
+
+// One UDP ASSOCIATE: the local relay socket and the client address it serves
+struct UdpAssoc {
+    socket: Arc<UdpSocket>,
+    client: Mutex<Option<SocketAddr>>,
 }
 
 pub struct Socks5Server {
     listener: TcpListener,
-    // Map StreamID -> Sender<SocksMsg>
-    streams: Arc<Mutex<HashMap<u32, tokio::sync::mpsc::Sender<SocksMsg>>>>,
-    next_stream_id: Arc<Mutex<u32>>,
+    // Map AssocID -> UDP relay
+    udp_assocs: Arc<Mutex<HashMap<u32, Arc<UdpAssoc>>>>,
+    next_assoc_id: Arc<Mutex<u32>>,
+    credentials: Socks5Credentials,
 }
 
 impl Socks5Server {
@@ -39,50 +67,89 @@ impl Socks5Server {
         
         Ok((Self {
             listener,
-            streams: Arc::new(Mutex::new(HashMap::new())),
-            next_stream_id: Arc::new(Mutex::new(1)),
+            udp_assocs: Arc::new(Mutex::new(HashMap::new())),
+            next_assoc_id: Arc::new(Mutex::new(1)),
+            credentials: Socks5Credentials::generate(),
         }, port))
     }
 
+    pub fn credentials(&self) -> Socks5Credentials {
+        self.credentials.clone()
+    }
+
     pub async fn run(
         self: Arc<Self>, 
-        signal_client: Arc<SignalingClient>, 
-        my_id: String,
-        route_table: Arc<Mutex<HashMap<Ipv4Addr, String>>>
+        tunnels: TcpTunnels, 
+        rules: RuleEngine
     ) {
         loop {
             if let Ok((socket, addr)) = self.listener.accept().await {
                 let server = self.clone();
-                let client = signal_client.clone();
-                let my_id = my_id.clone();
-                let routes = route_table.clone();
+                let tunnels = tunnels.clone();
+                let rules = rules.clone();
                 
                 tokio::spawn(async move {
-                    if let Err(e) = server.handle_client(socket, client, my_id, routes).await {
+                    if let Err(e) = server.handle_client(socket, tunnels, rules).await {
                         debug!("Socks client error {}: {}", addr, e);
                     }
                 });
             }
         }
     }
-    
-    pub async fn on_msg(&self, stream_id: u32, msg: SocksMsg) {
-        let mut streams = self.streams.lock().await;
-        if let Some(tx) = streams.get(&stream_id) {
-            if tx.send(msg).await.is_err() {
-                streams.remove(&stream_id);
-            }
+
+    /// A datagram came back from `src` for one of our UDP associations.
+    pub async fn on_udp_reply(&self, assoc_id: u32, src: SocketAddr, data: &[u8]) {
+        let assoc = match self.udp_assocs.lock().await.get(&assoc_id) {
+            Some(a) => a.clone(),
+            None => return,
+        };
+        let client = match *assoc.client.lock().await {
+            Some(c) => c,
+            None => return,
+        };
+        let mut packet = encode_udp_header(src);
+        packet.extend_from_slice(data);
+        let _ = assoc.socket.send_to(&packet, client).await;
+    }
+
+    async fn next_id(&self) -> u32 {
+        let mut id = self.next_assoc_id.lock().await;
+        *id += 1;
+        *id
+    }
+
+    /// RFC 1929 sub-negotiation: VER(1) ULEN(1) UNAME PLEN(1) PASSWD
+    async fn authenticate(&self, socket: &mut TcpStream) -> Result<()> {
+        let mut ver_ulen = [0u8; 2];
+        socket.read_exact(&mut ver_ulen).await?;
+        if ver_ulen[0] != 0x01 {
+            return Err(anyhow!("Unsupported auth version {}", ver_ulen[0]));
+        }
+        let mut username = vec![0u8; ver_ulen[1] as usize];
+        socket.read_exact(&mut username).await?;
+
+        let mut plen = [0u8; 1];
+        socket.read_exact(&mut plen).await?;
+        let mut password = vec![0u8; plen[0] as usize];
+        socket.read_exact(&mut password).await?;
+
+        let user_ok = constant_time_eq(&username, self.credentials.username.as_bytes());
+        let pass_ok = constant_time_eq(&password, self.credentials.password.as_bytes());
+        if user_ok && pass_ok {
+            socket.write_all(&[0x01, 0x00]).await?;
+            Ok(())
         } else {
-            // Stream not found (maybe closed)
+            warn!("SOCKS5 authentication failed from {:?}", socket.peer_addr().ok());
+            socket.write_all(&[0x01, 0x01]).await?;
+            Err(anyhow!("SOCKS5 authentication failed"))
         }
     }
 
     async fn handle_client(
         &self, 
         mut socket: TcpStream, 
-        signal_client: Arc<SignalingClient>,
-        my_id: String,
-        route_table: Arc<Mutex<HashMap<Ipv4Addr, String>>>
+        tunnels: TcpTunnels,
+        rules: RuleEngine
     ) -> Result<()> {
         // 1. Handshake
         let mut buf = [0u8; 2];
@@ -94,149 +161,457 @@ impl Socks5Server {
         let mut methods = vec![0u8; n_methods];
         socket.read_exact(&mut methods).await?;
         
-        // Respond: No Auth (0x00)
-        socket.write_all(&[0x05, 0x00]).await?;
+        // Only username/password (0x02) is accepted; "No acceptable methods" otherwise.
+        if !methods.contains(&0x02) {
+            socket.write_all(&[0x05, 0xFF]).await?;
+            return Err(anyhow!("Client did not offer username/password auth"));
+        }
+        socket.write_all(&[0x05, 0x02]).await?;
+        self.authenticate(&mut socket).await?;
 
         // 2. Request
         let mut head = [0u8; 4];
         socket.read_exact(&mut head).await?;
         // VER(1) CMD(1) RSV(1) ATYP(1)
-        if head[1] != 0x01 { // CONNECT
-            return Err(anyhow!("Unsupported command"));
-        }
-
-        let target_ip: Ipv4Addr;
-        let target_host: String;
-        
-        match head[3] {
-            0x01 => { // IPv4
-                let mut ip_buf = [0u8; 4];
-                socket.read_exact(&mut ip_buf).await?;
-                target_ip = Ipv4Addr::from(ip_buf);
-                target_host = target_ip.to_string();
-            },
-            0x03 => { // Domain
-                let mut len_buf = [0u8; 1];
-                socket.read_exact(&mut len_buf).await?;
-                let len = len_buf[0] as usize;
-                let mut host_buf = vec![0u8; len];
-                socket.read_exact(&mut host_buf).await?;
-                target_host = String::from_utf8_lossy(&host_buf).to_string();
-                // Resolve DNS? Or just try to parse as IP
-                if let Ok(ip) = target_host.parse::<Ipv4Addr>() {
-                    target_ip = ip;
-                } else {
-                    // Domain resolution not supported yet for route lookup
-                    return Err(anyhow!("Domain routing not supported"));
-                }
-            },
-            _ => return Err(anyhow!("Unsupported address type")),
-        }
+        let target_host = match read_addr(&mut socket, head[3]).await {
+            Ok(host) => host,
+            Err(e) => {
+                socket.write_all(&encode_reply(0x08, None)).await?; // Address type not supported
+                return Err(e);
+            }
+        };
 
         let mut port_buf = [0u8; 2];
         socket.read_exact(&mut port_buf).await?;
         let port = u16::from_be_bytes(port_buf);
 
+        match head[1] {
+            0x01 => {} // CONNECT
+            0x03 => {
+                let (link, my_id) = (tunnels.link().clone(), tunnels.my_id().to_string());
+                return self.handle_udp_associate(socket, link, my_id, rules).await;
+            }
+            cmd => {
+                socket.write_all(&encode_reply(0x07, None)).await?; // Command not supported
+                return Err(anyhow!("Unsupported command {}", cmd));
+            }
+        }
+
         info!("SOCKS5 Request: {}:{}", target_host, port);
 
-        // 3. Lookup Route
-        let target_peer = {
-            let routes = route_table.lock().await;
-            routes.get(&target_ip).cloned()
+        // 3. Apply proxy rules (mesh IP, peer name, gateway domain or direct)
+        let target = match rules.route(&target_host, port).await {
+            ProxyRoute::Mesh(target) => target,
+            ProxyRoute::Direct => return connect_direct(socket, &target_host, port).await,
+            ProxyRoute::Reject => {
+                debug!("SOCKS5 {}:{} rejected by rule", target_host, port);
+                socket.write_all(&encode_reply(0x02, None)).await?; // Connection not allowed by ruleset
+                return Ok(());
+            }
+            ProxyRoute::Unreachable => {
+                socket.write_all(&encode_reply(0x03, None)).await?; // Network unreachable
+                return Ok(());
+            }
         };
 
-        if let Some(peer_id) = target_peer {
-            // Found route! Initiate tunnel.
-            let stream_id = {
-                let mut id = self.next_stream_id.lock().await;
-                *id += 1;
-                *id
-            };
-
-            let (tx, mut rx) = tokio::sync::mpsc::channel(32);
-            {
-                let mut streams = self.streams.lock().await;
-                streams.insert(stream_id, tx);
+        // Found route! Initiate tunnel.
+        match tunnels.connect(target, port).await {
+            Ok(tunnel) => {
+                socket.write_all(&encode_reply(0x00, tunnel.bind_addr())).await?;
+                tunnel.splice(socket).await;
+                Ok(())
             }
+            Err(reply) => {
+                socket.write_all(&encode_reply(reply, None)).await?;
+                Err(anyhow!("Peer could not connect (reply {:#04x})", reply))
+            }
+        }
+    }
+}
+
+/// DIRECT: connect from this machine and splice the two sockets together.
+async fn connect_direct(mut socket: TcpStream, host: &str, port: u16) -> Result<()> {
+    let mut remote = match connect_host(host, port).await {
+        Ok(s) => s,
+        Err(e) => {
+            socket.write_all(&encode_reply(reply_code_for(&e), None)).await?;
+            return Err(e.into());
+        }
+    };
+    socket.write_all(&encode_reply(0x00, remote.local_addr().ok())).await?;
+    tokio::io::copy_bidirectional(&mut socket, &mut remote).await?;
+    Ok(())
+}
+
+impl Socks5Server {
+    async fn handle_udp_associate(
+        &self,
+        mut socket: TcpStream,
+        link: PeerLink,
+        my_id: String,
+        rules: RuleEngine,
+    ) -> Result<()> {
+        // Bound on all interfaces so DIRECT datagrams can leave from it; only a loopback
+        // client is ever accepted below.
+        let relay = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+        let relay_port = relay.local_addr()?.port();
+        let assoc_id = self.next_id().await;
+        let assoc = Arc::new(UdpAssoc {
+            socket: relay.clone(),
+            client: Mutex::new(None),
+        });
+        self.udp_assocs.lock().await.insert(assoc_id, assoc.clone());
 
-            // Send TcpConnect
-            signal_client.send(SignalMessage::TcpConnect {
-                stream_id,
-                target: peer_id.clone(),
-                source: my_id.clone(),
-                target_ip: target_host,
-                target_port: port,
-            }).await?;
-
-            // Wait for Connected
-            match rx.recv().await {
-                Some(SocksMsg::Connected(true)) => {
-                    // Reply Success
-                    socket.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
-                },
-                _ => {
-                    // Fail
-                    socket.write_all(&[0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
-                    return Err(anyhow!("Connection refused by peer"));
+        // Reply with the relay address: BND.ADDR = 127.0.0.1, BND.PORT = relay port
+        let relay_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), relay_port);
+        socket.write_all(&encode_reply(0x00, Some(relay_addr))).await?;
+        info!("SOCKS5 UDP association {} relaying on 127.0.0.1:{}", assoc_id, relay_port);
+
+        // Targets reached DIRECT from the relay socket; their replies go back to the client
+        let mut direct_targets: HashSet<SocketAddr> = HashSet::new();
+        let mut ctrl_buf = [0u8; 64];
+        let mut buf = vec![0u8; 65535];
+        let result = loop {
+            tokio::select! {
+                // The association lives as long as its TCP control connection (RFC 1928, section 7)
+                res = socket.read(&mut ctrl_buf) => {
+                    match res {
+                        Ok(0) | Err(_) => break Ok(()),
+                        Ok(_) => {}
+                    }
                 }
-            }
+                res = relay.recv_from(&mut buf) => {
+                    let (n, from) = match res {
+                        Ok(r) => r,
+                        Err(e) => break Err(e.into()),
+                    };
 
-            // Pump Data
-            let (mut rd, mut wr) = socket.into_split();
-            let my_id_clone = my_id.clone();
-            let peer_id_clone = peer_id.clone();
-            let client_clone = signal_client.clone();
-
-            // Local -> Remote
-            tokio::spawn(async move {
-                let mut buf = [0u8; 4096];
-                loop {
-                    match rd.read(&mut buf).await {
-                        Ok(0) => break,
-                        Ok(n) => {
-                            let b64 = BASE64.encode(&buf[..n]);
-                            let _ = client_clone.send(SignalMessage::TcpData {
-                                stream_id,
-                                target: peer_id_clone.clone(),
-                                source: my_id_clone.clone(),
-                                data: b64,
-                            }).await;
+                    // Only the local client may use the relay; the first sender claims it.
+                    {
+                        let mut client = assoc.client.lock().await;
+                        match *client {
+                            Some(addr) if addr != from => {
+                                if direct_targets.contains(&from) {
+                                    let mut packet = encode_udp_header(from);
+                                    packet.extend_from_slice(&buf[..n]);
+                                    let _ = relay.send_to(&packet, addr).await;
+                                }
+                                continue;
+                            }
+                            None if !from.ip().is_loopback() => continue,
+                            None => *client = Some(from),
+                            _ => {}
                         }
-                        Err(_) => break,
                     }
-                }
-                // Send Close
-                let _ = client_clone.send(SignalMessage::TcpClose {
-                    stream_id,
-                    target: peer_id_clone,
-                    source: my_id_clone,
-                }).await;
-            });
-
-            // Remote -> Local
-            while let Some(msg) = rx.recv().await {
-                match msg {
-                    SocksMsg::Data(data) => {
-                        if wr.write_all(&data).await.is_err() {
-                            break;
+
+                    let (dst, payload) = match parse_udp_header(&buf[..n]) {
+                        Some(d) => d,
+                        None => continue,
+                    };
+                    match rules.route(&dst.0, dst.1).await {
+                        ProxyRoute::Mesh(MeshTarget { peer_id, host }) => {
+                            let _ = link.send_unreliable(&peer_id, SignalMessage::UdpData {
+                                assoc_id,
+                                target: peer_id.clone(),
+                                source: my_id.clone(),
+                                dst_ip: host,
+                                dst_port: dst.1,
+                                data: BASE64.encode(payload),
+                            }).await;
+                        }
+                        ProxyRoute::Direct => {
+                            let addr = match tokio::net::lookup_host((dst.0.as_str(), dst.1)).await {
+                                Ok(mut addrs) => addrs.find(|a| a.is_ipv4()),
+                                Err(_) => None,
+                            };
+                            if let Some(addr) = addr {
+                                direct_targets.insert(addr);
+                                let _ = relay.send_to(payload, addr).await;
+                            }
                         }
-                    },
-                    SocksMsg::Closed => break,
-                    _ => {}
+                        ProxyRoute::Reject | ProxyRoute::Unreachable => {
+                            debug!("SOCKS5 UDP association {}: dropping datagram for {}:{}", assoc_id, dst.0, dst.1)
+                        }
+                    }
                 }
             }
-            
-            // Cleanup
-            {
-                let mut streams = self.streams.lock().await;
-                streams.remove(&stream_id);
+        };
+
+        self.udp_assocs.lock().await.remove(&assoc_id);
+        debug!("SOCKS5 UDP association {} closed", assoc_id);
+        result
+    }
+}
+
+/// Exit side of SOCKS5 UDP associations: emits datagrams from peers toward their
+/// targets and sends whatever comes back to the originating peer.
+#[derive(Default)]
+pub struct UdpAssociateExit {
+    // (SourcePeerID, AssocID) -> socket used toward the targets
+    sockets: Arc<Mutex<HashMap<(String, u32), Arc<UdpSocket>>>>,
+}
+
+// Exit sockets with no traffic either way for this long are closed.
+const UDP_EXIT_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+impl UdpAssociateExit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn handle_data(
+        &self,
+        link: &PeerLink,
+        my_id: &str,
+        source_peer: String,
+        assoc_id: u32,
+        dst_ip: &str,
+        dst_port: u16,
+        data: &[u8],
+    ) -> Result<()> {
+        let key = (source_peer.clone(), assoc_id);
+        let existing = self.sockets.lock().await.get(&key).cloned();
+        let socket = match existing {
+            Some(s) => s,
+            None => {
+                let socket = Arc::new(bind_exit_socket().await?);
+                self.sockets.lock().await.insert(key.clone(), socket.clone());
+
+                let recv_socket = socket.clone();
+                let sockets = self.sockets.clone();
+                let link = link.clone();
+                let my_id = my_id.to_string();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 65535];
+                    loop {
+                        let (n, from) = match tokio::time::timeout(UDP_EXIT_IDLE_TIMEOUT, recv_socket.recv_from(&mut buf)).await {
+                            Ok(Ok(r)) => r,
+                            Ok(Err(_)) | Err(_) => break,
+                        };
+                        let _ = link.send_unreliable(&key.0, SignalMessage::UdpReply {
+                            assoc_id: key.1,
+                            target: key.0.clone(),
+                            source: my_id.clone(),
+                            // IPv4 sources show up IPv4-mapped on a dual-stack socket
+                            src_ip: from.ip().to_canonical().to_string(),
+                            src_port: from.port(),
+                            data: BASE64.encode(&buf[..n]),
+                        }).await;
+                    }
+                    sockets.lock().await.remove(&key);
+                });
+                socket
             }
-        } else {
-            // No route found. Reject or Direct?
-            // Reject for now.
-            socket.write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?; // Network unreachable
-        }
+        };
 
+        let dst = tokio::net::lookup_host((dst_ip, dst_port)).await?
+            .next()
+            .ok_or_else(|| anyhow!("{} did not resolve", dst_ip))?;
+        let dst = match (socket.local_addr()?, dst) {
+            (SocketAddr::V6(_), SocketAddr::V4(v4)) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+            _ => dst,
+        };
+        socket.send_to(data, dst).await?;
         Ok(())
     }
 }
+
+/// Exit socket for one association: dual-stack so it reaches IPv4 and IPv6
+/// destinations alike, IPv4-only where the host has no IPv6.
+async fn bind_exit_socket() -> std::io::Result<UdpSocket> {
+    let dual_stack = || -> std::io::Result<UdpSocket> {
+        let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+        UdpSocket::from_std(socket.into())
+    };
+    match dual_stack() {
+        Ok(socket) => Ok(socket),
+        Err(e) => {
+            debug!("SOCKS5 UDP exit: no dual-stack socket ({}), using IPv4 only", e);
+            UdpSocket::bind("0.0.0.0:0").await
+        }
+    }
+}
+
+/// Reads DST.ADDR for the given ATYP and returns it as text.
+async fn read_addr(socket: &mut TcpStream, atyp: u8) -> Result<String> {
+    match atyp {
+        0x01 => { // IPv4
+            let mut ip_buf = [0u8; 4];
+            socket.read_exact(&mut ip_buf).await?;
+            Ok(Ipv4Addr::from(ip_buf).to_string())
+        },
+        0x03 => { // Domain
+            let mut len_buf = [0u8; 1];
+            socket.read_exact(&mut len_buf).await?;
+            let len = len_buf[0] as usize;
+            let mut host_buf = vec![0u8; len];
+            socket.read_exact(&mut host_buf).await?;
+            Ok(String::from_utf8_lossy(&host_buf).to_string())
+        },
+        0x04 => { // IPv6
+            let mut ip_buf = [0u8; 16];
+            socket.read_exact(&mut ip_buf).await?;
+            Ok(Ipv6Addr::from(ip_buf).to_string())
+        },
+        _ => Err(anyhow!("Unsupported address type")),
+    }
+}
+
+/// Splits a SOCKS5 UDP datagram: RSV(2) FRAG(1) ATYP(1) DST.ADDR DST.PORT(2) DATA.
+/// Fragments are not supported and are dropped, as RFC 1928 allows.
+fn parse_udp_header(buf: &[u8]) -> Option<((String, u16), &[u8])> {
+    if buf.len() < 4 || buf[2] != 0 {
+        return None;
+    }
+    let (host, rest) = match buf[3] {
+        0x01 => {
+            let octets: [u8; 4] = buf.get(4..8)?.try_into().ok()?;
+            (Ipv4Addr::from(octets).to_string(), &buf[8..])
+        }
+        0x03 => {
+            let len = *buf.get(4)? as usize;
+            let host = std::str::from_utf8(buf.get(5..5 + len)?).ok()?;
+            (host.to_string(), &buf[5 + len..])
+        }
+        0x04 => {
+            let octets: [u8; 16] = buf.get(4..20)?.try_into().ok()?;
+            (Ipv6Addr::from(octets).to_string(), &buf[20..])
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+    Some(((host, port), &rest[2..]))
+}
+
+fn encode_udp_header(addr: SocketAddr) -> Vec<u8> {
+    let mut header = vec![0x00, 0x00, 0x00];
+    header.extend_from_slice(&encode_addr(addr));
+    header
+}
+
+/// VER REP RSV ATYP BND.ADDR BND.PORT; an unknown bind address is sent as 0.0.0.0:0.
+fn encode_reply(reply: u8, bind_addr: Option<SocketAddr>) -> Vec<u8> {
+    let bind_addr = bind_addr.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let mut packet = vec![0x05, reply, 0x00];
+    packet.extend_from_slice(&encode_addr(bind_addr));
+    packet
+}
+
+/// ATYP ADDR PORT
+fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut out = Vec::with_capacity(19);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(0x01);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(0x04);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+/// Connects to `host:port`, trying each address it resolves to. A name that does not
+/// resolve fails with `ErrorKind::HostUnreachable`, so `reply_code_for` can tell it
+/// apart from other failures.
+pub async fn connect_host(host: &str, port: u16) -> std::io::Result<TcpStream> {
+    use std::io::{Error, ErrorKind};
+    let addrs = tokio::net::lookup_host((host, port)).await
+        .map_err(|e| Error::new(ErrorKind::HostUnreachable, e))?;
+    let mut last_err = Error::new(ErrorKind::HostUnreachable, format!("{} has no addresses", host));
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Maps a failed connect on the target side to the matching SOCKS5 reply code.
+pub fn reply_code_for(err: &std::io::Error) -> u8 {
+    use std::io::ErrorKind;
+    match err.kind() {
+        ErrorKind::ConnectionRefused => 0x05,
+        ErrorKind::TimedOut => 0x06,
+        // Includes hostnames that did not resolve, see `connect_host`
+        ErrorKind::HostUnreachable => 0x04,
+        ErrorKind::NetworkUnreachable => 0x03,
+        ErrorKind::PermissionDenied => 0x02,
+        _ => 0x01,
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_addr_ipv4_and_ipv6() {
+        let v4 = encode_addr(SocketAddr::from(([192, 168, 1, 2], 8080)));
+        assert_eq!(v4, [0x01, 192, 168, 1, 2, 0x1f, 0x90]);
+
+        let v6 = encode_addr("[2001:db8::1]:53".parse().unwrap());
+        assert_eq!(v6[0], 0x04);
+        assert_eq!(&v6[1..17], &"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        assert_eq!(&v6[17..], &[0x00, 0x35]);
+    }
+
+    #[test]
+    fn udp_header_round_trips() {
+        for addr in ["10.0.0.1:5353", "[fe80::1]:1900"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let mut packet = encode_udp_header(addr);
+            packet.extend_from_slice(b"payload");
+            let ((host, port), data) = parse_udp_header(&packet).unwrap();
+            assert_eq!(host.parse::<IpAddr>().unwrap(), addr.ip());
+            assert_eq!(port, addr.port());
+            assert_eq!(data, b"payload");
+        }
+    }
+
+    #[test]
+    fn parse_udp_header_domain() {
+        let mut packet = vec![0x00, 0x00, 0x00, 0x03, 11];
+        packet.extend_from_slice(b"example.com");
+        packet.extend_from_slice(&443u16.to_be_bytes());
+        packet.extend_from_slice(b"x");
+        let ((host, port), data) = parse_udp_header(&packet).unwrap();
+        assert_eq!((host.as_str(), port, data), ("example.com", 443, &b"x"[..]));
+    }
+
+    #[test]
+    fn parse_udp_header_rejects_fragments_and_truncation() {
+        // FRAG != 0
+        assert!(parse_udp_header(&[0x00, 0x00, 0x01, 0x01, 1, 2, 3, 4, 0, 80]).is_none());
+        // Unknown ATYP
+        assert!(parse_udp_header(&[0x00, 0x00, 0x00, 0x07, 1, 2, 3, 4, 0, 80]).is_none());
+        // Port cut off
+        assert!(parse_udp_header(&[0x00, 0x00, 0x00, 0x01, 1, 2, 3, 4, 0]).is_none());
+        // Domain longer than the datagram
+        assert!(parse_udp_header(&[0x00, 0x00, 0x00, 0x03, 20, b'a', b'b']).is_none());
+    }
+
+    #[test]
+    fn unresolvable_host_maps_to_host_unreachable() {
+        let err = std::io::Error::new(std::io::ErrorKind::HostUnreachable, "no such host");
+        assert_eq!(reply_code_for(&err), 0x04);
+        let err = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(reply_code_for(&err), 0x05);
+        let err = std::io::Error::other("failed to lookup address information");
+        assert_eq!(reply_code_for(&err), 0x01);
+    }
+}
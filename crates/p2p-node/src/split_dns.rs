@@ -0,0 +1,233 @@
+use std::net::Ipv4Addr;
+use std::process::Command;
+use anyhow::{Result, anyhow};
+use tracing::{info, warn};
+
+/// Points the OS resolver at our DNS server for a set of domains only (split DNS),
+/// leaving every other lookup on the system's usual resolvers.
+///
+/// `apply` may be called again with a new domain list; `revert` undoes it and is
+/// also run when the configurator is dropped.
+pub trait DnsConfigurator: Send {
+    fn apply(&mut self, server: Ipv4Addr, domains: &[String]) -> Result<()>;
+    fn revert(&mut self);
+}
+
+/// True if `domain` is dot-separated LDH labels (`a-z`, `0-9`, `-`, 1-63 characters,
+/// no leading or trailing hyphen). Configurators refuse anything else: domains end
+/// up in file paths and commands.
+pub fn is_dns_name(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        })
+}
+
+fn check_domains(domains: &[String]) -> Result<()> {
+    match domains.iter().find(|d| !is_dns_name(d)) {
+        Some(bad) => Err(anyhow!("Refusing to configure DNS for invalid domain {:?}", bad)),
+        None => Ok(()),
+    }
+}
+
+/// The configurator for this OS. `interface` is the TUN device name.
+pub fn platform_configurator(interface: &str) -> Box<dyn DnsConfigurator> {
+    #[cfg(target_os = "linux")]
+    {
+        Box::new(SystemdResolved::new(interface))
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let _ = interface;
+        Box::new(ResolverFiles::default())
+    }
+    #[cfg(target_os = "windows")]
+    {
+        let _ = interface;
+        Box::new(WindowsNrpt { applied: false })
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        let _ = interface;
+        Box::new(Unsupported)
+    }
+}
+
+/// Linux: per-link DNS through systemd-resolved. Routing-only domains (`~domain`)
+/// send just those names to the link's server.
+pub struct SystemdResolved {
+    interface: String,
+    applied: bool,
+}
+
+impl SystemdResolved {
+    pub fn new(interface: &str) -> Self {
+        Self {
+            interface: interface.to_string(),
+            applied: false,
+        }
+    }
+}
+
+impl DnsConfigurator for SystemdResolved {
+    fn apply(&mut self, server: Ipv4Addr, domains: &[String]) -> Result<()> {
+        check_domains(domains)?;
+        run("resolvectl", &["dns", &self.interface, &server.to_string()])?;
+        let mut args = vec!["domain".to_string(), self.interface.clone()];
+        args.extend(domains.iter().map(|d| format!("~{}", d)));
+        run("resolvectl", &args.iter().map(String::as_str).collect::<Vec<_>>())?;
+        self.applied = true;
+        info!("systemd-resolved: {} -> {} on {}", domains.join(", "), server, self.interface);
+        Ok(())
+    }
+
+    fn revert(&mut self) {
+        if std::mem::take(&mut self.applied) {
+            if let Err(e) = run("resolvectl", &["revert", &self.interface]) {
+                warn!("Failed to revert DNS on {}: {}", self.interface, e);
+            }
+        }
+    }
+}
+
+impl Drop for SystemdResolved {
+    fn drop(&mut self) {
+        self.revert();
+    }
+}
+
+/// macOS: one `/etc/resolver/<domain>` file per domain.
+#[derive(Default)]
+pub struct ResolverFiles {
+    written: Vec<String>,
+}
+
+impl ResolverFiles {
+    const DIR: &'static str = "/etc/resolver";
+}
+
+impl DnsConfigurator for ResolverFiles {
+    fn apply(&mut self, server: Ipv4Addr, domains: &[String]) -> Result<()> {
+        check_domains(domains)?;
+        self.revert();
+        std::fs::create_dir_all(Self::DIR)?;
+        for domain in domains {
+            let path = format!("{}/{}", Self::DIR, domain);
+            std::fs::write(&path, format!("# Added by Syuink\nnameserver {}\n", server))
+                .map_err(|e| anyhow!("Failed to write {}: {}", path, e))?;
+            self.written.push(path);
+        }
+        info!("Resolver files: {} -> {}", domains.join(", "), server);
+        Ok(())
+    }
+
+    fn revert(&mut self) {
+        for path in self.written.drain(..) {
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
+
+impl Drop for ResolverFiles {
+    fn drop(&mut self) {
+        self.revert();
+    }
+}
+
+/// Windows: Name Resolution Policy Table rules, tagged so they can be found again.
+pub struct WindowsNrpt {
+    applied: bool,
+}
+
+impl WindowsNrpt {
+    const TAG: &'static str = "Syuink";
+}
+
+impl DnsConfigurator for WindowsNrpt {
+    fn apply(&mut self, server: Ipv4Addr, domains: &[String]) -> Result<()> {
+        check_domains(domains)?;
+        self.revert();
+        self.applied = true;
+        // Values go in through the environment, never into the script text
+        let script = "Add-DnsClientNrptRule -Namespace $env:SYUINK_NAMESPACE -NameServers $env:SYUINK_SERVER -Comment $env:SYUINK_TAG";
+        for domain in domains {
+            let env = [
+                ("SYUINK_NAMESPACE", format!(".{}", domain)),
+                ("SYUINK_SERVER", server.to_string()),
+                ("SYUINK_TAG", Self::TAG.to_string()),
+            ];
+            run_with_env("powershell", &["-NoProfile", "-Command", script], &env)?;
+        }
+        info!("NRPT: {} -> {}", domains.join(", "), server);
+        Ok(())
+    }
+
+    fn revert(&mut self) {
+        if std::mem::take(&mut self.applied) {
+            let script = format!(
+                "Get-DnsClientNrptRule | Where-Object Comment -eq '{}' | Remove-DnsClientNrptRule -Force",
+                Self::TAG
+            );
+            if let Err(e) = run("powershell", &["-Command", &script]) {
+                warn!("Failed to remove NRPT rules: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for WindowsNrpt {
+    fn drop(&mut self) {
+        self.revert();
+    }
+}
+
+/// Leaves the OS alone; names still resolve when queried at the server directly.
+pub struct Unsupported;
+
+impl DnsConfigurator for Unsupported {
+    fn apply(&mut self, server: Ipv4Addr, domains: &[String]) -> Result<()> {
+        warn!("Split DNS is not supported on this OS; point {} at {} manually", domains.join(", "), server);
+        Ok(())
+    }
+
+    fn revert(&mut self) {}
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    run_with_env(program, args, &[])
+}
+
+fn run_with_env(program: &str, args: &[&str], env: &[(&str, String)]) -> Result<()> {
+    let output = Command::new(program).args(args).envs(env.iter().map(|(k, v)| (k, v))).output()
+        .map_err(|e| anyhow!("Failed to run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(anyhow!("{} {} failed: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dns_names_are_ldh_labels() {
+        for ok in ["home.arpa", "lab-1.corp.internal", "a", "x0.y9"] {
+            assert!(is_dns_name(ok), "{}", ok);
+        }
+        let long = "a".repeat(64);
+        for bad in ["", ".home.arpa", "home.arpa.", "a..b", "-a.lan", "a-.lan", "Home.arpa", "../../etc/sudoers.d/x", "lan' ; rm", "a b.lan", long.as_str()] {
+            assert!(!is_dns_name(bad), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn configurators_refuse_bad_domains() {
+        let mut files = ResolverFiles::default();
+        assert!(files.apply(Ipv4Addr::LOCALHOST, &["../../tmp/x".to_string()]).is_err());
+        assert!(files.written.is_empty());
+    }
+}
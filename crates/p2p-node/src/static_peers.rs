@@ -0,0 +1,353 @@
+//! Serverless mode: peers configured by hand, WireGuard style, and reached directly
+//! over QUIC. Each side pins the other's public key, so no signaling server is needed
+//! to introduce or vouch for them.
+//!
+//! Config format (keys are case-insensitive, `#` starts a comment):
+//!
+//! ```text
+//! [Interface]
+//! ID = lab-1
+//! PrivateKey = <base64, from `syuink-cli --genkey`>
+//! ListenPort = 41641
+//!
+//! [Peer]
+//! ID = lab-2
+//! Name = lab-2
+//! PublicKey = <base64>
+//! VirtualIP = 10.251.0.12
+//! Endpoint = 192.168.1.12:41641
+//! AllowedIPs = 192.168.60.0/24
+//! ```
+//!
+//! `ID` is the node ID this node presents; peers pin its key under that ID.
+//! `Endpoint` may be repeated or comma-separated and may be left out for peers that
+//! only dial us. `AllowedIPs` are the networks routed through that peer.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use ipnetwork::Ipv4Network;
+use ring::signature::{EcdsaKeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING};
+use tracing::warn;
+use crate::p2p::P2PManager;
+
+const REDIAL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// This node's long-term key. Its public half is what static peers pin; its QUIC
+/// certificate is made from it.
+#[derive(Clone)]
+pub struct NodeKey {
+    pkcs8: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl NodeKey {
+    pub fn generate() -> Result<Self> {
+        let pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+        Ok(Self { pkcs8: pair.serialize_der(), public: pair.public_key_raw().to_vec() })
+    }
+
+    /// From the base64 PKCS#8 form `to_base64` writes.
+    pub fn from_base64(text: &str) -> Result<Self> {
+        let pkcs8 = BASE64.decode(text.trim()).map_err(|e| anyhow!("Bad private key: {}", e))?;
+        let pair = rcgen::KeyPair::from_der(&pkcs8).map_err(|e| anyhow!("Bad private key: {}", e))?;
+        Ok(Self { public: pair.public_key_raw().to_vec(), pkcs8 })
+    }
+
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(&self.pkcs8)
+    }
+
+    /// Base64 public key, as written in peers' configs.
+    pub fn public_key(&self) -> String {
+        BASE64.encode(&self.public)
+    }
+
+    /// Self-signed certificate for this key: (certificate DER, private key DER).
+    pub(crate) fn certificate(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let pair = rcgen::KeyPair::from_der(&self.pkcs8)?;
+        let mut params = rcgen::CertificateParams::new(vec!["syuink-p2p".to_string()]);
+        params.alg = pair.algorithm();
+        params.key_pair = Some(pair);
+        let cert = rcgen::Certificate::from_params(params)?;
+        Ok((cert.serialize_der()?, cert.serialize_private_key_der()))
+    }
+
+    /// ECDSA P-256 signature over `msg`, checked with `verify_signature`.
+    pub(crate) fn sign(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        let rng = ring::rand::SystemRandom::new();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &self.pkcs8, &rng)
+            .map_err(|e| anyhow!("Bad private key: {}", e))?;
+        let sig = pair.sign(&rng, msg).map_err(|e| anyhow!("Signing failed: {}", e))?;
+        Ok(sig.as_ref().to_vec())
+    }
+}
+
+/// Whether `sig` is `NodeKey::sign` of `msg` by the key whose raw public half is `public_key`.
+pub(crate) fn verify_signature(public_key: &[u8], msg: &[u8], sig: &[u8]) -> bool {
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key).verify(msg, sig).is_ok()
+}
+
+// Never print the private half
+impl fmt::Debug for NodeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeKey").field("public_key", &self.public_key()).finish()
+    }
+}
+
+/// A peer configured by hand rather than announced by a signaling server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaticPeer {
+    pub id: String,
+    pub name: String,
+    /// Base64 public key (see `NodeKey::public_key`).
+    pub public_key: String,
+    pub ip: Ipv4Addr,
+    /// host:port pairs to dial, in order; empty if the peer only dials us.
+    pub endpoints: Vec<String>,
+    /// Networks reached through this peer, which must act as a gateway for them.
+    pub routes: Vec<Ipv4Network>,
+}
+
+impl StaticPeer {
+    pub(crate) fn public_key_bytes(&self) -> Result<Vec<u8>> {
+        BASE64.decode(self.public_key.trim()).map_err(|e| anyhow!("Bad public key for {}: {}", self.id, e))
+    }
+}
+
+/// Contents of a static config file.
+#[derive(Clone, Debug, Default)]
+pub struct StaticConfig {
+    /// Node ID to run as; static peers know us by it.
+    pub node_id: Option<String>,
+    pub private_key: Option<NodeKey>,
+    /// UDP port for direct QUIC, so peers know where to dial us.
+    pub listen_port: Option<u16>,
+    pub peers: Vec<StaticPeer>,
+}
+
+impl StaticConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read static config {}: {}", path.display(), e))?;
+        text.parse()
+    }
+}
+
+#[derive(Default)]
+struct PeerSection {
+    line: usize,
+    id: Option<String>,
+    name: Option<String>,
+    public_key: Option<String>,
+    ip: Option<Ipv4Addr>,
+    endpoints: Vec<String>,
+    routes: Vec<Ipv4Network>,
+}
+
+impl PeerSection {
+    fn finish(self) -> Result<StaticPeer> {
+        let missing = |key: &str| anyhow!("Static config line {}: [Peer] without {}", self.line, key);
+        let id = self.id.clone().ok_or_else(|| missing("ID"))?;
+        let peer = StaticPeer {
+            name: self.name.clone().unwrap_or_else(|| id.clone()),
+            public_key: self.public_key.clone().ok_or_else(|| missing("PublicKey"))?,
+            ip: self.ip.ok_or_else(|| missing("VirtualIP"))?,
+            endpoints: self.endpoints,
+            routes: self.routes,
+            id,
+        };
+        peer.public_key_bytes()?;
+        Ok(peer)
+    }
+}
+
+enum Section {
+    None,
+    Interface,
+    Peer(PeerSection),
+}
+
+impl FromStr for StaticConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut config = StaticConfig::default();
+        let mut section = Section::None;
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: &str| anyhow!("Static config line {}: {}: {}", n + 1, msg, line);
+
+            if line.starts_with('[') {
+                if let Section::Peer(peer) = std::mem::replace(&mut section, Section::None) {
+                    config.peers.push(peer.finish()?);
+                }
+                section = match line.to_ascii_lowercase().as_str() {
+                    "[interface]" => Section::Interface,
+                    "[peer]" => Section::Peer(PeerSection { line: n + 1, ..Default::default() }),
+                    _ => return Err(err("unknown section")),
+                };
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or_else(|| err("expected Key = Value"))?;
+            let (key, value) = (key.trim().to_ascii_lowercase(), value.trim());
+            match (&mut section, key.as_str()) {
+                (Section::Interface, "id") => config.node_id = Some(value.to_string()),
+                (Section::Interface, "privatekey") => {
+                    config.private_key = Some(NodeKey::from_base64(value).map_err(|e| err(&e.to_string()))?);
+                }
+                (Section::Interface, "listenport") => {
+                    config.listen_port = Some(value.parse().map_err(|_| err("bad port"))?);
+                }
+                (Section::Peer(peer), "id") => peer.id = Some(value.to_string()),
+                (Section::Peer(peer), "name") => peer.name = Some(value.to_string()),
+                (Section::Peer(peer), "publickey") => peer.public_key = Some(value.to_string()),
+                (Section::Peer(peer), "virtualip") => {
+                    peer.ip = Some(value.parse().map_err(|_| err("bad IPv4 address"))?);
+                }
+                (Section::Peer(peer), "endpoint") => {
+                    peer.endpoints.extend(list(value).map(str::to_string));
+                }
+                (Section::Peer(peer), "allowedips") => {
+                    for net in list(value) {
+                        peer.routes.push(net.parse().map_err(|_| err("bad IPv4 network"))?);
+                    }
+                }
+                (Section::None, _) => return Err(err("setting outside a section")),
+                _ => return Err(err("unknown setting")),
+            }
+        }
+        if let Section::Peer(peer) = section {
+            config.peers.push(peer.finish()?);
+        }
+        Ok(config)
+    }
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Keeps a direct connection to `peer` up, dialing its endpoints in turn. Returns at
+/// once for a peer without endpoints; it has to dial us.
+pub(crate) async fn keep_connected(p2p: Arc<P2PManager>, peer: StaticPeer) {
+    if peer.endpoints.is_empty() {
+        return;
+    }
+    loop {
+        if p2p.get_connection(&peer.id).await.is_none() {
+            'endpoints: for endpoint in &peer.endpoints {
+                let addrs = match tokio::net::lookup_host(endpoint.as_str()).await {
+                    Ok(addrs) => addrs,
+                    Err(e) => {
+                        warn!("[Static] Cannot resolve {} for {}: {}", endpoint, peer.name, e);
+                        continue;
+                    }
+                };
+                for addr in addrs {
+                    if p2p.connect_to(peer.id.clone(), addr).await.is_ok() {
+                        break 'endpoints;
+                    }
+                }
+            }
+        }
+        tokio::time::sleep(REDIAL_INTERVAL).await;
+    }
+}
+
+/// The raw public key in an X.509 certificate's SubjectPublicKeyInfo.
+pub(crate) fn cert_public_key(cert: &[u8]) -> Option<Vec<u8>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(cert.public_key().subject_public_key.data.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<StaticConfig> {
+        text.parse()
+    }
+
+    fn error(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_interface_and_peers() {
+        let (key, peer_key) = (NodeKey::generate().unwrap(), NodeKey::generate().unwrap());
+        let text = format!(
+            "# office mesh\n\
+             [Interface]\n\
+             ID = laptop\n\
+             PrivateKey = {}\n\
+             ListenPort = 51820  # fixed for the firewall\n\
+             \n\
+             [peer]\n\
+             id = nas\n\
+             PublicKey = {}\n\
+             VirtualIP = 10.251.0.20\n\
+             Endpoint = nas.example.com:51820, 192.0.2.7:51820,\n\
+             Endpoint = [2001:db8::7]:51820\n\
+             AllowedIPs = 192.168.1.0/24 ,192.168.2.0/24\n",
+            key.to_base64(),
+            peer_key.public_key(),
+        );
+        let config = parse(&text).unwrap();
+        assert_eq!(config.node_id.as_deref(), Some("laptop"));
+        assert_eq!(config.private_key.unwrap().public_key(), key.public_key());
+        assert_eq!(config.listen_port, Some(51820));
+        assert_eq!(config.peers, vec![StaticPeer {
+            id: "nas".to_string(),
+            name: "nas".to_string(),
+            public_key: peer_key.public_key(),
+            ip: Ipv4Addr::new(10, 251, 0, 20),
+            endpoints: vec!["nas.example.com:51820".to_string(), "192.0.2.7:51820".to_string(), "[2001:db8::7]:51820".to_string()],
+            routes: vec!["192.168.1.0/24".parse().unwrap(), "192.168.2.0/24".parse().unwrap()],
+        }]);
+    }
+
+    #[test]
+    fn every_peer_section_is_finished() {
+        let (a, b) = (NodeKey::generate().unwrap().public_key(), NodeKey::generate().unwrap().public_key());
+        let text = format!("[Peer]\nID = a\nName = Alpha\nPublicKey = {}\nVirtualIP = 10.251.0.2\n[Peer]\nID = b\nPublicKey = {}\nVirtualIP = 10.251.0.3\n", a, b);
+        let config = parse(&text).unwrap();
+        let names: Vec<&str> = config.peers.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["Alpha", "b"]);
+        assert!(config.peers.iter().all(|p| p.endpoints.is_empty() && p.routes.is_empty()));
+        assert!(parse("# nothing but comments\n\n").unwrap().peers.is_empty());
+    }
+
+    #[test]
+    fn bad_sections_are_rejected() {
+        assert!(error("[Wireguard]\n").contains("line 1: unknown section"));
+        assert!(error("ID = laptop\n").contains("setting outside a section"));
+        assert!(error("[Interface]\nEndpoint = 192.0.2.7:51820\n").contains("unknown setting"));
+        assert!(error("[Interface]\nListenPort\n").contains("expected Key = Value"));
+        assert!(error("[Interface]\nListenPort = 70000\n").contains("bad port"));
+    }
+
+    #[test]
+    fn incomplete_peers_are_rejected() {
+        let key = NodeKey::generate().unwrap().public_key();
+        assert!(error("[Peer]\nPublicKey = x\nVirtualIP = 10.251.0.2\n").contains("line 1: [Peer] without ID"));
+        assert!(error("[Peer]\nID = a\nVirtualIP = 10.251.0.2\n").contains("without PublicKey"));
+        // Checked when the next section starts, not only at the end
+        let text = format!("[Interface]\n[Peer]\nID = a\nPublicKey = {}\n[Peer]\n", key);
+        assert!(error(&text).contains("line 2: [Peer] without VirtualIP"));
+        assert!(error("[Peer]\nID = a\nPublicKey = not base64!\nVirtualIP = 10.251.0.2\n").contains("Bad public key for a"));
+        assert!(error(&format!("[Peer]\nID = a\nPublicKey = {}\nVirtualIP = 10.251.0.256\n", key)).contains("bad IPv4 address"));
+        assert!(error(&format!("[Peer]\nID = a\nPublicKey = {}\nVirtualIP = 10.251.0.2\nAllowedIPs = 192.168.1.0/24, fd00::/8\n", key)).contains("bad IPv4 network"));
+    }
+}
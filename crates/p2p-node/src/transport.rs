@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use quinn::{RecvStream, SendStream};
+use crate::directory::MeshTarget;
+use crate::p2p::P2PManager;
+use crate::relay::RelayClient;
+use crate::signaling::{SignalMessage, SignalingClient};
+
+/// Delivers peer-addressed signaling messages, over a direct P2P connection or the
+/// packet relay when the message tolerates loss, through the signaling server otherwise.
+#[derive(Clone)]
+pub struct PeerLink {
+    // None when only static peers are configured
+    signal: Option<Arc<SignalingClient>>,
+    p2p: Arc<P2PManager>,
+    relay: Arc<RelayClient>,
+}
+
+impl PeerLink {
+    pub fn new(signal: Option<Arc<SignalingClient>>, p2p: Arc<P2PManager>, relay: Arc<RelayClient>) -> Self {
+        Self { signal, p2p, relay }
+    }
+
+    /// Reliable, ordered delivery through the signaling server.
+    pub async fn send(&self, msg: SignalMessage) -> Result<()> {
+        match &self.signal {
+            Some(signal) => signal.send(msg).await,
+            None => Err(anyhow!("No signaling server to relay through")),
+        }
+    }
+
+    /// Best-effort delivery for datagram payloads: direct path, then the packet
+    /// relay, then the signaling server.
+    pub async fn send_unreliable(&self, peer_id: &str, msg: SignalMessage) -> Result<()> {
+        if self.p2p.send_message(peer_id, &msg).await || self.relay.send_message(peer_id, &msg).await {
+            return Ok(());
+        }
+        self.send(msg).await
+    }
+
+    /// TCP stream over the direct QUIC connection, if there is one.
+    pub async fn open_stream(&self, peer_id: &str, host: &str, port: u16) -> Option<std::result::Result<(SendStream, RecvStream), u8>> {
+        self.p2p.open_stream(peer_id, host, port).await
+    }
+}
+
+/// Events for one outbound TCP tunnel, fed from the signaling loop.
+pub enum TunnelMsg {
+    /// Outcome of the remote connect: an RFC 1928 reply code (0 = succeeded)
+    /// and the address the remote side connected from.
+    Connected { reply: u8, bind_addr: Option<SocketAddr> },
+    Data(Vec<u8>),
+    Closed,
+}
+
+/// Outbound TCP streams carried to peers as `TcpConnect`/`TcpData`/`TcpClose`,
+/// shared by every local listener that opens them (SOCKS5, HTTP proxy, forwards).
+#[derive(Clone)]
+pub struct TcpTunnels {
+    link: PeerLink,
+    my_id: String,
+    // Map StreamID -> Sender<TunnelMsg>
+    streams: Arc<Mutex<HashMap<u32, mpsc::Sender<TunnelMsg>>>>,
+    next_stream_id: Arc<Mutex<u32>>,
+}
+
+impl TcpTunnels {
+    pub fn new(link: PeerLink, my_id: String) -> Self {
+        Self {
+            link,
+            my_id,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            next_stream_id: Arc::new(Mutex::new(1)),
+        }
+    }
+
+    pub fn link(&self) -> &PeerLink {
+        &self.link
+    }
+
+    pub fn my_id(&self) -> &str {
+        &self.my_id
+    }
+
+    pub async fn on_msg(&self, stream_id: u32, msg: TunnelMsg) {
+        let mut streams = self.streams.lock().await;
+        if let Some(tx) = streams.get(&stream_id) {
+            if tx.send(msg).await.is_err() {
+                streams.remove(&stream_id);
+            }
+        }
+    }
+
+    /// Asks `target.peer_id` to connect to `target.host:port` and waits for the answer,
+    /// over a direct QUIC stream when connected, through the relay otherwise.
+    /// On failure the error is the SOCKS5 reply code describing why.
+    pub async fn connect(&self, target: MeshTarget, port: u16) -> std::result::Result<TcpTunnel, u8> {
+        match self.link.open_stream(&target.peer_id, &target.host, port).await {
+            Some(Ok((send, recv))) => return Ok(TcpTunnel {
+                bind_addr: None,
+                body: TunnelBody::Quic { send, recv },
+            }),
+            Some(Err(reply)) => return Err(reply),
+            None => {}
+        }
+
+        let stream_id = {
+            let mut id = self.next_stream_id.lock().await;
+            *id += 1;
+            *id
+        };
+
+        let (tx, mut rx) = mpsc::channel(32);
+        self.streams.lock().await.insert(stream_id, tx);
+
+        let sent = self.link.send(SignalMessage::TcpConnect {
+            stream_id,
+            target: target.peer_id.clone(),
+            source: self.my_id.clone(),
+            target_ip: target.host,
+            target_port: port,
+        }).await;
+
+        let outcome = match sent {
+            Ok(()) => rx.recv().await,
+            Err(_) => None,
+        };
+        match outcome {
+            Some(TunnelMsg::Connected { reply: 0x00, bind_addr }) => Ok(TcpTunnel {
+                bind_addr,
+                body: TunnelBody::Relay {
+                    stream_id,
+                    peer_id: target.peer_id,
+                    rx,
+                    tunnels: self.clone(),
+                },
+            }),
+            Some(TunnelMsg::Connected { reply, .. }) => {
+                self.streams.lock().await.remove(&stream_id);
+                Err(reply)
+            }
+            _ => {
+                self.streams.lock().await.remove(&stream_id);
+                Err(0x01)
+            }
+        }
+    }
+}
+
+/// An established tunnel to a peer-side TCP connection.
+pub struct TcpTunnel {
+    bind_addr: Option<SocketAddr>,
+    body: TunnelBody,
+}
+
+enum TunnelBody {
+    // TcpData/TcpClose messages through PeerLink
+    Relay {
+        stream_id: u32,
+        peer_id: String,
+        rx: mpsc::Receiver<TunnelMsg>,
+        tunnels: TcpTunnels,
+    },
+    // Bidirectional stream on the direct QUIC connection
+    Quic {
+        send: SendStream,
+        recv: RecvStream,
+    },
+}
+
+impl TcpTunnel {
+    /// Local address of the peer's socket toward the target, if it reported one.
+    pub fn bind_addr(&self) -> Option<SocketAddr> {
+        self.bind_addr
+    }
+
+    /// Sends bytes ahead of `splice`, e.g. a rewritten request head.
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.body {
+            TunnelBody::Relay { stream_id, peer_id, tunnels, .. } => {
+                tunnels.link.send(SignalMessage::TcpData {
+                    stream_id: *stream_id,
+                    target: peer_id.clone(),
+                    source: tunnels.my_id.clone(),
+                    data: data.to_vec(),
+                }).await
+            }
+            TunnelBody::Quic { send, .. } => {
+                send.write_all(data).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Pumps data both ways until either end closes.
+    pub async fn splice(self, socket: TcpStream) {
+        match self.body {
+            TunnelBody::Relay { stream_id, peer_id, rx, tunnels } => {
+                splice_relay(socket, stream_id, peer_id, rx, tunnels).await
+            }
+            TunnelBody::Quic { send, recv } => splice_quic(socket, send, recv).await,
+        }
+    }
+}
+
+async fn splice_relay(
+    socket: TcpStream,
+    stream_id: u32,
+    peer_id: String,
+    mut rx: mpsc::Receiver<TunnelMsg>,
+    tunnels: TcpTunnels,
+) {
+    let (mut rd, mut wr) = socket.into_split();
+    let link = tunnels.link.clone();
+    let my_id = tunnels.my_id.clone();
+
+    // Local -> Remote
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4096];
+        loop {
+            match rd.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    let _ = link.send(SignalMessage::TcpData {
+                        stream_id,
+                        target: peer_id.clone(),
+                        source: my_id.clone(),
+                        data: buf[..n].to_vec(),
+                    }).await;
+                }
+                Err(_) => break,
+            }
+        }
+        // Send Close
+        let _ = link.send(SignalMessage::TcpClose {
+            stream_id,
+            target: peer_id,
+            source: my_id,
+        }).await;
+    });
+
+    // Remote -> Local
+    while let Some(msg) = rx.recv().await {
+        match msg {
+            TunnelMsg::Data(data) => {
+                if wr.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+            TunnelMsg::Closed => break,
+            _ => {}
+        }
+    }
+
+    // Cleanup
+    tunnels.streams.lock().await.remove(&stream_id);
+}
+
+/// Relays a TCP socket over a QUIC stream pair; used on both ends of a direct stream.
+pub async fn splice_quic(socket: TcpStream, mut send: SendStream, mut recv: RecvStream) {
+    let (mut rd, mut wr) = socket.into_split();
+    let upstream = async {
+        let _ = tokio::io::copy(&mut rd, &mut send).await;
+        let _ = send.finish().await;
+    };
+    let downstream = async {
+        let _ = tokio::io::copy(&mut recv, &mut wr).await;
+        let _ = wr.shutdown().await;
+    };
+    tokio::join!(upstream, downstream);
+}
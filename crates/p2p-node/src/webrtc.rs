@@ -17,14 +17,14 @@ use webrtc::peer_connection::RTCPeerConnection;
 
 use std::time::Duration;
 use crate::p2p::{P2PEvent, P2PTransport};
+use crate::SharedPacketWriter;
 use tokio::io::AsyncWriteExt;
-use tun_device::AsyncDevice;
 
 pub struct WebRTCManager {
     api: webrtc::api::API,
     connections: Arc<Mutex<HashMap<String, Arc<RTCPeerConnection>>>>,
     my_id: String,
-    tun_writer: Arc<Mutex<tokio::io::WriteHalf<AsyncDevice>>>,
+    tun_writer: SharedPacketWriter,
     event_tx: tokio::sync::mpsc::Sender<P2PEvent>,
     ice_servers: Vec<RTCIceServer>,
 }
@@ -32,7 +32,7 @@ pub struct WebRTCManager {
 impl WebRTCManager {
     pub async fn new(
         my_id: String,
-        tun_writer: Arc<Mutex<tokio::io::WriteHalf<AsyncDevice>>>,
+        tun_writer: SharedPacketWriter,
         event_tx: tokio::sync::mpsc::Sender<P2PEvent>,
     ) -> Result<Self> {
 
@@ -0,0 +1,124 @@
+//! Binary WebSocket framing for data-plane signaling messages.
+//!
+//! When both a node and the signaling server support it (negotiated at join),
+//! `tun_packet`, `broadcast` and `tcp_data` travel as binary frames instead of JSON
+//! with base64 payloads:
+//! `[kind u8][source len u8][source][target len u8][target][stream id u32 BE, tcp_data only][payload]`.
+//! A broadcast has an empty target.
+
+pub const KIND_TUN_PACKET: u8 = 0x01;
+pub const KIND_BROADCAST: u8 = 0x02;
+pub const KIND_TCP_DATA: u8 = 0x03;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataFrame<'a> {
+    pub kind: u8,
+    pub source: &'a str,
+    pub target: &'a str,
+    /// Only carried for `KIND_TCP_DATA`.
+    pub stream_id: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> DataFrame<'a> {
+    /// `None` if an id is longer than 255 bytes.
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let source = self.source.as_bytes();
+        let target = self.target.as_bytes();
+        let mut out = Vec::with_capacity(7 + source.len() + target.len() + self.payload.len());
+        out.push(self.kind);
+        out.push(u8::try_from(source.len()).ok()?);
+        out.extend_from_slice(source);
+        out.push(u8::try_from(target.len()).ok()?);
+        out.extend_from_slice(target);
+        if self.kind == KIND_TCP_DATA {
+            out.extend_from_slice(&self.stream_id.to_be_bytes());
+        }
+        out.extend_from_slice(self.payload);
+        Some(out)
+    }
+
+    pub fn decode(bytes: &'a [u8]) -> Option<Self> {
+        let (&kind, rest) = bytes.split_first()?;
+        if !matches!(kind, KIND_TUN_PACKET | KIND_BROADCAST | KIND_TCP_DATA) {
+            return None;
+        }
+        let (source, rest) = take_str(rest)?;
+        let (target, rest) = take_str(rest)?;
+        let (stream_id, payload) = if kind == KIND_TCP_DATA {
+            if rest.len() < 4 {
+                return None;
+            }
+            let (id, payload) = rest.split_at(4);
+            (u32::from_be_bytes([id[0], id[1], id[2], id[3]]), payload)
+        } else {
+            (0, rest)
+        };
+        Some(Self { kind, source, target, stream_id, payload })
+    }
+}
+
+// u8 length-prefixed UTF-8 string
+fn take_str(bytes: &[u8]) -> Option<(&str, &[u8])> {
+    let (&len, rest) = bytes.split_first()?;
+    if rest.len() < len as usize {
+        return None;
+    }
+    let (s, rest) = rest.split_at(len as usize);
+    Some((std::str::from_utf8(s).ok()?, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tun_packet_round_trips() {
+        let frame = DataFrame { kind: KIND_TUN_PACKET, source: "node-a", target: "node-b", stream_id: 0, payload: &[0x45, 0, 0, 20] };
+        let bytes = frame.encode().unwrap();
+        assert_eq!(bytes[..2], [KIND_TUN_PACKET, 6]);
+        assert_eq!(DataFrame::decode(&bytes), Some(frame));
+    }
+
+    #[test]
+    fn broadcast_has_an_empty_target() {
+        let frame = DataFrame { kind: KIND_BROADCAST, source: "node-a", target: "", stream_id: 0, payload: b"mdns" };
+        assert_eq!(DataFrame::decode(&frame.encode().unwrap()), Some(frame));
+    }
+
+    #[test]
+    fn tcp_data_carries_the_stream_id() {
+        let frame = DataFrame { kind: KIND_TCP_DATA, source: "a", target: "b", stream_id: 0x01020304, payload: b"GET /" };
+        let bytes = frame.encode().unwrap();
+        assert_eq!(bytes, [&[KIND_TCP_DATA, 1, b'a', 1, b'b', 1, 2, 3, 4][..], b"GET /"].concat());
+        assert_eq!(DataFrame::decode(&bytes), Some(frame));
+    }
+
+    #[test]
+    fn stream_id_is_only_encoded_for_tcp_data() {
+        let frame = DataFrame { kind: KIND_TUN_PACKET, source: "a", target: "b", stream_id: 7, payload: b"x" };
+        let bytes = frame.encode().unwrap();
+        let decoded = DataFrame::decode(&bytes).unwrap();
+        assert_eq!((decoded.stream_id, decoded.payload), (0, &b"x"[..]));
+    }
+
+    #[test]
+    fn long_ids_are_not_encoded() {
+        let id = "x".repeat(256);
+        let frame = DataFrame { kind: KIND_TUN_PACKET, source: &id, target: "b", stream_id: 0, payload: &[] };
+        assert_eq!(frame.encode(), None);
+    }
+
+    #[test]
+    fn bad_frames_are_rejected() {
+        assert_eq!(DataFrame::decode(&[]), None);
+        // Unknown kind
+        assert_eq!(DataFrame::decode(&[0x09, 0, 0]), None);
+        // Target cut off
+        assert_eq!(DataFrame::decode(&[KIND_TUN_PACKET, 1, b'a']), None);
+        // Stream id cut off
+        assert_eq!(DataFrame::decode(&[KIND_TCP_DATA, 1, b'a', 1, b'b', 0, 0]), None);
+        // Source not UTF-8
+        assert_eq!(DataFrame::decode(&[KIND_TUN_PACKET, 1, 0xff, 0]), None);
+    }
+}
@@ -1,3 +1,6 @@
+pub mod data_frame;
+pub mod relay;
+
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -0,0 +1,170 @@
+//! Wire format between nodes and a packet relay.
+//!
+//! A node keeps one QUIC connection to the relay. It first opens a bidirectional
+//! stream, writes a bincode `RelayHello` and finishes it; the relay answers with a
+//! single `HelloStatus` byte. After that every frame is
+//! `[kind u8][peer id len u8][peer id][payload]`: the node names the destination
+//! peer, the relay rewrites it to the source before delivering. Frames go as QUIC
+//! datagrams when they fit, otherwise u16 BE length-prefixed on a unidirectional
+//! stream each side opens on first use.
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// ALPN of the relay protocol.
+pub const ALPN: &[u8] = b"syuink-relay";
+
+/// Payload is a raw IP packet.
+pub const KIND_PACKET: u8 = 0x01;
+/// Payload is a JSON signaling message for the peer.
+pub const KIND_MESSAGE: u8 = 0x02;
+/// Relay to node: the named peer is not connected, payload empty.
+pub const KIND_UNREACHABLE: u8 = 0x03;
+
+/// Sent once by the node. `token` is what the signaling server issued for this
+/// group and id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayHello {
+    pub group: String,
+    pub id: String,
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HelloStatus {
+    Ok = 0x00,
+    Unauthorized = 0x01,
+}
+
+impl RelayHello {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap_or_default()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+/// One frame; `peer` is the destination on the way in, the source on the way out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub kind: u8,
+    pub peer: &'a str,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(kind: u8, peer: &'a str, payload: &'a [u8]) -> Self {
+        Self { kind, peer, payload }
+    }
+
+    /// `None` if the peer id is longer than 255 bytes.
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let id = self.peer.as_bytes();
+        let id_len = u8::try_from(id.len()).ok()?;
+        let mut out = Vec::with_capacity(2 + id.len() + self.payload.len());
+        out.push(self.kind);
+        out.push(id_len);
+        out.extend_from_slice(id);
+        out.extend_from_slice(self.payload);
+        Some(out)
+    }
+
+    pub fn decode(bytes: &'a [u8]) -> Option<Self> {
+        let (&kind, rest) = bytes.split_first()?;
+        let (&id_len, rest) = rest.split_first()?;
+        if rest.len() < id_len as usize {
+            return None;
+        }
+        let (id, payload) = rest.split_at(id_len as usize);
+        let peer = std::str::from_utf8(id).ok()?;
+        Some(Self { kind, peer, payload })
+    }
+
+    /// The same frame naming `peer` instead.
+    pub fn readdressed(&self, peer: &str) -> Option<Vec<u8>> {
+        Frame::new(self.kind, peer, self.payload).encode()
+    }
+}
+
+/// Writes one u16 BE length-prefixed frame to an overflow stream.
+pub async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut W, frame: &[u8]) -> std::io::Result<()> {
+    let len = u16::try_from(frame.len())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "frame too large"))?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(frame).await
+}
+
+/// Reads one frame written by `write_frame`.
+pub async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trips() {
+        let frame = Frame::new(KIND_PACKET, "node-b", &[0x45, 0x00, 0x01]);
+        let bytes = frame.encode().unwrap();
+        assert_eq!(bytes, [KIND_PACKET, 6, b'n', b'o', b'd', b'e', b'-', b'b', 0x45, 0x00, 0x01]);
+        assert_eq!(Frame::decode(&bytes), Some(frame));
+    }
+
+    #[test]
+    fn empty_payload_and_peer() {
+        let frame = Frame::new(KIND_UNREACHABLE, "", &[]);
+        assert_eq!(Frame::decode(&frame.encode().unwrap()), Some(frame));
+    }
+
+    #[test]
+    fn readdressed_keeps_kind_and_payload() {
+        let frame = Frame::new(KIND_MESSAGE, "dest", b"{}");
+        let out = frame.readdressed("source").unwrap();
+        assert_eq!(Frame::decode(&out), Some(Frame::new(KIND_MESSAGE, "source", b"{}")));
+    }
+
+    #[test]
+    fn peer_id_longer_than_255_bytes_is_not_encoded() {
+        let id = "x".repeat(256);
+        assert_eq!(Frame::new(KIND_PACKET, &id, &[]).encode(), None);
+        assert!(Frame::new(KIND_PACKET, &id[..255], &[]).encode().is_some());
+    }
+
+    #[test]
+    fn truncated_or_invalid_frames_are_rejected() {
+        assert_eq!(Frame::decode(&[]), None);
+        assert_eq!(Frame::decode(&[KIND_PACKET]), None);
+        // Claims a 5-byte id, has 2
+        assert_eq!(Frame::decode(&[KIND_PACKET, 5, b'a', b'b']), None);
+        // Id is not UTF-8
+        assert_eq!(Frame::decode(&[KIND_PACKET, 1, 0xff]), None);
+    }
+
+    #[test]
+    fn hello_round_trips() {
+        let hello = RelayHello { group: "g".into(), id: "node-a".into(), token: "t".into() };
+        let decoded = RelayHello::decode(&hello.encode()).unwrap();
+        assert_eq!((decoded.group, decoded.id, decoded.token), (hello.group, hello.id, hello.token));
+        assert!(RelayHello::decode(&[0xff]).is_none());
+    }
+
+    #[tokio::test]
+    async fn stream_frames_round_trip() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        write_frame(&mut a, b"first").await.unwrap();
+        write_frame(&mut a, &[]).await.unwrap();
+        write_frame(&mut a, &[7u8; 300]).await.unwrap();
+        assert_eq!(read_frame(&mut b).await.unwrap(), b"first");
+        assert_eq!(read_frame(&mut b).await.unwrap(), b"");
+        assert_eq!(read_frame(&mut b).await.unwrap(), vec![7u8; 300]);
+        assert!(write_frame(&mut a, &vec![0u8; 65536]).await.is_err());
+    }
+}
@@ -0,0 +1,10 @@
+//! In-process harness for exercising `p2p-node` without root or a kernel TUN:
+//! a mock signaling server, nodes on in-memory packet devices, and packet helpers.
+
+pub mod signaling;
+pub mod node;
+pub mod packet;
+
+pub use signaling::{MockSignalingServer, PathMode};
+pub use node::{lan_config, Mesh, TestNode, TIMEOUT};
+pub use packet::{echo_server, exchange_packet, free_udp_port, parse_udp, udp_packet};
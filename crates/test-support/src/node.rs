@@ -0,0 +1,234 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use anyhow::{Result, anyhow, bail};
+use p2p_node::{IpReport, LanDiscoveryConfig, MemoryDevice, MemoryDeviceHandle, NodeCommand, NodeConfig, P2PNode, PeerInfo};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use crate::packet::free_udp_port;
+use crate::signaling::{MockSignalingServer, PathMode};
+
+/// How long any single wait in the harness may take.
+pub const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// LAN discovery on a port of its own, so nodes of other tests stay out of the group.
+pub fn lan_config() -> NodeConfig {
+    NodeConfig {
+        lan_discovery: LanDiscoveryConfig { enabled: true, port: free_udp_port() },
+        ..Default::default()
+    }
+}
+
+/// A `P2PNode` running in this process on a `MemoryDevice`.
+pub struct TestNode {
+    pub id: String,
+    pub name: String,
+    pub ip: Ipv4Addr,
+    pub report: IpReport,
+    device: MemoryDeviceHandle,
+    peers: watch::Receiver<Vec<PeerInfo>>,
+    commands: mpsc::Sender<NodeCommand>,
+    shutdown: broadcast::Sender<()>,
+    task: JoinHandle<Result<(String, u16)>>,
+}
+
+impl TestNode {
+    pub async fn start(signaling_url: &str, name: &str, ip: Ipv4Addr, config: NodeConfig) -> Result<Self> {
+        Self::start_with_servers(&[signaling_url.to_string()], name, ip, config).await
+    }
+
+    /// Like `start`, with fallback signaling servers after the first.
+    pub async fn start_with_servers(signaling_urls: &[String], name: &str, ip: Ipv4Addr, config: NodeConfig) -> Result<Self> {
+        Self::start_as(uuid::Uuid::new_v4().to_string(), signaling_urls, name, ip, config).await
+    }
+
+    /// A node without a signaling server, using `name` as its ID so static peers can
+    /// be configured with it.
+    pub async fn start_static(name: &str, ip: Ipv4Addr, config: NodeConfig) -> Result<Self> {
+        Self::start_as(name.to_string(), &[], name, ip, config).await
+    }
+
+    async fn start_as(id: String, signaling_urls: &[String], name: &str, ip: Ipv4Addr, config: NodeConfig) -> Result<Self> {
+        let (device, handle) = MemoryDevice::new();
+        let (shutdown, shutdown_rx) = broadcast::channel(1);
+        let (report_tx, mut report_rx) = mpsc::channel(1);
+        let (peer_update_tx, mut peer_update_rx) = mpsc::channel::<Vec<PeerInfo>>(16);
+        let (commands, command_rx) = mpsc::channel(16);
+
+        let node = P2PNode::new(ip, Ipv4Addr::new(255, 255, 255, 0), name.to_string()).with_config(config);
+        let task = tokio::spawn(node.start_with_device(
+            Box::new(device), ip,
+            shutdown_rx, Some(report_tx), Some(peer_update_tx),
+            signaling_urls.to_vec(), None,
+            id.clone(), (None, None, None, false), Vec::new(),
+            command_rx,
+        ));
+
+        // The node blocks on peer updates, so always drain them
+        let (peers_tx, peers) = watch::channel(Vec::new());
+        tokio::spawn(async move {
+            while let Some(list) = peer_update_rx.recv().await {
+                let _ = peers_tx.send(list);
+            }
+        });
+
+        let report = match timeout(TIMEOUT, report_rx.recv()).await {
+            Ok(Some(report)) => report,
+            _ => {
+                task.abort();
+                bail!("Node {} did not come up", name);
+            }
+        };
+
+        Ok(Self {
+            id,
+            name: name.to_string(),
+            ip,
+            report,
+            device: handle,
+            peers,
+            commands,
+            shutdown,
+            task,
+        })
+    }
+
+    /// Waits until `name` is in our peer list, with one of `statuses` if any are given
+    /// ("relay", "p2p", "webrtc").
+    pub async fn wait_for_peer(&mut self, name: &str, statuses: &[&str]) -> Result<PeerInfo> {
+        let wait = async {
+            loop {
+                let found = self.peers.borrow().iter()
+                    .find(|p| p.name == name && (statuses.is_empty() || statuses.contains(&p.route_status.as_str())))
+                    .cloned();
+                if let Some(peer) = found {
+                    return Ok(peer);
+                }
+                self.peers.changed().await.map_err(|_| anyhow!("{} stopped", self.name))?;
+            }
+        };
+        timeout(TIMEOUT, wait).await
+            .map_err(|_| anyhow!("{} never saw peer {} ({:?})", self.name, name, statuses))?
+    }
+
+    /// Hands an IP packet to the node as if a local application sent it.
+    pub fn send_packet(&self, packet: Vec<u8>) {
+        self.device.send(packet);
+    }
+
+    /// Next packet the node delivers locally that satisfies `matches`; others are skipped.
+    pub async fn recv_packet(&mut self, matches: impl Fn(&[u8]) -> bool) -> Result<Vec<u8>> {
+        let wait = async {
+            while let Some(packet) = self.device.recv().await {
+                if matches(&packet) {
+                    return Ok(packet);
+                }
+            }
+            Err(anyhow!("{} stopped", self.name))
+        };
+        timeout(TIMEOUT, wait).await
+            .map_err(|_| anyhow!("{} received no matching packet", self.name))?
+    }
+
+    pub async fn command(&self, command: NodeCommand) -> Result<()> {
+        self.commands.send(command).await.map_err(|_| anyhow!("{} stopped", self.name))
+    }
+
+    /// TCP connection to `target` through this node's SOCKS5 proxy.
+    pub async fn socks5_connect(&self, target: SocketAddr) -> Result<TcpStream> {
+        let SocketAddr::V4(target) = target else {
+            bail!("IPv4 targets only");
+        };
+        let auth = &self.report.socks5_auth;
+        let mut socket = TcpStream::connect(("127.0.0.1", self.report.socks5_port)).await?;
+
+        // 1. Username/password method
+        socket.write_all(&[0x05, 0x01, 0x02]).await?;
+        let mut method = [0u8; 2];
+        socket.read_exact(&mut method).await?;
+        if method != [0x05, 0x02] {
+            bail!("SOCKS5 server chose method {:#04x}", method[1]);
+        }
+        let mut login = vec![0x01, auth.username.len() as u8];
+        login.extend_from_slice(auth.username.as_bytes());
+        login.push(auth.password.len() as u8);
+        login.extend_from_slice(auth.password.as_bytes());
+        socket.write_all(&login).await?;
+        let mut status = [0u8; 2];
+        socket.read_exact(&mut status).await?;
+        if status[1] != 0x00 {
+            bail!("SOCKS5 authentication failed");
+        }
+
+        // 2. CONNECT
+        let mut request = vec![0x05, 0x01, 0x00, 0x01];
+        request.extend_from_slice(&target.ip().octets());
+        request.extend_from_slice(&target.port().to_be_bytes());
+        socket.write_all(&request).await?;
+        let mut reply = [0u8; 4];
+        socket.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            bail!("SOCKS5 CONNECT to {} failed with reply {:#04x}", target, reply[1]);
+        }
+        let addr_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => socket.read_u8().await? as usize,
+            other => bail!("Bad SOCKS5 reply address type {}", other),
+        };
+        let mut bound = vec![0u8; addr_len + 2];
+        socket.read_exact(&mut bound).await?;
+        Ok(socket)
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown.send(());
+        match timeout(TIMEOUT, self.task).await {
+            Ok(Ok(result)) => result.map(|_| ()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(anyhow!("{} did not shut down", self.name)),
+        }
+    }
+}
+
+/// A mock signaling server and N nodes named `node-0`, `node-1`, ... on
+/// 10.251.0.10, 10.251.0.11, ..., started once every node sees every other.
+pub struct Mesh {
+    pub server: MockSignalingServer,
+    pub nodes: Vec<TestNode>,
+}
+
+impl Mesh {
+    pub async fn start(n: usize, mode: PathMode) -> Result<Self> {
+        Self::start_with(n, mode, |_| NodeConfig::default()).await
+    }
+
+    /// Like `start`, with `config(i)` for node i.
+    pub async fn start_with(n: usize, mode: PathMode, config: impl Fn(usize) -> NodeConfig) -> Result<Self> {
+        let server = MockSignalingServer::start(mode).await?;
+        let mut nodes = Vec::with_capacity(n);
+        for i in 0..n {
+            let ip = Ipv4Addr::new(10, 251, 0, 10 + i as u8);
+            nodes.push(TestNode::start(&server.url(), &Self::name(i), ip, config(i)).await?);
+        }
+        for (i, node) in nodes.iter_mut().enumerate() {
+            for j in (0..n).filter(|j| *j != i) {
+                node.wait_for_peer(&Self::name(j), &[]).await?;
+            }
+        }
+        Ok(Self { server, nodes })
+    }
+
+    pub fn name(i: usize) -> String {
+        format!("node-{}", i)
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        for node in self.nodes {
+            node.shutdown().await?;
+        }
+        Ok(())
+    }
+}
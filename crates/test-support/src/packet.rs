@@ -0,0 +1,68 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use anyhow::Result;
+use etherparse::{NetSlice, PacketBuilder, SlicedPacket, TransportSlice};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use crate::node::TestNode;
+
+/// IPv4/UDP packet as an application on `src` would hand it to the TUN device.
+pub fn udp_packet(src: Ipv4Addr, dst: Ipv4Addr, src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let builder = PacketBuilder::ipv4(src.octets(), dst.octets(), 64).udp(src_port, dst_port);
+    let mut packet = Vec::with_capacity(builder.size(payload.len()));
+    builder.write(&mut packet, payload).expect("writing to a Vec cannot fail");
+    packet
+}
+
+/// Source, destination and payload of an IPv4/UDP packet.
+pub fn parse_udp(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let sliced = SlicedPacket::from_ip(packet).ok()?;
+    let ip = match sliced.net? {
+        NetSlice::Ipv4(ip) => ip,
+        _ => return None,
+    };
+    let udp = match sliced.transport? {
+        TransportSlice::Udp(udp) => udp,
+        _ => return None,
+    };
+    let src = SocketAddr::from((ip.header().source_addr(), udp.source_port()));
+    let dst = SocketAddr::from((ip.header().destination_addr(), udp.destination_port()));
+    Some((src, dst, udp.payload()))
+}
+
+/// Sends one UDP packet from `from` to `to` and waits for `to` to deliver it.
+pub async fn exchange_packet(from: &TestNode, to: &mut TestNode, payload: &[u8]) -> Result<()> {
+    from.send_packet(udp_packet(from.ip, to.ip, 40000, 9000, payload));
+    let expected_src = SocketAddr::from((from.ip, 40000));
+    to.recv_packet(|p| matches!(parse_udp(p), Some((src, _, data)) if src == expected_src && data == payload)).await?;
+    Ok(())
+}
+
+/// A loopback UDP port that was free a moment ago.
+pub fn free_udp_port() -> u16 {
+    std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// TCP echo server on loopback, stopped when the handle is aborted or dropped with the runtime.
+pub async fn echo_server() -> std::io::Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let task = tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                loop {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            if socket.write_all(&buf[..n]).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+    Ok((addr, task))
+}
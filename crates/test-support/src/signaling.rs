@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use p2p_node::signaling::{ServiceDecl, SignalMessage};
+use serde_json::Value;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tracing::{debug, info};
+
+/// How the server lets peers reach each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathMode {
+    /// Announce each peer's address so nodes open direct QUIC connections.
+    Direct,
+    /// Hide addresses and drop WebRTC negotiation: all traffic takes the relay.
+    RelayOnly,
+}
+
+/// In-process stand-in for the signaling Worker, speaking the same `SignalMessage`
+/// JSON over WebSocket: join / peer_joined / peer_left, service registration, and
+/// relay of everything addressed to a `target`.
+pub struct MockSignalingServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct State {
+    next_conn: u64,
+    sessions: HashMap<u64, Session>,
+    services: HashMap<String, Vec<ServiceDecl>>,
+    // Message type -> number of messages relayed
+    relayed: HashMap<String, usize>,
+}
+
+struct Session {
+    tx: mpsc::UnboundedSender<Message>,
+    // Set once the session has joined
+    id: Option<String>,
+    announce: Option<SignalMessage>,
+}
+
+impl MockSignalingServer {
+    pub async fn start(mode: PathMode) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        info!("Mock signaling server on {} ({:?})", addr, mode);
+
+        let accept_state = state.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, remote)) = listener.accept().await {
+                let state = accept_state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, remote.ip(), state, mode).await {
+                        debug!("Mock signaling connection {} ended: {}", remote, e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { addr, state, task })
+    }
+
+    /// Base URL for `P2PNode::start`; any `/wapi/<group>` path is accepted.
+    pub fn url(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    /// How many messages of this type (e.g. "tun_packet", "tcp_connect") were relayed.
+    pub async fn relayed(&self, kind: &str) -> usize {
+        self.state.lock().await.relayed.get(kind).copied().unwrap_or(0)
+    }
+
+    /// Sessions that have joined.
+    pub async fn peer_count(&self) -> usize {
+        self.state.lock().await.sessions.values().filter(|s| s.id.is_some()).count()
+    }
+
+    /// Takes the server down: stops accepting and closes every session, as if the
+    /// process died.
+    pub async fn stop(&self) {
+        self.task.abort();
+        // Dropping a session's sender closes its WebSocket
+        self.state.lock().await.sessions.clear();
+    }
+}
+
+impl Drop for MockSignalingServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(stream: TcpStream, remote_ip: IpAddr, state: Arc<Mutex<State>>, mode: PathMode) -> Result<()> {
+    let ws = tokio_tungstenite::accept_async(stream).await?;
+    let (mut write, mut read) = ws.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+
+    let conn = {
+        let mut st = state.lock().await;
+        st.next_conn += 1;
+        let conn = st.next_conn;
+        st.sessions.insert(conn, Session { tx, id: None, announce: None });
+        conn
+    };
+
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if write.send(msg).await.is_err() {
+                break;
+            }
+        }
+        let _ = write.close().await;
+    });
+
+    while let Some(msg) = read.next().await {
+        match msg? {
+            Message::Text(text) => handle_text(&state, conn, remote_ip, mode, &text).await,
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    // Disconnected: tell the others, like the Worker's close handler
+    let mut st = state.lock().await;
+    if let Some(Session { id: Some(id), .. }) = st.sessions.remove(&conn) {
+        st.broadcast(&SignalMessage::PeerLeft { id: id.clone() }, None);
+        if st.services.remove(&id).is_some() {
+            st.broadcast_services();
+        }
+    }
+    writer.abort();
+    Ok(())
+}
+
+async fn handle_text(state: &Mutex<State>, conn: u64, remote_ip: IpAddr, mode: PathMode, text: &str) {
+    let value: Value = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    let kind = value.get("type").and_then(Value::as_str).unwrap_or_default().to_string();
+    let mut st = state.lock().await;
+
+    match serde_json::from_value::<SignalMessage>(value.clone()) {
+        Ok(SignalMessage::Join { id, ip, name, p2p_port, os, version, device_type, is_gateway, domains, public_key, .. }) => {
+            let announce = SignalMessage::PeerJoined {
+                id: id.clone(),
+                ip,
+                public_addr: match mode {
+                    PathMode::Direct => Some(remote_ip.to_string()),
+                    PathMode::RelayOnly => None,
+                },
+                p2p_port,
+                name,
+                os,
+                version,
+                device_type,
+                is_gateway,
+                connected_at: None,
+                domains,
+                public_key,
+            };
+
+            // A second session with the same ID replaces the first
+            st.sessions.retain(|c, s| *c == conn || s.id.as_deref() != Some(id.as_str()));
+
+            // 1. Existing peers to the joiner
+            let existing: Vec<SignalMessage> = st.sessions.iter()
+                .filter(|(c, _)| **c != conn)
+                .filter_map(|(_, s)| s.announce.clone())
+                .collect();
+            if let Some(session) = st.sessions.get_mut(&conn) {
+                for msg in &existing {
+                    session.send(msg);
+                }
+                session.id = Some(id);
+                session.announce = Some(announce.clone());
+            }
+
+            // 2. Current services to everyone, the joiner to the others
+            st.broadcast_services();
+            st.broadcast(&announce, Some(conn));
+        }
+        Ok(SignalMessage::RegisterServices { id, services }) => {
+            st.services.insert(id, services);
+            st.broadcast_services();
+        }
+        Ok(SignalMessage::Offer { .. } | SignalMessage::Answer { .. } | SignalMessage::Candidate { .. })
+            if mode == PathMode::RelayOnly => {}
+        _ => {
+            *st.relayed.entry(kind).or_default() += 1;
+            let target = value.get("target_id").or_else(|| value.get("target")).and_then(Value::as_str);
+            match target {
+                Some(target) => {
+                    if let Some(session) = st.sessions.values().find(|s| s.id.as_deref() == Some(target)) {
+                        let _ = session.tx.send(Message::Text(text.to_string()));
+                    }
+                }
+                None => {
+                    for (_, session) in st.sessions.iter().filter(|(c, _)| **c != conn) {
+                        let _ = session.tx.send(Message::Text(text.to_string()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Session {
+    fn send(&self, msg: &SignalMessage) {
+        if let Ok(json) = serde_json::to_string(msg) {
+            let _ = self.tx.send(Message::Text(json));
+        }
+    }
+}
+
+impl State {
+    // To every joined session except `except`
+    fn broadcast(&self, msg: &SignalMessage, except: Option<u64>) {
+        for (conn, session) in &self.sessions {
+            if Some(*conn) != except && session.id.is_some() {
+                session.send(msg);
+            }
+        }
+    }
+
+    fn broadcast_services(&self) {
+        let services = self.services.iter()
+            .flat_map(|(id, decls)| decls.iter().map(move |d| (id.clone(), d.clone())))
+            .collect();
+        self.broadcast(&SignalMessage::ServiceUpdate { services }, None);
+    }
+}
@@ -0,0 +1,70 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use p2p_node::NodeConfig;
+use test_support::{parse_udp, udp_packet, MockSignalingServer, PathMode, TestNode, TIMEOUT};
+
+async fn wait_for_peer_count(server: &MockSignalingServer, n: usize) {
+    let wait = async {
+        while server.peer_count().await != n {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    tokio::time::timeout(TIMEOUT, wait).await.expect("nodes never joined the fallback server");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn nodes_fail_over_to_the_next_signaling_server() {
+    let primary = MockSignalingServer::start(PathMode::RelayOnly).await.unwrap();
+    let fallback = MockSignalingServer::start(PathMode::RelayOnly).await.unwrap();
+    let servers = vec![primary.url(), fallback.url()];
+
+    let mut a = TestNode::start_with_servers(&servers, "node-a", Ipv4Addr::new(10, 251, 0, 10), NodeConfig::default())
+        .await
+        .unwrap();
+    let mut b = TestNode::start_with_servers(&servers, "node-b", Ipv4Addr::new(10, 251, 0, 11), NodeConfig::default())
+        .await
+        .unwrap();
+    a.wait_for_peer("node-b", &[]).await.unwrap();
+    b.wait_for_peer("node-a", &[]).await.unwrap();
+    assert_eq!(primary.peer_count().await, 2);
+    assert_eq!(fallback.peer_count().await, 0);
+
+    primary.stop().await;
+    wait_for_peer_count(&fallback, 2).await;
+
+    // Same IDs and IPs on the new server: packets still reach the right node. The
+    // first may go out before the peers have been re-announced to each other.
+    let expected_src = SocketAddr::from((a.ip, 40000));
+    let mut delivered = false;
+    for attempt in 0..10 {
+        let payload = format!("after failover {}", attempt);
+        a.send_packet(udp_packet(a.ip, b.ip, 40000, 9000, payload.as_bytes()));
+        let received = b.recv_packet(|p| {
+            matches!(parse_udp(p), Some((src, _, data)) if src == expected_src && data == payload.as_bytes())
+        });
+        if tokio::time::timeout(Duration::from_secs(1), received).await.is_ok_and(|r| r.is_ok()) {
+            delivered = true;
+            break;
+        }
+    }
+    assert!(delivered, "no packet crossed the fallback server");
+    assert!(fallback.relayed("tun_packet").await >= 1);
+
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn node_starts_on_a_fallback_when_the_first_server_is_down() {
+    let down = MockSignalingServer::start(PathMode::RelayOnly).await.unwrap();
+    down.stop().await;
+    let fallback = MockSignalingServer::start(PathMode::RelayOnly).await.unwrap();
+    let servers = vec![down.url(), fallback.url()];
+
+    let node = TestNode::start_with_servers(&servers, "node-a", Ipv4Addr::new(10, 251, 0, 10), NodeConfig::default())
+        .await
+        .unwrap();
+    wait_for_peer_count(&fallback, 1).await;
+
+    node.shutdown().await.unwrap();
+}
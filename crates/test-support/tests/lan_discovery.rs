@@ -0,0 +1,70 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use p2p_node::{NodeConfig, NodeKey, StaticPeer};
+use test_support::{exchange_packet, lan_config, MockSignalingServer, PathMode, TestNode};
+
+// `key` running as `name`, knowing `peer` by its key alone: no endpoint to dial
+fn static_on_lan(lan: &NodeConfig, key: &NodeKey, name: &str, ip: Ipv4Addr, peer_key: &NodeKey) -> NodeConfig {
+    NodeConfig {
+        key: Some(key.clone()),
+        static_peers: vec![StaticPeer {
+            id: name.to_string(),
+            name: name.to_string(),
+            public_key: peer_key.public_key(),
+            ip,
+            endpoints: Vec::new(),
+            routes: Vec::new(),
+        }],
+        ..lan.clone()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn static_peers_find_each_other_on_the_lan() {
+    let lan = lan_config();
+    let (key_a, key_b) = (NodeKey::generate().unwrap(), NodeKey::generate().unwrap());
+    let (ip_a, ip_b) = (Ipv4Addr::new(10, 251, 0, 10), Ipv4Addr::new(10, 251, 0, 11));
+    let mut a = TestNode::start_static("node-a", ip_a, static_on_lan(&lan, &key_a, "node-b", ip_b, &key_b)).await.unwrap();
+    let mut b = TestNode::start_static("node-b", ip_b, static_on_lan(&lan, &key_b, "node-a", ip_a, &key_a)).await.unwrap();
+    a.wait_for_peer("node-b", &["p2p"]).await.unwrap();
+    b.wait_for_peer("node-a", &["p2p"]).await.unwrap();
+
+    exchange_packet(&a, &mut b, b"lan a -> b").await.unwrap();
+    exchange_packet(&b, &mut a, b"lan b -> a").await.unwrap();
+
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn lan_announcements_from_unknown_keys_are_ignored() {
+    let lan = lan_config();
+    let (key_a, key_b, key_c) = (NodeKey::generate().unwrap(), NodeKey::generate().unwrap(), NodeKey::generate().unwrap());
+    let (ip_a, ip_b, ip_c) = (Ipv4Addr::new(10, 251, 0, 10), Ipv4Addr::new(10, 251, 0, 11), Ipv4Addr::new(10, 251, 0, 12));
+    // node-a only knows node-b; node-c announces itself next to it
+    let mut a = TestNode::start_static("node-a", ip_a, static_on_lan(&lan, &key_a, "node-b", ip_b, &key_b)).await.unwrap();
+    let c = TestNode::start_static("node-c", ip_c, static_on_lan(&lan, &key_c, "node-a", ip_a, &key_a)).await.unwrap();
+
+    let found = tokio::time::timeout(Duration::from_secs(12), a.wait_for_peer("node-c", &[])).await;
+    assert!(!matches!(found, Ok(Ok(_))), "node-a took node-c's announcement");
+
+    a.shutdown().await.unwrap();
+    c.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn lan_path_is_preferred_over_the_relay() {
+    let server = MockSignalingServer::start(PathMode::RelayOnly).await.unwrap();
+    let servers = vec![server.url()];
+    let config = lan_config();
+    let mut a = TestNode::start_with_servers(&servers, "node-a", Ipv4Addr::new(10, 251, 0, 10), config.clone()).await.unwrap();
+    let mut b = TestNode::start_with_servers(&servers, "node-b", Ipv4Addr::new(10, 251, 0, 11), config).await.unwrap();
+    a.wait_for_peer("node-b", &["p2p"]).await.unwrap();
+    b.wait_for_peer("node-a", &["p2p"]).await.unwrap();
+
+    exchange_packet(&a, &mut b, b"over the lan").await.unwrap();
+    assert_eq!(server.relayed("tun_packet").await, 0);
+
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
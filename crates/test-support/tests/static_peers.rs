@@ -0,0 +1,101 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use p2p_node::{NodeConfig, NodeKey, StaticPeer};
+use test_support::{exchange_packet, free_udp_port, parse_udp, udp_packet, TestNode};
+
+fn static_config(key: &NodeKey, port: u16, peer: StaticPeer) -> NodeConfig {
+    NodeConfig {
+        key: Some(key.clone()),
+        p2p_port: Some(port),
+        static_peers: vec![peer],
+        ..Default::default()
+    }
+}
+
+fn peer(name: &str, key: &NodeKey, ip: Ipv4Addr, port: u16) -> StaticPeer {
+    StaticPeer {
+        id: name.to_string(),
+        name: name.to_string(),
+        public_key: key.public_key(),
+        ip,
+        endpoints: vec![format!("127.0.0.1:{}", port)],
+        routes: Vec::new(),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn static_peers_connect_without_a_signaling_server() {
+    let (key_a, key_b) = (NodeKey::generate().unwrap(), NodeKey::generate().unwrap());
+    let (port_a, port_b) = (free_udp_port(), free_udp_port());
+    let (ip_a, ip_b) = (Ipv4Addr::new(10, 251, 0, 10), Ipv4Addr::new(10, 251, 0, 11));
+
+    let mut a = TestNode::start_static("node-a", ip_a, static_config(&key_a, port_a, peer("node-b", &key_b, ip_b, port_b)))
+        .await
+        .unwrap();
+    let mut b = TestNode::start_static("node-b", ip_b, static_config(&key_b, port_b, peer("node-a", &key_a, ip_a, port_a)))
+        .await
+        .unwrap();
+    a.wait_for_peer("node-b", &["p2p"]).await.unwrap();
+    b.wait_for_peer("node-a", &["p2p"]).await.unwrap();
+
+    exchange_packet(&a, &mut b, b"static a -> b").await.unwrap();
+    exchange_packet(&b, &mut a, b"static b -> a").await.unwrap();
+
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn static_peer_with_the_wrong_key_is_rejected() {
+    let (key_a, key_b, other) = (NodeKey::generate().unwrap(), NodeKey::generate().unwrap(), NodeKey::generate().unwrap());
+    let (port_a, port_b) = (free_udp_port(), free_udp_port());
+    let (ip_a, ip_b) = (Ipv4Addr::new(10, 251, 0, 10), Ipv4Addr::new(10, 251, 0, 11));
+
+    // node-a expects some other key for node-b, in either direction
+    let mut a = TestNode::start_static("node-a", ip_a, static_config(&key_a, port_a, peer("node-b", &other, ip_b, port_b)))
+        .await
+        .unwrap();
+    let b = TestNode::start_static("node-b", ip_b, static_config(&key_b, port_b, peer("node-a", &key_a, ip_a, port_a)))
+        .await
+        .unwrap();
+
+    let connected = tokio::time::timeout(Duration::from_secs(5), a.wait_for_peer("node-b", &["p2p"])).await;
+    assert!(!matches!(connected, Ok(Ok(_))), "node-a accepted node-b's key");
+
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn unconfigured_peer_id_is_rejected() {
+    let (key_a, key_b, key_c) = (NodeKey::generate().unwrap(), NodeKey::generate().unwrap(), NodeKey::generate().unwrap());
+    let (port_a, port_b, port_c) = (free_udp_port(), free_udp_port(), free_udp_port());
+    let (ip_a, ip_b, ip_c) = (Ipv4Addr::new(10, 251, 0, 10), Ipv4Addr::new(10, 251, 0, 11), Ipv4Addr::new(10, 251, 0, 12));
+
+    // node-a only knows node-b; node-c knows node-a's real key and dials it anyway
+    let mut a = TestNode::start_static("node-a", ip_a, static_config(&key_a, port_a, peer("node-b", &key_b, ip_b, port_b)))
+        .await
+        .unwrap();
+    let c = TestNode::start_static("node-c", ip_c, static_config(&key_c, port_c, peer("node-a", &key_a, ip_a, port_a)))
+        .await
+        .unwrap();
+
+    let expected_src = SocketAddr::from((ip_c, 40000));
+    let sender = async {
+        loop {
+            c.send_packet(udp_packet(ip_c, ip_a, 40000, 9000, b"from an unknown peer"));
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    };
+    let received = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::select! {
+            received = a.recv_packet(|p| matches!(parse_udp(p), Some((src, _, _)) if src == expected_src)) => received,
+            _ = sender => unreachable!(),
+        }
+    })
+    .await;
+    assert!(!matches!(received, Ok(Ok(_))), "node-a took a packet from an unconfigured peer");
+
+    a.shutdown().await.unwrap();
+    c.shutdown().await.unwrap();
+}
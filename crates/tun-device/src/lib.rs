@@ -1,9 +1,32 @@
 use std::net::Ipv4Addr;
-use tokio::io::{ReadHalf, WriteHalf};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 use tun::Configuration;
 pub use tun::AsyncDevice; // Re-export for consumers
 use anyhow::{Result, Context};
 
+mod memory;
+pub use memory::{MemoryDevice, MemoryDeviceHandle};
+
+pub type PacketReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type PacketWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Where the node reads and writes IP packets: one read returns one packet and one
+/// write carries one packet.
+pub trait PacketDevice: Send {
+    /// Packets carry the 4-byte protocol information header (macOS utun).
+    fn packet_information(&self) -> bool {
+        false
+    }
+
+    /// A kernel interface, which the OS routes and resolves through. In-process
+    /// devices skip all OS configuration.
+    fn is_kernel(&self) -> bool {
+        false
+    }
+
+    fn into_split(self: Box<Self>) -> (PacketReader, PacketWriter);
+}
+
 pub struct TunDevice {
     reader: ReadHalf<AsyncDevice>,
     writer: WriteHalf<AsyncDevice>,
@@ -53,3 +76,17 @@ impl TunDevice {
         (self.reader, self.writer)
     }
 }
+
+impl PacketDevice for TunDevice {
+    fn packet_information(&self) -> bool {
+        cfg!(target_os = "macos")
+    }
+
+    fn is_kernel(&self) -> bool {
+        true
+    }
+
+    fn into_split(self: Box<Self>) -> (PacketReader, PacketWriter) {
+        (Box::new(self.reader), Box::new(self.writer))
+    }
+}
@@ -0,0 +1,93 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use crate::{PacketDevice, PacketReader, PacketWriter};
+
+/// In-process packet device backed by channels, for running a node without a
+/// kernel TUN (tests, or programs embedding the node).
+///
+/// Packets injected through the `MemoryDeviceHandle` are read by the node as if a
+/// local application sent them; packets the node writes come out of the handle.
+pub struct MemoryDevice {
+    reader: MemoryReader,
+    writer: MemoryWriter,
+}
+
+/// The "operating system" side of a `MemoryDevice`.
+pub struct MemoryDeviceHandle {
+    to_node: mpsc::UnboundedSender<Vec<u8>>,
+    from_node: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl MemoryDevice {
+    pub fn new() -> (Self, MemoryDeviceHandle) {
+        let (to_node, node_rx) = mpsc::unbounded_channel();
+        let (node_tx, from_node) = mpsc::unbounded_channel();
+        let device = Self {
+            reader: MemoryReader { rx: node_rx },
+            writer: MemoryWriter { tx: node_tx },
+        };
+        (device, MemoryDeviceHandle { to_node, from_node })
+    }
+}
+
+impl PacketDevice for MemoryDevice {
+    fn into_split(self: Box<Self>) -> (PacketReader, PacketWriter) {
+        (Box::new(self.reader), Box::new(self.writer))
+    }
+}
+
+impl MemoryDeviceHandle {
+    /// Hands a packet to the node. Returns false once the node has shut down.
+    pub fn send(&self, packet: Vec<u8>) -> bool {
+        self.to_node.send(packet).is_ok()
+    }
+
+    /// Next packet the node wrote, or None once the node has shut down.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.from_node.recv().await
+    }
+}
+
+struct MemoryReader {
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl AsyncRead for MemoryReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(packet)) => {
+                // Like a TUN read into a short buffer, the excess is dropped
+                let n = packet.len().min(buf.remaining());
+                buf.put_slice(&packet[..n]);
+                Poll::Ready(Ok(()))
+            }
+            // Handle dropped: end of file
+            Poll::Ready(None) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+struct MemoryWriter {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.tx.send(buf.to_vec()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "memory device closed"))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}